@@ -0,0 +1,26 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use std::io::Cursor;
+
+// The launcher is a binary, so the wad reader is pulled in by path
+#[allow(dead_code)]
+#[path = "../../src/packet_helper/message_helper/wad_helper/mod.rs"]
+mod wad_helper;
+
+fuzz_target!(|data: &[u8]| {
+    let mut archive = match wad_helper::Archive::from_reader(Cursor::new(data)) {
+        Ok(a) => a,
+        Err(_) => return,
+    };
+    // keep allocations small enough for the fuzzer's rss limit
+    archive.set_limits(wad_helper::Limits {
+        max_entry_size: 16 << 20,
+        ..Default::default()
+    });
+
+    for entry in archive.entries().to_vec() {
+        let _ = archive.read(&entry);
+    }
+    let _ = wad_helper::verify::verify(&mut archive);
+});
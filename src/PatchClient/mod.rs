@@ -1,16 +1,16 @@
-use std::{
-    cmp::min,
-    error::Error,
-    fs::File,
-    io::{Bytes, Cursor, Write},
-    thread,
-    time::Duration,
-};
+pub mod scan;
+
+use std::{cmp::min, collections::HashMap, fs::File, io::Write, thread, time::Duration};
 
 use crate::{
-    crypto::rec1::{decrypt_rec1, gen_rec1},
-    packet_helper::{self, message_helper, ArgType, FormattedPacket},
-    table_list_parser::{self, PatchFile, TableList},
+    crypto::rec1::{decrypt_rec1, gen_rec1, Rec1Record},
+    mod_helper::manager::ModManager,
+    packet_helper::{
+        self,
+        message_helper::{wad_helper, Service},
+        ArgType, FormattedPacket,
+    },
+    table_list_parser::{PatchFile, TableList},
     WizClient::{self, Connection},
 };
 
@@ -18,6 +18,15 @@ pub struct Patcher {
     base_url: String,
     file_list: Vec<PatchFile>,
     game_dir: String,
+    // name of the directory the file list is in, eg. V_r726845.Wizard_1_520
+    revision: String,
+    // files that don't match the file list on purpose, eg. modded wads
+    keep: Vec<String>,
+    // modded files whose originals the update changed, replaced with the new
+    // vanilla files whether or not they're verified
+    replace: Vec<String>,
+    // check files that are already there against the file list
+    verify: bool,
 }
 
 use chrono::prelude::*;
@@ -25,12 +34,8 @@ use chrono::prelude::*;
 use std::time::{SystemTime, UNIX_EPOCH};
 
 use futures_util::StreamExt;
-use indicatif::{ProgressBar, ProgressStyle};
 use reqwest::Client;
-use tokio::{runtime::Runtime, task};
-
-use std::net::Shutdown::Both;
-use std::net::TcpListener;
+use tokio::task;
 
 impl Patcher {
     pub async fn download_file(client: &Client, url: &str, path: &str) -> Result<(), String> {
@@ -75,25 +80,39 @@ impl Patcher {
         let mut stream = res.bytes_stream();
 
         while let Some(item) = stream.next().await {
-            let chunk = item.or(Err(format!("Error while downloading file")))?;
+            let chunk = item.or(Err(String::from("Error while downloading file")))?;
             file.write_all(&chunk)
-                .or(Err(format!("Error while writing to file")))?;
+                .or(Err(String::from("Error while writing to file")))?;
             let new = min(downloaded + (chunk.len() as u64), total_size);
             downloaded = new;
         }
-        return Ok(());
+        Ok(())
+    }
+
+    // Checks every entry of a downloaded wad. A wad that fails is deleted so the
+    // next patch downloads it again.
+    pub fn verify_wad(path: &str) -> Result<(), String> {
+        let mut archive = wad_helper::Archive::open(path)?;
+        let report = wad_helper::verify::verify(&mut archive);
+        if report.is_ok() {
+            return Ok(());
+        }
+
+        for failure in report.failures() {
+            println!("{}: {} {}", path, failure.name, failure.status);
+        }
+        std::fs::remove_file(path).or(Err(format!("Failed to remove '{}'", path)))?;
+        Err(format!(
+            "{} failed verification ({} bad entries)",
+            path,
+            report.failures().len()
+        ))
     }
 
     pub async fn init(game_dir: String, mut file_list: FormattedPacket) -> Patcher {
-        let latest_file_list_url = match file_list.get_arg("ListFileURL") {
-            Some(x) => x,
-            None => String::from(""),
-        };
+        let latest_file_list_url = file_list.get_arg("ListFileURL").unwrap_or_default();
 
-        let base_url = match file_list.get_arg("URLPrefix") {
-            Some(x) => x,
-            None => String::from(""),
-        };
+        let base_url = file_list.get_arg("URLPrefix").unwrap_or_default();
         println!("Got latest file list: {}", latest_file_list_url);
 
         Self::download_file(
@@ -105,14 +124,73 @@ impl Patcher {
         .unwrap();
 
         let file_list = TableList::from_file("./LatestFileList.bin");
+        let revision = latest_file_list_url
+            .rsplit('/')
+            .nth(1)
+            .unwrap_or("")
+            .to_string();
 
         Patcher {
-            base_url: base_url,
+            base_url,
             file_list: file_list.get_records(),
-            game_dir: game_dir,
+            game_dir,
+            revision,
+            keep: Vec::new(),
+            replace: Vec::new(),
+            verify: false,
         }
     }
 
+    pub fn revision(&self) -> &str {
+        &self.revision
+    }
+
+    pub fn file_list(&self) -> &[PatchFile] {
+        &self.file_list
+    }
+
+    // Files (relative to the game dir) that are left as they are even though
+    // they don't match the file list
+    pub fn keep(&mut self, files: Vec<String>) -> &mut Self {
+        self.keep = files;
+        self
+    }
+
+    // Files (relative to the game dir) that are downloaded again even if they
+    // exist, for the mods to go back on top of after
+    pub fn replace(&mut self, files: Vec<String>) -> &mut Self {
+        self.replace = files;
+        self
+    }
+
+    // Other existing files are skipped unless they're verified: each one is
+    // then crc'd and downloaded again if it isn't what the file list has
+    pub fn verify(&mut self, verify: bool) -> &mut Self {
+        self.verify = verify;
+        self
+    }
+
+    // The file list's crc is of the whole file as installed
+    fn is_current(path: &str, file: &PatchFile) -> bool {
+        let size = std::fs::metadata(path).map(|m| m.len()).unwrap_or(0);
+        size == file.size() as u64 && wad_helper::file_crc(path).ok() == Some(file.crc())
+    }
+
+    // Whether an existing file has to go for the file list's version of it
+    fn is_out_of_date(
+        path: &str,
+        write_path: &str,
+        file: &PatchFile,
+        verify: bool,
+        keep: &[String],
+        replace: &[String],
+    ) -> bool {
+        std::path::Path::new(path).exists()
+            && !keep.iter().any(|f| f == write_path)
+            && (replace.iter().any(|f| f == write_path)
+                || (verify && !Self::is_current(path, file)))
+    }
+
     pub async fn patch(self, thread_count: usize, only_essential: bool) {
         let thread_chunks: Vec<Vec<PatchFile>> = self
             .file_list
@@ -124,11 +202,14 @@ impl Patcher {
         for chunk in thread_chunks {
             let base_url = self.base_url.clone();
             let game_dir = self.game_dir.clone();
+            let keep = self.keep.clone();
+            let replace = self.replace.clone();
+            let verify = self.verify;
 
             tasks.push(task::spawn(async move {
                 for file in chunk {
                     let mut write_path = file.src_name.clone();
-                    let src_name = file.src_name;
+                    let src_name = file.src_name.clone();
                     if only_essential {
                         if !src_name.contains("Root.wad")
                             && !src_name.contains("Bin")
@@ -151,33 +232,52 @@ impl Patcher {
                             write_path = src_name.replace("Windows/Bin/", "Bin/");
                         }
                     }
+                    let path = format!("{}{}", &game_dir, write_path);
+                    if Self::is_out_of_date(&path, &write_path, &file, verify, &keep, &replace) {
+                        println!("{} is out of date", write_path);
+                        if std::fs::remove_file(&path).is_err() {
+                            println!("Failed to remove '{}'", path);
+                        }
+                    }
+                    let existed = std::path::Path::new(&path).exists();
                     Self::download_file(
                         &Client::new(),
                         &format!("{}/{}", &base_url, src_name),
-                        &format!("{}{}", &game_dir, write_path),
+                        &path,
                     )
                     .await
                     .unwrap();
+
+                    if !existed && path.ends_with(".wad") {
+                        if let Err(e) = Self::verify_wad(&path) {
+                            println!("{}", e);
+                        }
+                    }
                 }
             }));
         }
 
         for task in tasks {
-            task.await;
+            if let Err(e) = task.await {
+                println!("A download task failed: {}", e);
+            }
         }
     }
 }
 
-pub fn get_ck2(username: String, password: String) -> Result<(String, u64), String> {
+pub fn get_ck2(
+    username: String,
+    password: String,
+    services: &HashMap<u8, Service>,
+) -> Result<(Rec1Record, u64), String> {
     let client: WizClient::Client = WizClient::Client::new();
-    let services = message_helper::get_services();
 
     let stream = client.create_stream("165.193.63.4:12000");
 
-    let serializer = packet_helper::Serializer::new(&services);
+    let serializer = packet_helper::Serializer::new(services);
 
     let session_offer_raw = &client.recv(&stream);
-    let session_offer = packet_helper::SessionOffer::new(&session_offer_raw);
+    let session_offer = packet_helper::SessionOffer::new(session_offer_raw);
     println!("Got session offer: {:#X?}", session_offer);
 
     let dt = Utc::now();
@@ -244,55 +344,50 @@ pub fn get_ck2(username: String, password: String) -> Result<(String, u64), Stri
     client.send(&stream, authen.as_slice());
 
     let buf = client.recv(&stream);
-    let deserializer = packet_helper::Deserializer::new(&services);
+    let deserializer = packet_helper::Deserializer::new(services);
     let mut deserialized_auth_rsp = deserializer.deserialize(buf, true).unwrap();
     println!("server returned packet {:#X?}", deserialized_auth_rsp);
 
-    let mut server_rec1 = match deserialized_auth_rsp.get_arg_vec("Rec1") {
-        Some(x) => x,
-        None => vec![],
-    };
+    let mut server_rec1 = deserialized_auth_rsp
+        .get_arg_vec("Rec1")
+        .unwrap_or_default();
 
-    let reason = match deserialized_auth_rsp.get_arg_vec("Reason") {
-        Some(x) => x,
-        None => vec![],
-    };
+    let reason = deserialized_auth_rsp
+        .get_arg_vec("Reason")
+        .unwrap_or_default();
 
-    if server_rec1.len() == 0 {
-        if reason.len() > 0 {
-            return Err(format!(
-                "Could not login. Reason: {}",
-                String::from_utf8(reason).unwrap().to_string()
-            ));
-        }
+    if server_rec1.is_empty() && !reason.is_empty() {
+        return Err(format!(
+            "Could not login. Reason: {}",
+            String::from_utf8_lossy(&reason)
+        ));
     }
 
-    let mut uid = match deserialized_auth_rsp.get_arg("UserID") {
+    let uid = match deserialized_auth_rsp.get_arg("UserID") {
         Some(x) => x.parse::<u64>().unwrap(),
         None => 0,
     };
     assert_ne!(uid, 0);
 
-    return Ok((
-        decrypt_rec1(
-            &mut server_rec1,
-            session_offer.sid,
-            session_offer.time_low,
-            session_offer.time_milli,
-        ),
-        uid,
-    ));
+    let record = decrypt_rec1(
+        &mut server_rec1,
+        session_offer.sid,
+        session_offer.time_low,
+        session_offer.time_milli,
+    )?;
+    Ok((record, uid))
 }
 
-pub async fn install_min() {
+// Patches the game, checking the files that are there first if `verify` is
+// set. Mods are applied again on top of whatever the update replaced.
+pub async fn install_min(services: &HashMap<u8, Service>, verify: bool) {
     let client: WizClient::Client = WizClient::Client::new();
-    let services = message_helper::get_services();
-    let serializer = packet_helper::Serializer::new(&services);
+    let serializer = packet_helper::Serializer::new(services);
 
     let stream = client.create_stream("165.193.63.4:12500");
 
     let session_offer_raw = &client.recv(&stream);
-    let session_offer = packet_helper::SessionOffer::new(&session_offer_raw);
+    let session_offer = packet_helper::SessionOffer::new(session_offer_raw);
     println!("Got session offer: {:#X?}", session_offer);
 
     let file_list = match serializer.serialize(
@@ -319,11 +414,130 @@ pub async fn install_min() {
     client.send(&stream, file_list.as_slice());
 
     let buf = client.recv(&stream);
-    let deserializer = packet_helper::Deserializer::new(&services);
+    let deserializer = packet_helper::Deserializer::new(services);
     let deserialized_file_list = deserializer.deserialize(buf, false).unwrap();
     println!("server returned packet {:#X?}", deserialized_file_list);
 
-    let patcher = Patcher::init(String::from("./test/"), deserialized_file_list).await;
+    let game_dir = String::from("./test/");
+    let mut patcher = Patcher::init(game_dir.clone(), deserialized_file_list).await;
+
+    patcher.verify(verify);
+
+    // modded files that are up to date are left alone, the rest get the new
+    // vanilla files and the mods go back on top of them after
+    let mut manager = match ModManager::open(&game_dir) {
+        Ok(manager) => Some(manager),
+        Err(e) => {
+            println!(
+                "Couldn't load the installed mods, patching without them: {}",
+                e
+            );
+            None
+        }
+    };
+    if let Some(manager) = &mut manager {
+        manager.with_file_list(patcher.file_list().to_vec());
+        patcher.keep(manager.current_files());
+        patcher.replace(manager.outdated_files());
+    }
+    let revision = patcher.revision().to_string();
+
     patcher.patch(50, true).await;
+
+    let result = match &mut manager {
+        Some(manager) => manager.reapply_after_update(&revision),
+        None => {
+            println!("Finished patching... ready to launch.");
+            return;
+        }
+    };
+    match result {
+        Ok(report) => {
+            for file in &report.replaced {
+                println!("{} was updated, mods applied again", file);
+            }
+            for failure in &report.apply.failed {
+                println!("{}", failure);
+            }
+            for (id, path) in &report.overridden {
+                println!("{} changes {}, which the update changed", id, path);
+            }
+            for id in &report.needs_review {
+                println!("{} needs a review for {}", id, revision);
+            }
+        }
+        Err(e) => println!("Couldn't apply mods again: {}", e),
+    }
     println!("Finished patching... ready to launch.");
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mod_helper::manager::GAME_DATA_DIR;
+    use crate::mod_helper::package::{Manifest, PackageBuilder, EXTENSION};
+    use crate::mod_helper::version::Version;
+    use crate::packet_helper::message_helper::wad_helper::fixtures::{write_wad, ENTRIES};
+    use std::path::Path;
+
+    const ROOT: &str = "Data/GameData/Root.wad";
+
+    // The file list's entry for `name` with `path` as its contents
+    fn list_entry(name: &str, path: &Path) -> PatchFile {
+        let size = std::fs::metadata(path).unwrap().len() as u32;
+        PatchFile::new(name, size, wad_helper::file_crc(path).unwrap())
+    }
+
+    #[test]
+    fn replaces_modded_files_an_update_changed_without_verify() {
+        let dir = tempfile::tempdir().unwrap();
+        let game_dir = dir.path().join("game");
+        write_wad(&game_dir.join(GAME_DATA_DIR), "Root.wad", ENTRIES);
+        let mod_path = dir.path().join(format!("bar.{}", EXTENSION));
+        let mut builder = PackageBuilder::new(Manifest::new("bar", Version::new(1, 0, 0)));
+        builder.add_file("Root.wad/GameData/Bar.txt", b"modded".to_vec());
+        builder.write(&mod_path).unwrap();
+
+        let mut manager = ModManager::open(&game_dir).unwrap();
+        manager.with_file_list(vec![list_entry(ROOT, &game_dir.join(ROOT))]);
+        manager.install(&mod_path).unwrap();
+        manager.enable("bar").unwrap();
+        manager.apply().unwrap();
+
+        // the new file list has a different Root.wad
+        let mut updated = ENTRIES.to_vec();
+        updated[0] = ("GameData/Foo.xml", b"<Updated/>", true);
+        let new_root = write_wad(&dir.path().join("update"), "Root.wad", &updated);
+        let file = list_entry(ROOT, &new_root);
+        manager.with_file_list(vec![file.clone()]);
+        assert!(manager.current_files().is_empty());
+        assert_eq!(manager.outdated_files(), [ROOT]);
+
+        let path = game_dir.join(ROOT).to_str().unwrap().to_string();
+        let (keep, replace) = (manager.current_files(), manager.outdated_files());
+        assert!(Patcher::is_out_of_date(
+            &path, ROOT, &file, false, &keep, &replace
+        ));
+        // unmodded files are still only checked when verifying
+        assert!(!Patcher::is_out_of_date(
+            &path,
+            ROOT,
+            &file,
+            false,
+            &keep,
+            &[]
+        ));
+
+        // what the patcher does with it
+        std::fs::remove_file(&path).unwrap();
+        std::fs::copy(&new_root, &path).unwrap();
+        let report = manager.reapply_after_update("r2").unwrap();
+        assert_eq!(report.replaced, [ROOT]);
+        let mut archive = wad_helper::Archive::open(&path).unwrap();
+        assert_eq!(archive.read_file("GameData/Bar.txt").unwrap(), b"modded");
+        assert_eq!(
+            archive.read_file("GameData/Foo.xml").unwrap(),
+            b"<Updated/>"
+        );
+    }
+}
@@ -0,0 +1,321 @@
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+use crate::mod_helper::manager::{ModManager, MODS_DIR};
+use crate::packet_helper::message_helper::wad_helper::file_crc;
+use crate::table_list_parser::PatchFile;
+
+// How a file in the game dir compares to the game's file list
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum FileStatus {
+    Vanilla,
+    Modified,
+    Missing,
+    // in the game dir but not in the file list
+    Extra,
+}
+
+impl fmt::Display for FileStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // padded so reports line up
+        f.pad(match self {
+            FileStatus::Vanilla => "vanilla",
+            FileStatus::Modified => "modified",
+            FileStatus::Missing => "missing",
+            FileStatus::Extra => "extra",
+        })
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ScannedFile {
+    // relative to the game dir
+    pub path: String,
+    pub status: FileStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub size: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expected_size: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expected_crc: Option<u32>,
+    // enabled mods the mod manager put in the file
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub mods: Vec<String>,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct ScanReport {
+    pub files: Vec<ScannedFile>,
+}
+
+impl ScanReport {
+    pub fn count(&self, status: FileStatus) -> usize {
+        self.files.iter().filter(|f| f.status == status).count()
+    }
+
+    // Nothing changed, missing or added, mods included
+    pub fn is_vanilla(&self) -> bool {
+        self.files.iter().all(|f| f.status == FileStatus::Vanilla)
+    }
+}
+
+fn walk(dir: &Path, prefix: &str, files: &mut BTreeSet<String>) -> Result<(), String> {
+    let read_err = |_| format!("Couldn't read directory '{}'", dir.display());
+    for item in fs::read_dir(dir).map_err(read_err)? {
+        let item = item.map_err(read_err)?;
+        let name = format!("{}{}", prefix, item.file_name().to_string_lossy());
+        let file_type = item.file_type().map_err(read_err)?;
+        if file_type.is_dir() {
+            // the mod manager's own files aren't part of the game
+            if name != MODS_DIR {
+                walk(&item.path(), &format!("{}/", name), files)?;
+            }
+        } else if file_type.is_file() {
+            files.insert(name);
+        }
+    }
+    Ok(())
+}
+
+// Where the patcher put a file, the minimal install moves Windows/Bin to Bin
+fn installed_path(name: &str, present: &BTreeSet<String>) -> String {
+    let name = name.replace('\\', "/");
+    if !present.contains(&name) {
+        if let Some(rest) = name.strip_prefix("Windows/Bin/") {
+            let moved = format!("Bin/{}", rest);
+            if present.contains(&moved) {
+                return moved;
+            }
+        }
+    }
+    name
+}
+
+// Compares every file in `game_dir` against its size and crc in the file
+// list. Modified and extra files the mod manager knows about are attributed
+// to the enabled mods in them.
+pub fn scan<P: AsRef<Path>>(
+    game_dir: P,
+    file_list: &[PatchFile],
+    manager: Option<&ModManager>,
+) -> Result<ScanReport, String> {
+    let game_dir = game_dir.as_ref();
+    let mut present = BTreeSet::new();
+    walk(game_dir, "", &mut present)?;
+
+    let mods_of = |path: &str| -> Vec<String> {
+        let manager = match manager {
+            Some(manager) => manager,
+            None => return Vec::new(),
+        };
+        let state = manager.state();
+        state.files.get(path).map_or(Vec::new(), |changed| {
+            changed
+                .mods
+                .iter()
+                .filter(|id| state.mods.get(*id).is_some_and(|m| m.enabled))
+                .cloned()
+                .collect()
+        })
+    };
+
+    let mut files: BTreeMap<String, ScannedFile> = BTreeMap::new();
+    for record in file_list {
+        let path = installed_path(record.name(), &present);
+        let expected_size = record.size() as u64;
+        let (status, size) = if present.remove(&path) {
+            let full = game_dir.join(&path);
+            let size = fs::metadata(&full)
+                .map(|m| m.len())
+                .or(Err(format!("Couldn't read '{}'", full.display())))?;
+            // only files of the right size are worth a crc
+            let same = size == expected_size && file_crc(&full)? == record.crc();
+            let status = if same {
+                FileStatus::Vanilla
+            } else {
+                FileStatus::Modified
+            };
+            (status, Some(size))
+        } else {
+            (FileStatus::Missing, None)
+        };
+        let mods = match status {
+            FileStatus::Modified => mods_of(&path),
+            _ => Vec::new(),
+        };
+        files.insert(
+            path.clone(),
+            ScannedFile {
+                path,
+                status,
+                size,
+                expected_size: Some(expected_size),
+                expected_crc: Some(record.crc()),
+                mods,
+            },
+        );
+    }
+
+    for path in present {
+        let size = fs::metadata(game_dir.join(&path)).map(|m| m.len()).ok();
+        let mods = mods_of(&path);
+        files.insert(
+            path.clone(),
+            ScannedFile {
+                path,
+                status: FileStatus::Extra,
+                size,
+                expected_size: None,
+                expected_crc: None,
+                mods,
+            },
+        );
+    }
+
+    Ok(ScanReport {
+        files: files.into_values().collect(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mod_helper::package::{Manifest, PackageBuilder, EXTENSION};
+    use crate::mod_helper::version::Version;
+    use crate::packet_helper::message_helper::wad_helper::fixtures::{write_wad, ENTRIES};
+    use std::path::PathBuf;
+
+    // A game dir with Root.wad and a client binary, and the file list for it
+    fn game(dir: &Path) -> (PathBuf, Vec<PatchFile>) {
+        let game_dir = dir.join("game");
+        let game_data = game_dir.join("Data/GameData");
+        fs::create_dir_all(&game_data).unwrap();
+        write_wad(&game_data, "Root.wad", ENTRIES);
+        fs::create_dir_all(game_dir.join("Bin")).unwrap();
+        fs::write(game_dir.join("Bin/Client.exe"), b"client").unwrap();
+
+        let file_list = ["Data/GameData/Root.wad", "Bin/Client.exe"]
+            .iter()
+            .map(|path| {
+                let full = game_dir.join(path);
+                let size = fs::metadata(&full).unwrap().len() as u32;
+                PatchFile::new(path, size, file_crc(&full).unwrap())
+            })
+            .collect();
+        (game_dir, file_list)
+    }
+
+    fn status(report: &ScanReport, path: &str) -> FileStatus {
+        report.files.iter().find(|f| f.path == path).unwrap().status
+    }
+
+    #[test]
+    fn finds_changed_missing_and_extra_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let (game_dir, mut file_list) = game(dir.path());
+        let report = scan(&game_dir, &file_list, None).unwrap();
+        assert!(report.is_vanilla());
+        assert_eq!(report.count(FileStatus::Vanilla), 2);
+
+        // same size, different contents
+        fs::write(game_dir.join("Bin/Client.exe"), b"CLIENT").unwrap();
+        fs::write(game_dir.join("Readme.txt"), b"extra").unwrap();
+        file_list.push(PatchFile::new("Data/GameData/Gone.wad", 10, 0));
+        let report = scan(&game_dir, &file_list, None).unwrap();
+        assert!(!report.is_vanilla());
+        assert_eq!(status(&report, "Bin/Client.exe"), FileStatus::Modified);
+        assert_eq!(
+            status(&report, "Data/GameData/Gone.wad"),
+            FileStatus::Missing
+        );
+        assert_eq!(status(&report, "Readme.txt"), FileStatus::Extra);
+        assert_eq!(
+            status(&report, "Data/GameData/Root.wad"),
+            FileStatus::Vanilla
+        );
+        let extra = report
+            .files
+            .iter()
+            .find(|f| f.path == "Readme.txt")
+            .unwrap();
+        assert_eq!((extra.size, extra.expected_size), (Some(5), None));
+    }
+
+    #[test]
+    fn follows_the_minimal_install() {
+        let dir = tempfile::tempdir().unwrap();
+        let (game_dir, mut file_list) = game(dir.path());
+        // listed where the full client has it, the minimal install moved it
+        let client = file_list.pop().unwrap();
+        file_list.push(PatchFile::new(
+            "Windows\\Bin\\Client.exe",
+            client.size(),
+            client.crc(),
+        ));
+        let report = scan(&game_dir, &file_list, None).unwrap();
+        assert!(report.is_vanilla());
+        assert!(report.files.iter().any(|f| f.path == "Bin/Client.exe"));
+    }
+
+    #[test]
+    fn attributes_changes_to_enabled_mods() {
+        let dir = tempfile::tempdir().unwrap();
+        let (game_dir, file_list) = game(dir.path());
+        let path = dir.path().join(format!("my_mod.{}", EXTENSION));
+        let mut builder = PackageBuilder::new(Manifest::new("my_mod", Version::new(1, 0, 0)));
+        builder.add_file("Root.wad/GameData/Bar.txt", b"modded".to_vec());
+        builder.add_file("New.wad/Extra.txt", b"extra".to_vec());
+        builder.write(&path).unwrap();
+
+        let mut manager = ModManager::open(&game_dir).unwrap();
+        manager.install(&path).unwrap();
+        manager.enable("my_mod").unwrap();
+        manager.apply().unwrap();
+        let report = scan(&game_dir, &file_list, Some(&manager)).unwrap();
+        let mods = |path: &str| {
+            let file = report.files.iter().find(|f| f.path == path).unwrap();
+            (file.status, file.mods.clone())
+        };
+        assert_eq!(
+            mods("Data/GameData/Root.wad"),
+            (FileStatus::Modified, vec![String::from("my_mod")])
+        );
+        assert_eq!(
+            mods("Data/GameData/New.wad"),
+            (FileStatus::Extra, vec![String::from("my_mod")])
+        );
+        // the mod manager's own files aren't scanned
+        assert!(report.files.iter().all(|f| !f.path.starts_with(MODS_DIR)));
+
+        manager.restore().unwrap();
+        let report = scan(&game_dir, &file_list, Some(&manager)).unwrap();
+        assert!(report.is_vanilla());
+    }
+
+    #[test]
+    fn attributes_mods_spelling_the_wad_differently() {
+        let dir = tempfile::tempdir().unwrap();
+        let (game_dir, file_list) = game(dir.path());
+        let path = dir.path().join(format!("my_mod.{}", EXTENSION));
+        let mut builder = PackageBuilder::new(Manifest::new("my_mod", Version::new(1, 0, 0)));
+        builder.add_file("root.wad/gamedata/BAR.txt", b"modded".to_vec());
+        builder.write(&path).unwrap();
+
+        let mut manager = ModManager::open(&game_dir).unwrap();
+        manager.install(&path).unwrap();
+        manager.enable("my_mod").unwrap();
+        manager.apply().unwrap();
+        let report = scan(&game_dir, &file_list, Some(&manager)).unwrap();
+        let root = report
+            .files
+            .iter()
+            .find(|f| f.path == "Data/GameData/Root.wad")
+            .unwrap();
+        assert_eq!(root.status, FileStatus::Modified);
+        assert_eq!(root.mods, ["my_mod"]);
+    }
+}
@@ -0,0 +1,767 @@
+pub mod type_dump;
+
+use serde::ser::{SerializeMap, Serializer};
+use serde::Serialize;
+use std::cell::Cell;
+
+use crate::packet_helper::message_helper::wad_helper::inflate;
+use type_dump::{PropertyType, TypeDump};
+
+// Binary-serialized ObjectProperty files (most of GameData that isn't xml)
+// start with this, followed by the serializer flags and the root object
+pub const MAGIC: &[u8; 4] = b"BINd";
+
+// Serializer flags
+const COMPACT_LENGTH_PREFIXES: u32 = 0x2;
+const HUMAN_READABLE_ENUMS: u32 = 0x4;
+const WITH_COMPRESSION: u32 = 0x8;
+
+// Unlike other property errors these aren't recovered from by keeping the
+// property raw
+const TOO_DEEP: &str = "Objects are nested too deep";
+const TOO_MANY_ELEMENTS: &str = "Lists have too many elements";
+
+// Most list elements reserved up front, the rest grow as they're decoded
+const PREALLOCATED_ELEMENTS: usize = 1024;
+
+// Caps on what a file may make us allocate or recurse into, BINd files come
+// from mods as much as from the game
+#[derive(Debug, Clone, Copy)]
+pub struct Limits {
+    // size the body may inflate to
+    pub max_size: u32,
+    // inflated size / compressed size
+    pub max_ratio: u32,
+    // objects nested in objects
+    pub max_depth: usize,
+    // list elements in the whole file
+    pub max_elements: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_size: 64 << 20,
+            max_ratio: 1100,
+            max_depth: 64,
+            max_elements: 1 << 22,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Null,
+    Bool(bool),
+    Int(i64),
+    UInt(u64),
+    Float(f64),
+    String(String),
+    Enum(String),
+    List(Vec<Value>),
+    Object(Box<Object>),
+    // bytes of a property whose type isn't known, or didn't decode as it
+    Raw(Vec<u8>),
+}
+
+impl Serialize for Value {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Value::Null => serializer.serialize_none(),
+            Value::Bool(b) => serializer.serialize_bool(*b),
+            Value::Int(i) => serializer.serialize_i64(*i),
+            Value::UInt(u) => serializer.serialize_u64(*u),
+            Value::Float(f) => serializer.serialize_f64(*f),
+            Value::String(s) | Value::Enum(s) => serializer.serialize_str(s),
+            Value::List(l) => l.serialize(serializer),
+            Value::Object(o) => o.serialize(serializer),
+            Value::Raw(bytes) => {
+                let mut map = serializer.serialize_map(Some(1))?;
+                map.serialize_entry("raw", &hex(bytes))?;
+                map.end()
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Property {
+    pub hash: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(rename = "type", skip_serializing_if = "Option::is_none")]
+    pub type_name: Option<String>,
+    pub value: Value,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Object {
+    #[serde(rename = "class")]
+    pub class_hash: u32,
+    #[serde(rename = "name", skip_serializing_if = "Option::is_none")]
+    pub class_name: Option<String>,
+    pub properties: Vec<Property>,
+}
+
+impl Object {
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).unwrap()
+    }
+
+    pub fn to_xml(&self) -> String {
+        let mut out = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+        write_object_xml(self, 0, &mut out);
+        out
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn escape_xml(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn write_object_xml(object: &Object, depth: usize, out: &mut String) {
+    let indent = "  ".repeat(depth);
+    out.push_str(&format!(
+        "{}<Object class=\"{}\"",
+        indent, object.class_hash
+    ));
+    if let Some(name) = &object.class_name {
+        out.push_str(&format!(" name=\"{}\"", escape_xml(name)));
+    }
+    out.push_str(">\n");
+    for prop in &object.properties {
+        out.push_str(&format!("{}  <Property hash=\"{}\"", indent, prop.hash));
+        if let Some(name) = &prop.name {
+            out.push_str(&format!(" name=\"{}\"", escape_xml(name)));
+        }
+        if let Some(type_name) = &prop.type_name {
+            out.push_str(&format!(" type=\"{}\"", escape_xml(type_name)));
+        }
+        write_value_xml(&prop.value, depth + 1, out);
+        out.push_str("</Property>\n");
+    }
+    out.push_str(&format!("{}</Object>\n", indent));
+}
+
+// Writes the rest of the opening tag and the contents, the caller closes it
+fn write_value_xml(value: &Value, depth: usize, out: &mut String) {
+    let indent = "  ".repeat(depth);
+    match value {
+        Value::Null => out.push_str(" null=\"true\">"),
+        Value::Bool(b) => out.push_str(&format!(">{}", b)),
+        Value::Int(i) => out.push_str(&format!(">{}", i)),
+        Value::UInt(u) => out.push_str(&format!(">{}", u)),
+        Value::Float(f) => out.push_str(&format!(">{}", f)),
+        Value::String(s) | Value::Enum(s) => out.push_str(&format!(">{}", escape_xml(s))),
+        Value::Raw(bytes) => out.push_str(&format!(" raw=\"true\">{}", hex(bytes))),
+        Value::Object(o) => {
+            out.push_str(">\n");
+            write_object_xml(o, depth + 1, out);
+            out.push_str(&indent);
+        }
+        Value::List(items) => {
+            out.push_str(">\n");
+            for item in items {
+                out.push_str(&format!("{}  <Item", indent));
+                write_value_xml(item, depth + 1, out);
+                out.push_str("</Item>\n");
+            }
+            out.push_str(&indent);
+        }
+    }
+}
+
+// Values are packed least significant bit first, only strings and other byte
+// buffers start on a byte boundary
+struct BitReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> BitReader<'a> {
+    fn len(&self) -> usize {
+        self.data.len() * 8
+    }
+
+    fn seek(&mut self, pos: usize) {
+        self.pos = pos;
+    }
+
+    fn realign(&mut self) {
+        self.pos = (self.pos + 7) & !7;
+    }
+
+    fn read_bits(&mut self, n: u32) -> Result<u64, String> {
+        if self.pos + n as usize > self.len() {
+            return Err(format!("Unexpected end of data at bit {}", self.pos));
+        }
+        let mut val = 0u64;
+        for i in 0..n {
+            let bit = (self.data[self.pos / 8] >> (self.pos % 8)) & 1;
+            val |= (bit as u64) << i;
+            self.pos += 1;
+        }
+        Ok(val)
+    }
+
+    fn bit(&mut self) -> Result<bool, String> {
+        Ok(self.read_bits(1)? == 1)
+    }
+
+    fn u32(&mut self) -> Result<u32, String> {
+        Ok(self.read_bits(32)? as u32)
+    }
+
+    // sign extends an `n` bit value
+    fn signed(&mut self, n: u32) -> Result<i64, String> {
+        let val = self.read_bits(n)?;
+        let shift = 64 - n;
+        Ok(((val << shift) as i64) >> shift)
+    }
+
+    fn bytes(&mut self, n: usize) -> Result<&'a [u8], String> {
+        self.realign();
+        let start = self.pos / 8;
+        if start + n > self.data.len() {
+            return Err(format!("Unexpected end of data at byte {}", start));
+        }
+        self.pos += n * 8;
+        Ok(&self.data[start..start + n])
+    }
+}
+
+struct Decoder<'a> {
+    types: Option<&'a TypeDump>,
+    flags: u32,
+    limits: Limits,
+    // list elements claimed so far, against `limits.max_elements`
+    elements: Cell<usize>,
+}
+
+impl<'a> Decoder<'a> {
+    fn length(&self, r: &mut BitReader, string: bool) -> Result<usize, String> {
+        let len = if self.flags & COMPACT_LENGTH_PREFIXES != 0 {
+            if r.bit()? {
+                r.read_bits(31)?
+            } else {
+                r.read_bits(7)?
+            }
+        } else if string {
+            r.read_bits(16)?
+        } else {
+            r.read_bits(32)?
+        };
+        Ok(len as usize)
+    }
+
+    fn floats(&self, r: &mut BitReader, n: usize) -> Result<Value, String> {
+        let mut items = Vec::new();
+        for _ in 0..n {
+            items.push(Value::Float(f32::from_bits(r.u32()?) as f64));
+        }
+        Ok(Value::List(items))
+    }
+
+    fn ints(&self, r: &mut BitReader, n: usize, bits: u32) -> Result<Value, String> {
+        let mut items = Vec::new();
+        for _ in 0..n {
+            items.push(Value::Int(r.signed(bits)?));
+        }
+        Ok(Value::List(items))
+    }
+
+    // `depth` is that of the object the value is in
+    fn value(&self, r: &mut BitReader, ty: &PropertyType, depth: usize) -> Result<Value, String> {
+        let type_name = ty.type_name.as_str();
+        let bare = type_name
+            .trim_start_matches("class ")
+            .trim_start_matches("struct ");
+
+        Ok(match bare {
+            "bool" => Value::Bool(r.bit()?),
+            "char" | "signed char" => Value::Int(r.signed(8)?),
+            "unsigned char" => Value::UInt(r.read_bits(8)?),
+            "short" => Value::Int(r.signed(16)?),
+            "unsigned short" | "wchar_t" => Value::UInt(r.read_bits(16)?),
+            "int" | "long" => Value::Int(r.signed(32)?),
+            "unsigned int" | "unsigned long" => Value::UInt(r.read_bits(32)?),
+            "__int64" => Value::Int(r.read_bits(64)? as i64),
+            "unsigned __int64" | "gid" => Value::UInt(r.read_bits(64)?),
+            "float" => Value::Float(f32::from_bits(r.u32()?) as f64),
+            "double" => Value::Float(f64::from_bits(r.read_bits(64)?)),
+            "s24" => Value::Int(r.signed(24)?),
+            "u24" => Value::UInt(r.read_bits(24)?),
+            "std::string" => {
+                let len = self.length(r, true)?;
+                Value::String(String::from_utf8_lossy(r.bytes(len)?).into_owned())
+            }
+            "std::wstring" => {
+                let len = self.length(r, true)?;
+                let units: Vec<u16> = r
+                    .bytes(len * 2)?
+                    .chunks_exact(2)
+                    .map(|c| u16::from_le_bytes([c[0], c[1]]))
+                    .collect();
+                Value::String(String::from_utf16_lossy(&units))
+            }
+            "Vector3D" | "Euler" => self.floats(r, 3)?,
+            "Quaternion" => self.floats(r, 4)?,
+            "Matrix3x3" => self.floats(r, 9)?,
+            "Point<float>" | "Size<float>" => self.floats(r, 2)?,
+            "Rect<float>" => self.floats(r, 4)?,
+            "Point<int>" | "Size<int>" => self.ints(r, 2, 32)?,
+            "Rect<int>" => self.ints(r, 4, 32)?,
+            // stored as b, g, r, a
+            "Color" => {
+                let mut items = Vec::new();
+                for _ in 0..4 {
+                    items.push(Value::UInt(r.read_bits(8)?));
+                }
+                Value::List(items)
+            }
+            _ if type_name.starts_with("bui") => {
+                Value::UInt(r.read_bits(bit_width(type_name, "bui")?)?)
+            }
+            _ if type_name.starts_with("bi") => Value::Int(r.signed(bit_width(type_name, "bi")?)?),
+            _ if type_name.starts_with("enum ") => {
+                if self.flags & HUMAN_READABLE_ENUMS != 0 {
+                    let len = self.length(r, true)?;
+                    Value::Enum(String::from_utf8_lossy(r.bytes(len)?).into_owned())
+                } else {
+                    let val = r.u32()?;
+                    match ty.enum_options.get(&val) {
+                        Some(option) => Value::Enum(option.clone()),
+                        None => Value::UInt(val as u64),
+                    }
+                }
+            }
+            // anything else that is a class is a nested (or pointed to) object
+            _ if type_name.starts_with("class ") || type_name.starts_with("struct ") => {
+                match self.object(r, depth + 1)? {
+                    Some(object) => Value::Object(Box::new(object)),
+                    None => Value::Null,
+                }
+            }
+            _ => return Err(format!("Unknown type '{}'", type_name)),
+        })
+    }
+
+    fn property(
+        &self,
+        r: &mut BitReader,
+        ty: &PropertyType,
+        end: usize,
+        depth: usize,
+    ) -> Result<Value, String> {
+        if !ty.dynamic {
+            return self.value(r, ty, depth);
+        }
+        let count = self.length(r, false)?;
+        // every element takes at least a bit
+        let room = end
+            .checked_sub(r.pos)
+            .ok_or(String::from("List length runs past its property"))?;
+        if count > room {
+            return Err(format!(
+                "List of {} elements doesn't fit its property",
+                count
+            ));
+        }
+        let elements = self.elements.get().saturating_add(count);
+        if elements > self.limits.max_elements {
+            return Err(String::from(TOO_MANY_ELEMENTS));
+        }
+        self.elements.set(elements);
+        // the count is only what the file says
+        let mut items = Vec::with_capacity(count.min(PREALLOCATED_ELEMENTS));
+        for _ in 0..count {
+            items.push(self.value(r, ty, depth)?);
+        }
+        Ok(Value::List(items))
+    }
+
+    // Bits from here to `end`, for properties that can't be decoded
+    fn raw(&self, r: &mut BitReader, end: usize) -> Result<Value, String> {
+        let mut bytes = Vec::new();
+        while r.pos < end {
+            let n = (end - r.pos).min(8) as u32;
+            bytes.push(r.read_bits(n)? as u8);
+        }
+        Ok(Value::Raw(bytes))
+    }
+
+    // Objects are their class hash and size, then every property with its
+    // size and hash. The sizes (in bits, counting the size itself) let
+    // properties of unknown classes and types be skipped.
+    fn object(&self, r: &mut BitReader, depth: usize) -> Result<Option<Object>, String> {
+        let class_hash = r.u32()?;
+        if class_hash == 0 {
+            return Ok(None);
+        }
+        if depth > self.limits.max_depth {
+            return Err(String::from(TOO_DEEP));
+        }
+
+        let start = r.pos;
+        let end = start + r.u32()? as usize;
+        if end > r.len() || end < r.pos {
+            return Err(format!(
+                "Object {} at bit {} has an invalid size",
+                class_hash, start
+            ));
+        }
+
+        let class = self.types.and_then(|t| t.class(class_hash));
+        let mut properties = Vec::new();
+        while r.pos < end {
+            let prop_start = r.pos;
+            let prop_end = prop_start + r.u32()? as usize;
+            if prop_end > end || prop_end < prop_start + 64 {
+                return Err(format!(
+                    "Property at bit {} in object {} has an invalid size",
+                    prop_start, class_hash
+                ));
+            }
+            let hash = r.u32()?;
+            let value_start = r.pos;
+
+            let ty = class.and_then(|c| c.properties.get(&hash));
+            let value = match ty.map(|ty| self.property(r, ty, prop_end, depth)) {
+                Some(Ok(value)) if r.pos <= prop_end => value,
+                Some(Err(e)) if e == TOO_DEEP || e == TOO_MANY_ELEMENTS => return Err(e),
+                _ => {
+                    r.seek(value_start);
+                    self.raw(r, prop_end)?
+                }
+            };
+            r.seek(prop_end);
+
+            properties.push(Property {
+                hash,
+                name: ty.map(|t| t.name.clone()),
+                type_name: ty.map(|t| t.type_name.clone()),
+                value,
+            });
+        }
+        r.seek(end);
+
+        Ok(Some(Object {
+            class_hash,
+            class_name: class.map(|c| c.name.clone()),
+            properties,
+        }))
+    }
+}
+
+fn bit_width(type_name: &str, prefix: &str) -> Result<u32, String> {
+    match type_name[prefix.len()..].parse::<u32>() {
+        Ok(n) if (1..=64).contains(&n) => Ok(n),
+        _ => Err(format!("Unknown type '{}'", type_name)),
+    }
+}
+
+pub fn is_bind(data: &[u8]) -> bool {
+    data.starts_with(MAGIC)
+}
+
+// Decodes a BINd file into its root object. Without `types` only class and
+// property hashes are known and every value is left raw.
+pub fn decode(data: &[u8], types: Option<&TypeDump>) -> Result<Object, String> {
+    decode_with_limits(data, types, Limits::default())
+}
+
+pub fn decode_with_limits(
+    data: &[u8],
+    types: Option<&TypeDump>,
+    limits: Limits,
+) -> Result<Object, String> {
+    if !is_bind(data) || data.len() < 8 {
+        return Err(String::from("Not a BINd file"));
+    }
+    let flags = u32::from_le_bytes([data[4], data[5], data[6], data[7]]);
+    let mut body = &data[8..];
+
+    let inflated;
+    if flags & WITH_COMPRESSION != 0 && body.first().is_some_and(|c| *c != 0) {
+        if body.len() < 5 {
+            return Err(String::from("Compressed BINd file is truncated"));
+        }
+        let size = u32::from_le_bytes([body[1], body[2], body[3], body[4]]);
+        if size > limits.max_size {
+            return Err(format!(
+                "BINd file inflates to {} bytes, over the limit of {}",
+                size, limits.max_size
+            ));
+        }
+        if size as u64 > (body.len() - 5) as u64 * limits.max_ratio as u64 {
+            return Err(format!(
+                "BINd file claims to inflate {} bytes to {}, over the ratio limit of {}",
+                body.len() - 5,
+                size,
+                limits.max_ratio
+            ));
+        }
+        inflated = inflate(&body[5..], size as usize)?;
+        body = &inflated;
+    } else if flags & WITH_COMPRESSION != 0 {
+        body = body.get(1..).unwrap_or_default();
+    }
+
+    let decoder = Decoder {
+        types,
+        flags,
+        limits,
+        elements: Cell::new(0),
+    };
+    let mut reader = BitReader { data: body, pos: 0 };
+    decoder
+        .object(&mut reader, 0)?
+        .ok_or(String::from("BINd file holds a null object"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Packs values least significant bit first, like the game writes them
+    #[derive(Default)]
+    struct BitWriter {
+        data: Vec<u8>,
+        pos: usize,
+    }
+
+    impl BitWriter {
+        fn bits(&mut self, val: u64, n: u32) -> &mut Self {
+            for i in 0..n {
+                if self.pos.is_multiple_of(8) {
+                    self.data.push(0);
+                }
+                self.data[self.pos / 8] |= (((val >> i) & 1) as u8) << (self.pos % 8);
+                self.pos += 1;
+            }
+            self
+        }
+
+        fn u32(&mut self, val: u32) -> &mut Self {
+            self.bits(val as u64, 32)
+        }
+    }
+
+    const CLASS: u32 = 0x1000;
+    const NAME: u32 = 0x1001;
+    const LEVEL: u32 = 0x1002;
+    const ITEMS: u32 = 0x1003;
+    const CHILD: u32 = 0x1004;
+
+    fn types() -> TypeDump {
+        TypeDump::parse(&format!(
+            r#"{{"classes": {{"{}": {{"name": "class Thing", "properties": {{
+                "m_name": {{"type": "std::string", "hash": {}}},
+                "m_level": {{"type": "int", "hash": {}}},
+                "m_items": {{"type": "unsigned int", "hash": {}, "dynamic": true}},
+                "m_child": {{"type": "class Thing*", "hash": {}}}
+            }}}}}}}}"#,
+            CLASS, NAME, LEVEL, ITEMS, CHILD
+        ))
+        .unwrap()
+    }
+
+    // An object whose properties are (hash, bits) pairs, sizes filled in
+    fn object(class: u32, props: &[(u32, BitWriter)]) -> BitWriter {
+        let mut body = BitWriter::default();
+        for (hash, value) in props {
+            body.u32(64 + value.pos as u32).u32(*hash);
+            for i in 0..value.pos {
+                body.bits((value.data[i / 8] >> (i % 8)) as u64, 1);
+            }
+        }
+        let mut w = BitWriter::default();
+        w.u32(class).u32(32 + body.pos as u32);
+        for i in 0..body.pos {
+            w.bits((body.data[i / 8] >> (i % 8)) as u64, 1);
+        }
+        w
+    }
+
+    fn file(flags: u32, body: &[u8]) -> Vec<u8> {
+        let mut data = MAGIC.to_vec();
+        data.extend(flags.to_le_bytes());
+        data.extend(body);
+        data
+    }
+
+    fn string(s: &str) -> BitWriter {
+        let mut w = BitWriter::default();
+        w.bits(s.len() as u64, 16);
+        for b in s.bytes() {
+            w.bits(b as u64, 8);
+        }
+        w
+    }
+
+    fn int(i: i32) -> BitWriter {
+        let mut w = BitWriter::default();
+        w.u32(i as u32);
+        w
+    }
+
+    fn list(count: u32, items: &[u32]) -> BitWriter {
+        let mut w = BitWriter::default();
+        w.u32(count);
+        for i in items {
+            w.u32(*i);
+        }
+        w
+    }
+
+    // `levels` objects, each the child of the one before
+    fn nested(levels: usize) -> BitWriter {
+        let mut inner = object(CLASS, &[(LEVEL, int(levels as i32))]);
+        for _ in 1..levels {
+            inner = object(CLASS, &[(CHILD, inner)]);
+        }
+        inner
+    }
+
+    #[test]
+    fn decodes_known_properties() {
+        let types = types();
+        let body = object(
+            CLASS,
+            &[
+                (NAME, string("Wizard")),
+                (LEVEL, int(-3)),
+                (ITEMS, list(2, &[7, 9])),
+            ],
+        );
+        let root = decode(&file(0, &body.data), Some(&types)).unwrap();
+        assert_eq!(root.class_name.as_deref(), Some("class Thing"));
+        let values: Vec<_> = root.properties.iter().map(|p| p.value.clone()).collect();
+        assert_eq!(
+            values,
+            vec![
+                Value::String(String::from("Wizard")),
+                Value::Int(-3),
+                Value::List(vec![Value::UInt(7), Value::UInt(9)]),
+            ]
+        );
+        assert_eq!(root.properties[0].name.as_deref(), Some("m_name"));
+    }
+
+    #[test]
+    fn unknown_types_stay_raw() {
+        let body = object(CLASS, &[(LEVEL, int(5))]);
+        let root = decode(&file(0, &body.data), None).unwrap();
+        assert_eq!(root.properties[0].value, Value::Raw(vec![5, 0, 0, 0]));
+    }
+
+    #[test]
+    fn oversized_list_stays_raw() {
+        let types = types();
+        // claims far more elements than there are bits left
+        let body = object(CLASS, &[(ITEMS, list(u32::MAX, &[1]))]);
+        let root = decode(&file(0, &body.data), Some(&types)).unwrap();
+        assert!(matches!(root.properties[0].value, Value::Raw(_)));
+
+        // the count alone runs past the end of its property
+        let mut short = BitWriter::default();
+        short.bits(0, 16);
+        let body = object(CLASS, &[(ITEMS, short)]);
+        let root = decode(&file(0, &body.data), Some(&types)).unwrap();
+        assert_eq!(root.properties[0].value, Value::Raw(vec![0, 0]));
+    }
+
+    #[test]
+    fn list_elements_are_limited() {
+        let types = types();
+        // a count that fits the property's bits, but not as u32s
+        let mut huge = list(100_000, &[]);
+        for _ in 0..100_000 / 32 {
+            huge.u32(0);
+        }
+        let body = object(CLASS, &[(ITEMS, huge)]);
+        let root = decode(&file(0, &body.data), Some(&types)).unwrap();
+        assert!(matches!(root.properties[0].value, Value::Raw(_)));
+
+        let limits = Limits {
+            max_elements: 3,
+            ..Limits::default()
+        };
+        let body = object(CLASS, &[(ITEMS, list(2, &[7, 9]))]);
+        assert!(decode_with_limits(&file(0, &body.data), Some(&types), limits).is_ok());
+        // counted over the whole file
+        let body = object(
+            CLASS,
+            &[(ITEMS, list(2, &[7, 9])), (ITEMS, list(2, &[7, 9]))],
+        );
+        let err = decode_with_limits(&file(0, &body.data), Some(&types), limits).unwrap_err();
+        assert_eq!(err, TOO_MANY_ELEMENTS);
+
+        // one element past the default limit, with a bit for each
+        let count = Limits::default().max_elements + 1;
+        let mut over = list(count as u32, &[]);
+        for _ in 0..count.div_ceil(32) {
+            over.u32(0);
+        }
+        let body = object(CLASS, &[(ITEMS, over)]);
+        let err = decode(&file(0, &body.data), Some(&types)).unwrap_err();
+        assert_eq!(err, TOO_MANY_ELEMENTS);
+    }
+
+    #[test]
+    fn nesting_is_limited() {
+        let types = types();
+        let limits = Limits {
+            max_depth: 4,
+            ..Limits::default()
+        };
+        let ok = nested(5);
+        assert!(decode_with_limits(&file(0, &ok.data), Some(&types), limits).is_ok());
+        let deep = nested(6);
+        let err = decode_with_limits(&file(0, &deep.data), Some(&types), limits).unwrap_err();
+        assert_eq!(err, TOO_DEEP);
+    }
+
+    #[test]
+    fn compressed_size_is_limited() {
+        let body = object(CLASS, &[(LEVEL, int(5))]);
+        let mut compressor = libdeflater::Compressor::new(Default::default());
+        let mut compressed = vec![0; compressor.zlib_compress_bound(body.data.len())];
+        let len = compressor
+            .zlib_compress(&body.data, &mut compressed)
+            .unwrap();
+        compressed.truncate(len);
+
+        let packed = |size: u32| {
+            let mut packed = vec![1];
+            packed.extend(size.to_le_bytes());
+            packed.extend(&compressed);
+            file(WITH_COMPRESSION, &packed)
+        };
+        let root = decode(&packed(body.data.len() as u32), None).unwrap();
+        assert_eq!(root.properties[0].value, Value::Raw(vec![5, 0, 0, 0]));
+
+        let err = decode(&packed(u32::MAX), None).unwrap_err();
+        assert!(err.contains("over the limit"), "{}", err);
+        let err = decode(&packed(1 << 20), None).unwrap_err();
+        assert!(err.contains("ratio"), "{}", err);
+    }
+
+    #[test]
+    fn rejects_bad_headers() {
+        assert!(decode(b"BINd", None).is_err());
+        assert!(decode(b"XMLd\0\0\0\0", None).is_err());
+        assert!(decode(&file(WITH_COMPRESSION, &[1, 2]), None).is_err());
+        // root object with a size past the end of the file
+        let mut w = BitWriter::default();
+        w.u32(CLASS).u32(1000);
+        assert!(decode(&file(0, &w.data), None).is_err());
+    }
+}
@@ -0,0 +1,175 @@
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+// Type dump as written by wiztype (v2): every class with its properties, keyed
+// by class hash. Only what the decoder needs is read, the rest is ignored.
+#[derive(Debug, Deserialize)]
+struct RawDump {
+    classes: HashMap<String, RawClass>,
+}
+
+#[derive(Debug, Deserialize)]
+struct RawClass {
+    name: String,
+    #[serde(default)]
+    properties: HashMap<String, RawProperty>,
+}
+
+#[derive(Debug, Deserialize)]
+struct RawProperty {
+    #[serde(rename = "type")]
+    type_name: String,
+    hash: u32,
+    #[serde(default)]
+    container: Option<String>,
+    #[serde(default)]
+    dynamic: bool,
+    #[serde(default)]
+    enum_options: HashMap<String, serde_json::Value>,
+}
+
+#[derive(Debug, Clone)]
+pub struct PropertyType {
+    pub name: String,
+    pub type_name: String,
+    // lists and vectors are prefixed with an element count
+    pub dynamic: bool,
+    // enum value -> option name
+    pub enum_options: HashMap<u32, String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ClassType {
+    pub name: String,
+    pub properties: HashMap<u32, PropertyType>,
+}
+
+#[derive(Debug, Default)]
+pub struct TypeDump {
+    classes: HashMap<u32, ClassType>,
+}
+
+impl TypeDump {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<TypeDump, String> {
+        let path = path.as_ref();
+        let contents = fs::read_to_string(path)
+            .or(Err(format!("Couldn't read type dump '{}'", path.display())))?;
+        TypeDump::parse(&contents)
+            .map_err(|e| format!("Invalid type dump '{}': {}", path.display(), e))
+    }
+
+    pub fn parse(contents: &str) -> Result<TypeDump, String> {
+        let raw: RawDump = serde_json::from_str(contents).map_err(|e| e.to_string())?;
+
+        let mut classes = HashMap::new();
+        for (hash, class) in raw.classes {
+            let hash = hash
+                .parse::<u32>()
+                .or(Err(format!("Invalid class hash '{}'", hash)))?;
+            let properties = class
+                .properties
+                .into_iter()
+                .map(|(name, prop)| {
+                    let dynamic =
+                        prop.dynamic || prop.container.as_deref().is_some_and(|c| c != "Static");
+                    // options are either plain numbers or, for flags, strings
+                    let enum_options = prop
+                        .enum_options
+                        .into_iter()
+                        .filter_map(|(option, val)| {
+                            let val = match val {
+                                serde_json::Value::Number(n) => u32::try_from(n.as_u64()?).ok()?,
+                                serde_json::Value::String(s) => s.parse().ok()?,
+                                _ => return None,
+                            };
+                            Some((val, option))
+                        })
+                        .collect();
+                    (
+                        prop.hash,
+                        PropertyType {
+                            name,
+                            type_name: prop.type_name,
+                            dynamic,
+                            enum_options,
+                        },
+                    )
+                })
+                .collect();
+            classes.insert(
+                hash,
+                ClassType {
+                    name: class.name,
+                    properties,
+                },
+            );
+        }
+        Ok(TypeDump { classes })
+    }
+
+    pub fn class(&self, hash: u32) -> Option<&ClassType> {
+        self.classes.get(&hash)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DUMP: &str = r#"{
+        "version": 2,
+        "classes": {
+            "12345": {
+                "name": "class ItemTemplate",
+                "bases": ["class CoreTemplate"],
+                "properties": {
+                    "m_name": { "type": "std::string", "hash": 1, "container": "Static" },
+                    "m_tags": { "type": "std::string", "hash": 2, "container": "List" },
+                    "m_ids": { "type": "unsigned int", "hash": 3, "dynamic": true },
+                    "m_school": {
+                        "type": "enum SchoolType",
+                        "hash": 4,
+                        "enum_options": {
+                            "Fire": 2, "Ice": "3", "__DEFAULT": "Fire", "Huge": 4294967298
+                        }
+                    }
+                }
+            }
+        }
+    }"#;
+
+    #[test]
+    fn reads_classes_and_properties() {
+        let dump = TypeDump::parse(DUMP).unwrap();
+        let class = dump.class(12345).unwrap();
+        assert_eq!(class.name, "class ItemTemplate");
+        assert_eq!(class.properties.len(), 4);
+        assert!(dump.class(1).is_none());
+
+        let dynamic = |hash| class.properties[&hash].dynamic;
+        assert!(!dynamic(1));
+        assert!(dynamic(2));
+        assert!(dynamic(3));
+
+        // options that aren't numbers, or don't fit in a u32, are dropped
+        let school = &class.properties[&4];
+        assert_eq!(school.name, "m_school");
+        assert_eq!(school.type_name, "enum SchoolType");
+        assert_eq!(school.enum_options.len(), 2);
+        assert_eq!(school.enum_options[&2], "Fire");
+        assert_eq!(school.enum_options[&3], "Ice");
+    }
+
+    #[test]
+    fn rejects_bad_dumps() {
+        let err =
+            TypeDump::parse(r#"{ "classes": { "abc": { "name": "class A" } } }"#).unwrap_err();
+        assert_eq!(err, "Invalid class hash 'abc'");
+        assert!(TypeDump::parse(r#"{ "types": [] }"#).is_err());
+        assert!(TypeDump::load("no/such/dump.json")
+            .unwrap_err()
+            .starts_with("Couldn't read type dump"));
+    }
+}
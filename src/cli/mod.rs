@@ -0,0 +1,1237 @@
+use base64::{engine::general_purpose, Engine as _};
+use std::env;
+use std::fs;
+use std::path::Path;
+use zeroize::Zeroize;
+
+use crate::bind_helper::{self, type_dump::TypeDump};
+use crate::crypto::rec1;
+use crate::mod_helper::{
+    conflicts::find_conflicts,
+    manager::{ApplyReport, InstallReport, ModManager},
+    package::{Package, PackageBuilder},
+    repository::Repository,
+    resolver::{resolve, stack_layers},
+    signing::PublisherKey,
+    version::VersionReq,
+    vfs::Vfs,
+    xml_patch::{apply_patches, PatchSet},
+};
+use crate::packet_helper::message_helper::wad_helper::{
+    convert::{self, Manifest},
+    diff::{apply_delta, diff, write_delta},
+    extract::{entry_path, extract, ExtractOptions},
+    index::{decode_text, ContentIndex, UpdateReport},
+    lang::{self, LangFile},
+    query::Query,
+    verify::verify,
+    writer::Writer,
+    Archive,
+};
+use crate::table_list_parser::TableList;
+use crate::PatchClient::scan::{scan, FileStatus};
+
+const USAGE: &str = "Usage:
+  Wizard101Launcher [--services <file>]... [--verify] [--client-dir <dir>]
+      patches the game and launches it, logging in as WIZARD101_USERNAME with
+      WIZARD101_PASSWORD from the environment. --services layers extra message
+      service definitions (eg. for a private server) over Root.wad's, in order.
+      --verify checks the game files that are already there against the file
+      list and downloads the ones that don't match again. --client-dir is
+      where the client is started from (default ./test/Bin)
+  Wizard101Launcher wad list <wad> [query options]
+  Wizard101Launcher wad verify <wad>
+  Wizard101Launcher wad extract <wad> <dir> [--include <glob>]... [--exclude <glob>]... [--threads <n>]
+  Wizard101Launcher wad convert <from> <to> [--manifest <file>] [--level <0-12>]
+      converts between .wad and .zip/.tar/a directory, the manifest keeps the
+      wad header and compression flags so the round trip gives the same wad
+  Wizard101Launcher wad diff <old> <new> [--delta <out>]
+  Wizard101Launcher wad patch <old> <delta> <out>
+  Wizard101Launcher wad repack <wad> <out> [--put <entry>=<file>]... [--remove <entry>]... [--level <0-12>]
+  Wizard101Launcher index <game dir> [--index <file>]
+  Wizard101Launcher search <game dir> <words> [--index <file>] [--no-update]
+      finds the lines of text entries (xml, lang, ...) that have all of the
+      words in any wad, the index is updated first unless --no-update
+  Wizard101Launcher vfs <game data dir> list [<dir>] [layer options]
+  Wizard101Launcher vfs <game data dir> which <path> [layer options]
+  Wizard101Launcher vfs <game data dir> read <path> <out> [layer options]
+  Wizard101Launcher vfs <game data dir> build <wad> <out> [layer options]
+      paths are <wad>/<entry>, eg. Root.wad/GameMessages.xml. The game's wads
+      are the bottom layer, the layer options stack on top of them in order:
+        --dir <dir>            loose files laid out like the vfs
+        --wad <wad>=<mount>    a mod wad going into the wad at <mount>
+        --mod <package>        a mod package, these go on top of the rest in
+                               the load order of their dependencies
+  Wizard101Launcher xml-patch <game data dir> <out dir> <patch file>... [layer options]
+      applies xml patch files in order on top of the vfs and writes the
+      patched entries to <out dir>, laid out to be used as a --dir layer
+  Wizard101Launcher mod build <source dir> <out.midas> [--sign <key file>]
+      packs manifest.json, files/<wad>/<entry> and patches/*.json from the
+      source dir into a mod package, filling in the checksums, and signs the
+      manifest with the publisher key
+  Wizard101Launcher mod keygen <publisher> <key file>
+      makes a publisher key for signing and prints its public half
+  Wizard101Launcher mod order <package>...
+      prints the load order of the packages, or why there is none
+  Wizard101Launcher mod info <package>
+  Wizard101Launcher mod validate <package>
+  Wizard101Launcher mod install <game dir> <package>... [--file-list <file>]
+  Wizard101Launcher mod uninstall <game dir> <id>... [--file-list <file>]
+  Wizard101Launcher mod enable <game dir> <id>... [--file-list <file>]
+  Wizard101Launcher mod disable <game dir> <id>... [--file-list <file>]
+  Wizard101Launcher mod apply <game dir> [--file-list <file>]
+  Wizard101Launcher mod restore <game dir> [--file-list <file>]
+  Wizard101Launcher mod reapply <game dir> [--file-list <file>] [--revision <name>]
+  Wizard101Launcher mod list <game dir>
+  Wizard101Launcher mod conflicts <game dir> [--json]
+  Wizard101Launcher mod trust <game dir> <publisher> <public key>
+  Wizard101Launcher mod untrust <game dir> <publisher>
+  Wizard101Launcher mod policy <game dir> [allow|warn|deny]
+      installed mods live in <game dir>/Mods, the game files they change are
+      backed up there first. Restore disables every mod and puts the original
+      files back, --file-list (LatestFileList.bin) checks them against the
+      game's own sizes and crcs. Reapply takes modded files that a game update
+      replaced as the new originals and applies the mods again, marking the
+      ones that change entries the update changed, or whose patches fail, as
+      needing review. Conflicts lists where the
+      enabled mods overlap (same game file, wad entry or xml selector) and
+      which of them wins in the load order, --json prints it for other tools.
+      Installed packages that are signed have to be signed by a trusted
+      publisher, the policy says what happens to unsigned ones (warn by
+      default)
+  Wizard101Launcher repo search <repository> [<words>...] [--cache <dir>]
+  Wizard101Launcher repo install <game dir> <repository> <id>[@<version>]... [--cache <dir>] [--file-list <file>]
+  Wizard101Launcher repo upgrade <game dir> <repository> [--cache <dir>] [--file-list <file>]
+      a repository is an http(s) or file:// url or a directory with an
+      index.json of mods. Install downloads the newest matching version and
+      any missing dependencies, checks them against the index's checksums
+      and enables them. The index and packages are cached in --cache
+      (default mod_cache), searching and installing work from the cache
+      when the repository can't be reached
+  Wizard101Launcher scan <game dir> [--file-list <file>] [--all] [--json]
+      compares every file in the game dir against the file list (default
+      LatestFileList.bin) and lists the ones that are modified, missing or
+      extra, with the enabled mods that changed them. --all lists the
+      vanilla files too
+  Wizard101Launcher lang dump <wad> <entry>
+  Wizard101Launcher lang lookup <game dir> <id>
+      finds a string id (<section>_<key> or just the key) in every wad
+  Wizard101Launcher lang set <lang file> <key> <text>
+  Wizard101Launcher lang remove <lang file> <key>
+      sets (or adds) or removes a string in an extracted .lang file, keeping
+      the rest of the file as it was
+  Wizard101Launcher bind <file> [--types <dump.json>] [--xml]
+  Wizard101Launcher bind <wad> <entry> [--types <dump.json>] [--xml]
+      decodes a BINd object file to json (or xml), the type dump resolves
+      class and property hashes to names and lets values be decoded
+  Wizard101Launcher rec1 hash
+  Wizard101Launcher rec1 check <rec1> <sid> <time secs> <time millis> [--hash <password hash>]
+  Wizard101Launcher rec1 reply <username> <sid> <time secs> <time millis>
+      for test servers. Hash prints what to store for the password read from
+      stdin. Check decrypts a client's base64 rec1 and checks its ck1 against
+      the password hash, or the password read from stdin. Reply makes the
+      base64 rec1 answering a login and prints it with the ck2 in it
+
+Query options:
+  --glob <pattern>     match entry paths against a glob, eg. 'GameData/*.xml'
+  --regex <pattern>    match entry paths against a regex
+  --min-size <bytes>   only entries at least this big (uncompressed)
+  --max-size <bytes>   only entries at most this big (uncompressed)
+  --compressed         only compressed entries
+  --stored             only uncompressed entries";
+
+// `--flag value` pairs in the order they were given
+type Flags = Vec<(String, String)>;
+
+// Splits `args` into positional arguments and `--flag value` pairs. Flags in
+// `switches` don't take a value.
+fn parse_args(args: &[String], switches: &[&str]) -> Result<(Vec<String>, Flags), String> {
+    let mut positional = Vec::new();
+    let mut flags = Vec::new();
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        if !arg.starts_with("--") {
+            positional.push(arg.clone());
+        } else if switches.contains(&arg.as_str()) {
+            flags.push((arg.clone(), String::new()));
+        } else {
+            match iter.next() {
+                Some(val) => flags.push((arg.clone(), val.clone())),
+                None => return Err(format!("{} needs a value", arg)),
+            }
+        }
+    }
+    Ok((positional, flags))
+}
+
+fn parse_size(val: &str) -> Result<u32, String> {
+    val.parse::<u32>()
+        .or(Err(format!("Invalid size '{}'", val)))
+}
+
+const QUERY_SWITCHES: [&str; 2] = ["--compressed", "--stored"];
+
+// Builds a query from the query options, returns None if `flag` isn't one
+fn apply_query_flag(query: Query, flag: &str, val: &str) -> Option<Result<Query, String>> {
+    Some(match flag {
+        "--glob" => query.glob(val),
+        "--regex" => query.regex(val),
+        "--min-size" => parse_size(val).map(|s| query.min_size(s)),
+        "--max-size" => parse_size(val).map(|s| query.max_size(s)),
+        "--compressed" => Ok(query.compressed(true)),
+        "--stored" => Ok(query.compressed(false)),
+        _ => return None,
+    })
+}
+
+fn wad_list(args: &[String]) -> Result<(), String> {
+    let (positional, flags) = parse_args(args, &QUERY_SWITCHES)?;
+    let wad = match positional.as_slice() {
+        [wad] => wad,
+        _ => return Err(String::from(USAGE)),
+    };
+
+    let mut query = Query::new();
+    for (flag, val) in flags {
+        query = match apply_query_flag(query, &flag, &val) {
+            Some(q) => q?,
+            None => return Err(format!("Unknown option {}\n\n{}", flag, USAGE)),
+        };
+    }
+
+    let archive = Archive::open(wad)?;
+    println!(
+        "{:>10} {:>10} {:>10} {:>4} {:>8}  name",
+        "offset", "size", "zip size", "zip", "crc"
+    );
+    for entry in query.run(&archive) {
+        println!(
+            "{:>10} {:>10} {:>10} {:>4} {:08x}  {}",
+            entry.offset,
+            entry.size,
+            if entry.compressed {
+                entry.zip_size.to_string()
+            } else {
+                String::from("-")
+            },
+            if entry.compressed { "yes" } else { "no" },
+            entry.crc,
+            entry.name
+        );
+    }
+    Ok(())
+}
+
+fn wad_repack(args: &[String]) -> Result<(), String> {
+    let (positional, flags) = parse_args(args, &[])?;
+    let (wad, out) = match positional.as_slice() {
+        [wad, out] => (wad, out),
+        _ => return Err(String::from(USAGE)),
+    };
+
+    let mut archive = Archive::open(wad)?;
+
+    // replaced entries keep their compression, new ones are compressed
+    let mut puts = Vec::new();
+    let mut removes = Vec::new();
+    let mut level = None;
+    for (flag, val) in flags {
+        match flag.as_str() {
+            "--put" => {
+                let (name, file) = val
+                    .split_once('=')
+                    .ok_or(format!("--put expects <entry>=<file>, got '{}'", val))?;
+                let compress = archive.entry(name).is_none_or(|e| e.compressed);
+                puts.push((name.to_string(), file.to_string(), compress));
+            }
+            "--remove" => {
+                if archive.entry(&val).is_none() {
+                    return Err(format!("{} doesn't exist in {}", val, wad));
+                }
+                removes.push(val);
+            }
+            "--level" => {
+                level = Some(
+                    val.parse::<i32>()
+                        .or(Err(format!("Invalid level '{}'", val)))?,
+                )
+            }
+            _ => return Err(format!("Unknown option {}\n\n{}", flag, USAGE)),
+        }
+    }
+
+    let mut writer = Writer::from_archive(&mut archive);
+    if let Some(level) = level {
+        writer.compression_level(level);
+    }
+    for name in &removes {
+        writer.remove(name);
+    }
+    for (name, file, compress) in &puts {
+        writer.add_file(name, file, *compress);
+    }
+    writer.write(out)
+}
+
+fn wad_verify(args: &[String]) -> Result<(), String> {
+    let wad = match args {
+        [wad] => wad,
+        _ => return Err(String::from(USAGE)),
+    };
+
+    let mut archive = Archive::open(wad)?;
+    let report = verify(&mut archive);
+    let failures = report.failures();
+    for failure in &failures {
+        println!("{}: {}", failure.name, failure.status);
+    }
+    println!(
+        "{} entries checked, {} failed",
+        report.entries.len(),
+        failures.len()
+    );
+
+    if !failures.is_empty() {
+        return Err(format!("{} failed verification", wad));
+    }
+    Ok(())
+}
+
+fn wad_extract(args: &[String]) -> Result<(), String> {
+    let (positional, flags) = parse_args(args, &[])?;
+    let (wad, dir) = match positional.as_slice() {
+        [wad, dir] => (wad, dir),
+        _ => return Err(String::from(USAGE)),
+    };
+
+    let mut options = ExtractOptions::default();
+    for (flag, val) in flags {
+        match flag.as_str() {
+            "--include" => options.include.push(Query::new().glob(&val)?),
+            "--exclude" => options.exclude.push(Query::new().glob(&val)?),
+            "--threads" => {
+                options.threads = val
+                    .parse::<usize>()
+                    .or(Err(format!("Invalid thread count '{}'", val)))?
+            }
+            _ => return Err(format!("Unknown option {}\n\n{}", flag, USAGE)),
+        }
+    }
+
+    let archive = Archive::open(wad)?;
+    let report = extract(&archive, Path::new(dir), &options)?;
+    for (name, e) in &report.failed {
+        println!("{}: {}", name, e);
+    }
+    println!(
+        "Extracted {} entries to {}, {} failed",
+        report.extracted,
+        dir,
+        report.failed.len()
+    );
+
+    if !report.failed.is_empty() {
+        return Err(format!("Couldn't extract everything from {}", wad));
+    }
+    Ok(())
+}
+
+fn wad_convert(args: &[String]) -> Result<(), String> {
+    let (positional, flags) = parse_args(args, &[])?;
+    let (from, to) = match positional.as_slice() {
+        [from, to] => (Path::new(from), Path::new(to)),
+        _ => return Err(String::from(USAGE)),
+    };
+
+    let mut manifest_path = None;
+    let mut level = 9;
+    for (flag, val) in flags {
+        match flag.as_str() {
+            "--manifest" => manifest_path = Some(val),
+            "--level" => {
+                level = val
+                    .parse::<i32>()
+                    .or(Err(format!("Invalid level '{}'", val)))?
+            }
+            _ => return Err(format!("Unknown option {}\n\n{}", flag, USAGE)),
+        }
+    }
+
+    let ext = |p: &Path| p.extension().map(|e| e.to_string_lossy().to_lowercase());
+    match (ext(from).as_deref(), ext(to).as_deref()) {
+        (Some("wad"), to_ext) => {
+            let mut archive = Archive::open(from)?;
+            match to_ext {
+                Some("zip") => convert::wad_to_zip(&mut archive, to)?,
+                Some("tar") => convert::wad_to_tar(&mut archive, to)?,
+                None => {
+                    let report = extract(&archive, to, &Default::default())?;
+                    if let Some((name, e)) = report.failed.first() {
+                        return Err(format!("{}: {}", name, e));
+                    }
+                }
+                Some(other) => return Err(format!("Can't convert a wad to .{}", other)),
+            }
+            if let Some(path) = manifest_path {
+                Manifest::from_archive(&archive).save(path)?;
+            }
+            Ok(())
+        }
+        (from_ext, Some("wad")) => {
+            let manifest = match manifest_path {
+                Some(path) => Some(Manifest::load(path)?),
+                None => None,
+            };
+            match from_ext {
+                Some("zip") => convert::zip_to_wad(from, to, manifest.as_ref(), level),
+                Some("tar") => convert::tar_to_wad(from, to, manifest.as_ref(), level),
+                _ if from.is_dir() => convert::dir_to_wad(from, to, manifest.as_ref(), level),
+                _ => Err(format!("Can't convert '{}' to a wad", from.display())),
+            }
+        }
+        _ => Err(String::from("One side of a conversion has to be a .wad")),
+    }
+}
+
+fn wad_diff(args: &[String]) -> Result<(), String> {
+    let (positional, flags) = parse_args(args, &[])?;
+    let (old, new) = match positional.as_slice() {
+        [old, new] => (old, new),
+        _ => return Err(String::from(USAGE)),
+    };
+
+    let mut delta = None;
+    for (flag, val) in flags {
+        match flag.as_str() {
+            "--delta" => delta = Some(val),
+            _ => return Err(format!("Unknown option {}\n\n{}", flag, USAGE)),
+        }
+    }
+
+    let old = Archive::open(old)?;
+    let mut new = Archive::open(new)?;
+    let diff = diff(&old, &new);
+    for change in &diff.changes {
+        let sizes = match (&change.old, &change.new) {
+            (Some(old), Some(new)) => format!("{} -> {} bytes", old.size, new.size),
+            (Some(old), None) => format!("{} bytes", old.size),
+            (None, Some(new)) => format!("{} bytes", new.size),
+            (None, None) => String::new(),
+        };
+        println!(
+            "{:>12}  {} ({})",
+            change.kind.to_string(),
+            change.name,
+            sizes
+        );
+    }
+    println!("{} entries changed", diff.changes.len());
+
+    if let Some(delta) = delta {
+        write_delta(&diff, &old, &mut new, Path::new(&delta))?;
+    }
+    Ok(())
+}
+
+fn wad_patch(args: &[String]) -> Result<(), String> {
+    let (old, delta, out) = match args {
+        [old, delta, out] => (old, delta, out),
+        _ => return Err(String::from(USAGE)),
+    };
+
+    let mut old = Archive::open(old)?;
+    apply_delta(&mut old, Path::new(delta), Path::new(out))
+}
+
+fn wad(args: &[String]) -> Result<(), String> {
+    match args.first().map(|a| a.as_str()) {
+        Some("list") => wad_list(&args[1..]),
+        Some("verify") => wad_verify(&args[1..]),
+        Some("extract") => wad_extract(&args[1..]),
+        Some("convert") => wad_convert(&args[1..]),
+        Some("diff") => wad_diff(&args[1..]),
+        Some("patch") => wad_patch(&args[1..]),
+        Some("repack") => wad_repack(&args[1..]),
+        _ => Err(String::from(USAGE)),
+    }
+}
+
+fn bind(args: &[String]) -> Result<(), String> {
+    let (positional, flags) = parse_args(args, &["--xml"])?;
+
+    let mut types = None;
+    let mut xml = false;
+    for (flag, val) in flags {
+        match flag.as_str() {
+            "--types" => types = Some(TypeDump::load(val)?),
+            "--xml" => xml = true,
+            _ => return Err(format!("Unknown option {}\n\n{}", flag, USAGE)),
+        }
+    }
+
+    let data = match positional.as_slice() {
+        [file] => fs::read(file).or(Err(format!("Couldn't read '{}'", file)))?,
+        [wad, entry] => Archive::open(wad)?.read_file(entry)?,
+        _ => return Err(String::from(USAGE)),
+    };
+
+    let object = bind_helper::decode(&data, types.as_ref())?;
+    if xml {
+        print!("{}", object.to_xml());
+    } else {
+        println!("{}", object.to_json());
+    }
+    Ok(())
+}
+
+const DEFAULT_INDEX: &str = "wad_index.json";
+
+fn print_update(report: &UpdateReport) {
+    for (wad, entry, e) in &report.failed {
+        println!("{}:{}: {}", wad, entry, e);
+    }
+    println!(
+        "Indexed {} wads, {} unchanged, {} removed",
+        report.indexed.len(),
+        report.unchanged,
+        report.removed.len()
+    );
+}
+
+fn index(args: &[String]) -> Result<(), String> {
+    let (positional, flags) = parse_args(args, &[])?;
+    let game_dir = match positional.as_slice() {
+        [game_dir] => game_dir,
+        _ => return Err(String::from(USAGE)),
+    };
+
+    let mut index_path = String::from(DEFAULT_INDEX);
+    for (flag, val) in flags {
+        match flag.as_str() {
+            "--index" => index_path = val,
+            _ => return Err(format!("Unknown option {}\n\n{}", flag, USAGE)),
+        }
+    }
+
+    let mut index = ContentIndex::load(&index_path)?;
+    print_update(&index.update(game_dir)?);
+    index.save(&index_path)
+}
+
+fn search(args: &[String]) -> Result<(), String> {
+    let (positional, flags) = parse_args(args, &["--no-update"])?;
+    let (game_dir, query) = match positional.as_slice() {
+        [game_dir, query] => (game_dir, query),
+        _ => return Err(String::from(USAGE)),
+    };
+
+    let mut index_path = String::from(DEFAULT_INDEX);
+    let mut update = true;
+    for (flag, val) in flags {
+        match flag.as_str() {
+            "--index" => index_path = val,
+            "--no-update" => update = false,
+            _ => return Err(format!("Unknown option {}\n\n{}", flag, USAGE)),
+        }
+    }
+
+    let mut index = ContentIndex::load(&index_path)?;
+    if update {
+        let report = index.update(game_dir)?;
+        if !report.indexed.is_empty() || !report.removed.is_empty() {
+            print_update(&report);
+            index.save(&index_path)?;
+        }
+    }
+
+    // hits are sorted, so every entry only gets read once
+    let hits = index.search(query)?;
+    let mut current: Option<(String, String, Vec<String>)> = None;
+    for hit in &hits {
+        if current
+            .as_ref()
+            .is_none_or(|(wad, entry, _)| *wad != hit.wad || *entry != hit.entry)
+        {
+            let text = Archive::open(&hit.wad)?.read_file(&hit.entry)?;
+            let lines = decode_text(&text).lines().map(String::from).collect();
+            current = Some((hit.wad.clone(), hit.entry.clone(), lines));
+        }
+        let lines = &current.as_ref().unwrap().2;
+        // line 0 can only come from a broken index
+        let text = hit
+            .line
+            .checked_sub(1)
+            .and_then(|l| lines.get(l as usize))
+            .map_or("", |l| l.trim());
+        println!("{}:{}:{}: {}", hit.wad, hit.entry, hit.line, text);
+    }
+    println!("{} matching lines", hits.len());
+    Ok(())
+}
+
+// Game wads plus the layer options in `flags`
+fn build_vfs(game_data: &str, flags: Vec<(String, String)>) -> Result<Vfs, String> {
+    let mut vfs = Vfs::new();
+    vfs.add_base(game_data)?;
+    let mut packages = Vec::new();
+    for (flag, val) in flags {
+        match flag.as_str() {
+            "--dir" => vfs.add_dir(&val, &val)?,
+            "--wad" => {
+                let (wad, mount) = val
+                    .split_once('=')
+                    .ok_or(format!("--wad expects <wad>=<mount>, got '{}'", val))?;
+                vfs.add_wad(wad, wad, mount)?
+            }
+            "--mod" => packages.push(Package::open(&val)?),
+            _ => return Err(format!("Unknown option {}\n\n{}", flag, USAGE)),
+        }
+    }
+
+    if !packages.is_empty() {
+        let report = stack_layers(&mut vfs, &mut packages)?;
+        for failure in &report.failed {
+            println!("{}", failure);
+        }
+    }
+    Ok(vfs)
+}
+
+fn vfs(args: &[String]) -> Result<(), String> {
+    let (positional, flags) = parse_args(args, &[])?;
+    let (game_data, cmd, rest) = match positional.as_slice() {
+        [game_data, cmd, rest @ ..] => (game_data, cmd.as_str(), rest),
+        _ => return Err(String::from(USAGE)),
+    };
+    let vfs = build_vfs(game_data, flags)?;
+
+    match (cmd, rest) {
+        ("list", []) | ("list", [_]) => {
+            let dir = rest.first().map_or("", |d| d.as_str());
+            for name in vfs.list(dir) {
+                println!("{}", name);
+            }
+            Ok(())
+        }
+        ("which", [path]) => {
+            // top layer first, then the ones it hides
+            let mut layers = vfs.providers(path);
+            if layers.is_empty() {
+                return Err(format!("{} doesn't exist", path));
+            }
+            layers.reverse();
+            println!("{}", layers.join(" > "));
+            Ok(())
+        }
+        ("read", [path, out]) => {
+            fs::write(out, vfs.read(path)?).or(Err(format!("Failed to write file '{}'", out)))
+        }
+        ("build", [wad, out]) => vfs.build_wad(wad, out),
+        _ => Err(String::from(USAGE)),
+    }
+}
+
+fn xml_patch(args: &[String]) -> Result<(), String> {
+    let (positional, flags) = parse_args(args, &[])?;
+    let (game_data, out, patch_files) = match positional.as_slice() {
+        [game_data, out, patch_files @ ..] if !patch_files.is_empty() => {
+            (game_data, out, patch_files)
+        }
+        _ => return Err(String::from(USAGE)),
+    };
+    let vfs = build_vfs(game_data, flags)?;
+
+    let sets = patch_files
+        .iter()
+        .map(|p| PatchSet::load(p, p))
+        .collect::<Result<Vec<_>, _>>()?;
+    let (files, report) = apply_patches(&vfs, &sets);
+
+    for (path, data) in &files {
+        let dest = entry_path(Path::new(out), path)?;
+        if let Some(parent) = dest.parent() {
+            fs::create_dir_all(parent)
+                .or(Err(format!("Failed to create '{}'", parent.display())))?;
+        }
+        fs::write(&dest, data).or(Err(format!("Failed to write file '{}'", dest.display())))?;
+    }
+    for failure in &report.failed {
+        println!("{}", failure);
+    }
+    println!(
+        "{} patches applied to {} entries, {} failed",
+        report.applied,
+        files.len(),
+        report.failed.len()
+    );
+
+    if !report.failed.is_empty() {
+        return Err(String::from("Some patches didn't apply"));
+    }
+    Ok(())
+}
+
+fn print_apply(report: &ApplyReport) {
+    for warning in &report.warnings {
+        println!("Warning: {}", warning);
+    }
+    for failure in &report.failed {
+        println!("{}", failure);
+    }
+    for file in &report.written {
+        println!("modded   {}", file);
+    }
+    for file in &report.restored {
+        println!("restored {}", file);
+    }
+    println!(
+        "{} files modded, {} restored, {} patches failed",
+        report.written.len(),
+        report.restored.len(),
+        report.failed.len()
+    );
+}
+
+fn load_file_list(manager: &mut ModManager, path: &str) -> Result<(), String> {
+    if !Path::new(path).is_file() {
+        return Err(format!("Couldn't read file list '{}'", path));
+    }
+    manager.with_file_list(TableList::from_file(path).get_records());
+    Ok(())
+}
+
+fn print_installed(installed: &InstallReport) {
+    println!(
+        "Installed {} {}",
+        installed.manifest.id, installed.manifest.version
+    );
+    if let Some(warning) = &installed.warning {
+        println!("Warning: {}", warning);
+    }
+}
+
+fn mod_manager(cmd: &str, args: &[String]) -> Result<(), String> {
+    let (positional, flags) = parse_args(args, &["--json"])?;
+    let (game_dir, rest) = match positional.as_slice() {
+        [game_dir, rest @ ..] => (game_dir, rest),
+        _ => return Err(String::from(USAGE)),
+    };
+    let mut manager = ModManager::open(game_dir)?;
+    let mut json = false;
+    let mut revision = String::from("unknown");
+    for (flag, val) in flags {
+        match flag.as_str() {
+            "--json" => json = true,
+            "--revision" => revision = val,
+            "--file-list" => load_file_list(&mut manager, &val)?,
+            _ => return Err(format!("Unknown option {}\n\n{}", flag, USAGE)),
+        }
+    }
+
+    match (cmd, rest) {
+        ("list", []) => {
+            for (id, m) in &manager.state().mods {
+                let mut status = vec![if m.enabled { "enabled" } else { "disabled" }.to_string()];
+                match &m.publisher {
+                    Some(publisher) => status.push(format!("signed by {}", publisher)),
+                    None => status.push(String::from("unsigned")),
+                }
+                if let Some(rev) = &m.needs_review {
+                    status.push(format!("needs review for {}", rev));
+                }
+                println!("{} {} ({})", id, m.version, status.join(", "));
+                for file in manager.changes(id) {
+                    println!("    {}", file);
+                }
+            }
+            return Ok(());
+        }
+        ("conflicts", []) => {
+            let report = find_conflicts(&mut manager.enabled_packages()?)?;
+            if json {
+                println!("{}", serde_json::to_string_pretty(&report).unwrap());
+            } else {
+                println!("Load order: {}", report.order.join(", "));
+                for conflict in &report.conflicts {
+                    println!("{}", conflict);
+                }
+                println!("{} conflicts", report.conflicts.len());
+            }
+            return Ok(());
+        }
+        ("trust", [publisher, key]) => {
+            manager.trust_store().trust(publisher, key)?;
+            return manager.save();
+        }
+        ("untrust", [publisher]) => {
+            manager.trust_store().untrust(publisher)?;
+            return manager.save();
+        }
+        ("policy", []) => {
+            println!("{}", manager.state().unsigned);
+            return Ok(());
+        }
+        ("policy", [policy]) => {
+            manager.set_policy(policy.parse()?);
+            return manager.save();
+        }
+        ("install", packages) if !packages.is_empty() => {
+            let mut reapply = false;
+            for package in packages {
+                let installed = manager.install(package)?;
+                print_installed(&installed);
+                reapply |= manager.state().mods[&installed.manifest.id].enabled;
+            }
+            if !reapply {
+                return manager.save();
+            }
+        }
+        ("uninstall", ids) if !ids.is_empty() => {
+            for id in ids {
+                manager.uninstall(id)?;
+            }
+        }
+        ("enable", ids) if !ids.is_empty() => {
+            for id in ids {
+                manager.enable(id)?;
+            }
+        }
+        ("disable", ids) if !ids.is_empty() => {
+            for id in ids {
+                manager.disable(id)?;
+            }
+        }
+        ("apply", []) => {}
+        ("reapply", []) => {
+            let report = manager.reapply_after_update(&revision)?;
+            for file in &report.replaced {
+                println!("updated  {}", file);
+            }
+            print_apply(&report.apply);
+            for (id, path) in &report.overridden {
+                println!("{} changes {}, which the update changed", id, path);
+            }
+            for id in &report.needs_review {
+                println!("{} needs a review for {}", id, revision);
+            }
+            return Ok(());
+        }
+        ("restore", []) => {
+            print_apply(&manager.restore()?);
+            return Ok(());
+        }
+        _ => return Err(String::from(USAGE)),
+    }
+
+    print_apply(&manager.apply()?);
+    Ok(())
+}
+
+fn mod_package(args: &[String]) -> Result<(), String> {
+    if let Some(cmd) = args.first() {
+        if matches!(
+            cmd.as_str(),
+            "install"
+                | "uninstall"
+                | "enable"
+                | "disable"
+                | "apply"
+                | "restore"
+                | "list"
+                | "conflicts"
+                | "reapply"
+                | "trust"
+                | "untrust"
+                | "policy"
+        ) {
+            return mod_manager(cmd, &args[1..]);
+        }
+    }
+    match args {
+        [cmd, rest @ ..] if cmd == "build" => {
+            let (positional, flags) = parse_args(rest, &[])?;
+            let (src, out) = match positional.as_slice() {
+                [src, out] => (src, out),
+                _ => return Err(String::from(USAGE)),
+            };
+            let mut builder = PackageBuilder::from_dir(src)?;
+            for (flag, val) in flags {
+                match flag.as_str() {
+                    "--sign" => builder.sign(PublisherKey::load(&val)?),
+                    _ => return Err(format!("Unknown option {}\n\n{}", flag, USAGE)),
+                };
+            }
+            let manifest = builder.write(out)?;
+            println!(
+                "Built {} {} with {} files and {} patch files",
+                manifest.id,
+                manifest.version,
+                manifest.files.len(),
+                manifest.patches.len()
+            );
+            Ok(())
+        }
+        [cmd, paths @ ..] if cmd == "order" && !paths.is_empty() => {
+            let packages = paths
+                .iter()
+                .map(Package::open)
+                .collect::<Result<Vec<_>, _>>()?;
+            let manifests: Vec<_> = packages.iter().map(|p| p.manifest()).collect();
+            match resolve(&manifests) {
+                Ok(order) => {
+                    for (i, id) in order.iter().enumerate() {
+                        println!("{:>3}. {}", i + 1, id);
+                    }
+                    Ok(())
+                }
+                Err(errors) => {
+                    for e in &errors {
+                        println!("{}", e);
+                    }
+                    Err(String::from("No load order satisfies every mod"))
+                }
+            }
+        }
+        [cmd, publisher, out] if cmd == "keygen" => {
+            if Path::new(out).exists() {
+                return Err(format!("'{}' already exists", out));
+            }
+            let key = PublisherKey::generate(publisher);
+            key.save(out)?;
+            println!("{}", key.public_key());
+            Ok(())
+        }
+        [cmd, package] if cmd == "info" => {
+            let package = Package::open(package)?;
+            println!("{}", package.manifest().to_json());
+            Ok(())
+        }
+        [cmd, path] if cmd == "validate" => {
+            let mut package = Package::open(path)?;
+            match package.validate() {
+                Ok(()) => {
+                    println!("{} is valid", path);
+                    Ok(())
+                }
+                Err(problems) => {
+                    for problem in &problems {
+                        println!("{}", problem);
+                    }
+                    Err(format!("{} is invalid", path))
+                }
+            }
+        }
+        _ => Err(String::from(USAGE)),
+    }
+}
+
+async fn repo(args: &[String]) -> Result<(), String> {
+    let (positional, flags) = parse_args(args, &[])?;
+    let mut cache = String::from("mod_cache");
+    let mut file_list = None;
+    for (flag, val) in flags {
+        match flag.as_str() {
+            "--cache" => cache = val,
+            "--file-list" => file_list = Some(val),
+            _ => return Err(format!("Unknown option {}\n\n{}", flag, USAGE)),
+        }
+    }
+
+    let (cmd, rest) = match positional.split_first() {
+        Some((cmd, rest)) => (cmd.as_str(), rest),
+        None => return Err(String::from(USAGE)),
+    };
+    let (game_dir, url, ids) = match (cmd, rest) {
+        ("search", [url, words @ ..]) => {
+            let repository = Repository::new(url, &cache);
+            let (index, cached) = repository.index().await?;
+            if cached {
+                println!("{} can't be reached, using the cached index", url);
+            }
+            let found = index.search(words);
+            for entry in &found {
+                println!("{} {}  {}", entry.id, entry.version, entry.name);
+                if !entry.description.is_empty() {
+                    println!("    {}", entry.description);
+                }
+            }
+            println!("{} mods found", found.len());
+            return Ok(());
+        }
+        ("install", [game_dir, url, ids @ ..]) if !ids.is_empty() => (game_dir, url, ids),
+        ("upgrade", [game_dir, url]) => (game_dir, url, &[][..]),
+        _ => return Err(String::from(USAGE)),
+    };
+
+    let mut manager = ModManager::open(game_dir)?;
+    if let Some(path) = file_list {
+        load_file_list(&mut manager, &path)?;
+    }
+    let repository = Repository::new(url, &cache);
+    let (index, cached) = repository.index().await?;
+    if cached {
+        println!("{} can't be reached, using the cached index", url);
+    }
+
+    let installed = if cmd == "install" {
+        let wanted = ids
+            .iter()
+            .map(|id| match id.split_once('@') {
+                Some((id, req)) => Ok((id.to_string(), req.parse()?)),
+                None => Ok((id.clone(), VersionReq::any())),
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+        let installed = repository.install(&index, &mut manager, &wanted).await?;
+        for report in &installed {
+            manager.enable(&report.manifest.id)?;
+        }
+        installed
+    } else {
+        repository.upgrade(&index, &mut manager).await?
+    };
+    if installed.is_empty() {
+        println!("Everything is up to date");
+    }
+    for report in &installed {
+        print_installed(report);
+    }
+
+    let enabled = installed
+        .iter()
+        .any(|r| manager.state().mods[&r.manifest.id].enabled);
+    if !enabled {
+        return manager.save();
+    }
+    print_apply(&manager.apply()?);
+    Ok(())
+}
+
+fn scan_game(args: &[String]) -> Result<(), String> {
+    let (positional, flags) = parse_args(args, &["--all", "--json"])?;
+    let game_dir = match positional.as_slice() {
+        [game_dir] => game_dir,
+        _ => return Err(String::from(USAGE)),
+    };
+    let mut file_list = String::from("LatestFileList.bin");
+    let mut all = false;
+    let mut json = false;
+    for (flag, val) in flags {
+        match flag.as_str() {
+            "--file-list" => file_list = val,
+            "--all" => all = true,
+            "--json" => json = true,
+            _ => return Err(format!("Unknown option {}\n\n{}", flag, USAGE)),
+        }
+    }
+    if !Path::new(&file_list).is_file() {
+        return Err(format!("Couldn't read file list '{}'", file_list));
+    }
+
+    let records = TableList::from_file(&file_list).get_records();
+    let manager = ModManager::open(game_dir)?;
+    let report = scan(game_dir, &records, Some(&manager))?;
+    if json {
+        println!("{}", serde_json::to_string_pretty(&report).unwrap());
+        return Ok(());
+    }
+
+    for file in &report.files {
+        if !all && file.status == FileStatus::Vanilla {
+            continue;
+        }
+        if file.mods.is_empty() {
+            println!("{:<8} {}", file.status, file.path);
+        } else {
+            println!(
+                "{:<8} {} ({})",
+                file.status,
+                file.path,
+                file.mods.join(", ")
+            );
+        }
+    }
+    println!(
+        "{} vanilla, {} modified, {} missing, {} extra",
+        report.count(FileStatus::Vanilla),
+        report.count(FileStatus::Modified),
+        report.count(FileStatus::Missing),
+        report.count(FileStatus::Extra)
+    );
+    if report.is_vanilla() {
+        println!("The install is pristine");
+    }
+    Ok(())
+}
+
+fn lang(args: &[String]) -> Result<(), String> {
+    match args {
+        [cmd, wad, entry] if cmd == "dump" => {
+            let lang = LangFile::parse(&Archive::open(wad)?.read_file(entry)?)
+                .map_err(|e| format!("{}: {}", entry, e))?;
+            for (key, entry) in lang.entries() {
+                println!("{} = {}", lang.id(key), entry.value);
+            }
+            Ok(())
+        }
+        [cmd, game_dir, id] if cmd == "lookup" => {
+            let found = lang::lookup(game_dir, id)?;
+            for (wad, e) in &found.unreadable {
+                eprintln!("Skipped {}: {}", wad.display(), e);
+            }
+            for m in &found.matches {
+                println!("{}:{}: {} = {}", m.wad.display(), m.entry, m.key, m.value);
+            }
+            if found.matches.is_empty() {
+                return Err(format!("{} not found", id));
+            }
+            Ok(())
+        }
+        [cmd, file, key, text] if cmd == "set" => {
+            let data = fs::read(file).or(Err(format!("Couldn't read '{}'", file)))?;
+            let mut lang = LangFile::parse(&data).map_err(|e| format!("{}: {}", file, e))?;
+            lang.set(key, text)?;
+            fs::write(file, lang.to_bytes()).or(Err(format!("Failed to write file '{}'", file)))
+        }
+        [cmd, file, key] if cmd == "remove" => {
+            let data = fs::read(file).or(Err(format!("Couldn't read '{}'", file)))?;
+            let mut lang = LangFile::parse(&data).map_err(|e| format!("{}: {}", file, e))?;
+            if lang.remove(key).is_none() {
+                return Err(format!("{} not found in '{}'", key, file));
+            }
+            fs::write(file, lang.to_bytes()).or(Err(format!("Failed to write file '{}'", file)))
+        }
+        _ => Err(String::from(USAGE)),
+    }
+}
+
+// Reads a password from the first line of stdin
+fn read_password() -> Result<String, String> {
+    let mut password = String::new();
+    std::io::stdin()
+        .read_line(&mut password)
+        .or(Err("Couldn't read the password from stdin"))?;
+    let len = password.trim_end_matches(['\r', '\n']).len();
+    password.truncate(len);
+    Ok(password)
+}
+
+// The session a rec1 is encrypted for
+fn rec1_session(sid: &str, secs: &str, millis: &str) -> Result<(u16, u32, u32), String> {
+    let sid = sid.parse().or(Err(format!("Invalid sid '{}'", sid)))?;
+    let secs = secs.parse().or(Err(format!("Invalid time '{}'", secs)))?;
+    let millis = millis
+        .parse()
+        .or(Err(format!("Invalid time '{}'", millis)))?;
+    Ok((sid, secs, millis))
+}
+
+fn rec1(args: &[String]) -> Result<(), String> {
+    let (positional, flags) = parse_args(args, &[])?;
+    match positional.as_slice() {
+        [cmd] if cmd == "hash" => {
+            let mut password = read_password()?;
+            println!("{}", rec1::hash_password(&password));
+            password.zeroize();
+            Ok(())
+        }
+        [cmd, data, sid, secs, millis] if cmd == "check" => {
+            let (sid, secs, millis) = rec1_session(sid, secs, millis)?;
+            let data = general_purpose::STANDARD
+                .decode(data.trim())
+                .or(Err("The rec1 isn't valid base64"))?;
+            let record = rec1::read_client_rec1(&data, sid, secs, millis)?;
+            let matches = match flags.as_slice() {
+                [] => {
+                    let mut password = read_password()?;
+                    let matches = rec1::verify_ck1(&record.key, &password, sid, secs, millis);
+                    password.zeroize();
+                    matches
+                }
+                [(flag, hash)] if flag == "--hash" => {
+                    rec1::verify_ck1_hash(&record.key, hash, sid, secs, millis)
+                }
+                _ => return Err(String::from(USAGE)),
+            };
+            if !matches {
+                return Err(format!(
+                    "The ck1 from {} in session {} doesn't match",
+                    record.username, record.sid
+                ));
+            }
+            println!(
+                "The ck1 from {} in session {} matches",
+                record.username, record.sid
+            );
+            Ok(())
+        }
+        [cmd, username, sid, secs, millis] if cmd == "reply" && flags.is_empty() => {
+            let (sid, secs, millis) = rec1_session(sid, secs, millis)?;
+            let (data, ck2) = rec1::gen_server_rec1(username, sid, secs, millis);
+            println!("rec1: {}", general_purpose::STANDARD.encode(data));
+            println!("ck2: {}", *ck2);
+            Ok(())
+        }
+        _ => Err(String::from(USAGE)),
+    }
+}
+
+// What the launcher was asked to do when there's no subcommand
+pub struct LaunchOptions {
+    // service files to layer over the ones in Root.wad, in order
+    pub service_files: Vec<String>,
+    // check the game files already there, not just download missing ones
+    pub verify: bool,
+    // where WizardGraphicalClient.exe is
+    pub client_dir: String,
+}
+
+pub fn launch_options(args: &[String]) -> Result<LaunchOptions, String> {
+    let (positional, flags) = parse_args(args, &["--verify"])?;
+    if !positional.is_empty() {
+        return Err(String::from(USAGE));
+    }
+    let mut options = LaunchOptions {
+        service_files: Vec::new(),
+        verify: false,
+        client_dir: String::from("./test/Bin"),
+    };
+    for (flag, val) in flags {
+        match flag.as_str() {
+            "--services" => options.service_files.push(val),
+            "--verify" => options.verify = true,
+            "--client-dir" => options.client_dir = val,
+            _ => return Err(format!("Unknown option {}\n\n{}", flag, USAGE)),
+        }
+    }
+    Ok(options)
+}
+
+// The account to log in with, from WIZARD101_USERNAME and WIZARD101_PASSWORD
+// so the password stays out of the command line
+pub fn credentials() -> Result<(String, String), String> {
+    let var = |name| env::var(name).or(Err(format!("{} isn't set", name)));
+    Ok((var("WIZARD101_USERNAME")?, var("WIZARD101_PASSWORD")?))
+}
+
+// Runs the subcommand in `args` (without the program name). Returns None if
+// there is none, in which case the launcher runs as usual.
+pub async fn run(args: &[String]) -> Option<Result<(), String>> {
+    match args.first().map(|a| a.as_str()) {
+        Some("wad") => Some(wad(&args[1..])),
+        Some("index") => Some(index(&args[1..])),
+        Some("search") => Some(search(&args[1..])),
+        Some("vfs") => Some(vfs(&args[1..])),
+        Some("xml-patch") => Some(xml_patch(&args[1..])),
+        Some("mod") => Some(mod_package(&args[1..])),
+        Some("repo") => Some(repo(&args[1..]).await),
+        Some("scan") => Some(scan_game(&args[1..])),
+        Some("lang") => Some(lang(&args[1..])),
+        Some("bind") => Some(bind(&args[1..])),
+        Some("rec1") => Some(rec1(&args[1..])),
+        Some("help") | Some("--help") | Some("-h") => {
+            println!("{}", USAGE);
+            Some(Ok(()))
+        }
+        _ => None,
+    }
+}
@@ -2,7 +2,9 @@ use base64::{engine::general_purpose, Engine as _};
 use ofb::cipher::KeyIvInit;
 use ofb::cipher::StreamCipher;
 use ofb::Ofb;
+use rand_core::{OsRng, RngCore};
 use twofish::Twofish;
+use zeroize::{Zeroize, Zeroizing};
 
 type TwofishOfb = Ofb<Twofish>;
 
@@ -40,6 +42,30 @@ fn derive_nonce() -> [u8; 16] {
     iv
 }
 
+// OFB is its own inverse, so this both encrypts and decrypts
+fn apply_keystream(record: &mut [u8], sid: u16, time_secs: u32, time_millis: u32) {
+    let mut key = derive_key(sid, time_secs, time_millis);
+    let nonce = &derive_nonce();
+
+    let mut twofish = TwofishOfb::new((&key).into(), nonce.into());
+    twofish.apply_keystream(record);
+    key.zeroize();
+}
+
+// A decrypted rec1: the client's has its ck1 as the key, the server's
+// reply the ck2 to log in with
+pub struct Rec1Record {
+    pub sid: u16,
+    pub username: String,
+    pub key: String,
+}
+
+impl Drop for Rec1Record {
+    fn drop(&mut self) {
+        self.key.zeroize();
+    }
+}
+
 pub fn encrypt_rec1(
     sid: u16,
     username: &str,
@@ -47,45 +73,269 @@ pub fn encrypt_rec1(
     time_secs: u32,
     time_millis: u32,
 ) -> Vec<u8> {
-    let mut record = format!("{} {} {}", sid, username, client_key).into_bytes();
-
-    let key = &derive_key(sid, time_secs, time_millis);
-    let nonce = &derive_nonce();
-
-    let mut twofish = TwofishOfb::new(key.into(), nonce.into());
-    twofish.apply_keystream(&mut record);
-
+    // sized up front so the plaintext is never left behind by a reallocation,
+    // it's encrypted where it was written
+    let sid_text = sid.to_string();
+    let mut record = Vec::with_capacity(sid_text.len() + username.len() + client_key.len() + 2);
+    for part in [
+        sid_text.as_bytes(),
+        username.as_bytes(),
+        client_key.as_bytes(),
+    ] {
+        if !record.is_empty() {
+            record.push(b' ');
+        }
+        record.extend_from_slice(part);
+    }
+    apply_keystream(&mut record, sid, time_secs, time_millis);
     record
 }
 
-fn gen_ck1(password: &str, sid: u16, time_secs: u32, time_millis: u32) -> String {
+// What the server keeps instead of the password
+pub fn hash_password(password: &str) -> String {
     let mut hasher = Sha512::new();
     hasher.update(password);
-    let password_hash = general_purpose::STANDARD.encode(hasher.finalize());
+    general_purpose::STANDARD.encode(hasher.finalize())
+}
 
-    let mut hash2 = Sha512::new();
-    hash2.update(password_hash);
-    hash2.update(format!("{}{}{}", sid, time_secs, time_millis));
+fn ck1_from_hash(password_hash: &str, sid: u16, time_secs: u32, time_millis: u32) -> String {
+    let mut hasher = Sha512::new();
+    hasher.update(password_hash);
+    hasher.update(format!("{}{}{}", sid, time_secs, time_millis));
+    general_purpose::STANDARD.encode(hasher.finalize())
+}
 
-    general_purpose::STANDARD.encode(hash2.finalize())
+fn gen_ck1(password: &str, sid: u16, time_secs: u32, time_millis: u32) -> Zeroizing<String> {
+    let password_hash = Zeroizing::new(hash_password(password));
+    Zeroizing::new(ck1_from_hash(&password_hash, sid, time_secs, time_millis))
 }
 
 pub fn gen_rec1(
     username: String,
-    password: String,
+    mut password: String,
     sid: u16,
     time_secs: u32,
     time_millis: u32,
 ) -> Vec<u8> {
     let client_key = gen_ck1(&password, sid, time_secs, time_millis);
+    password.zeroize();
     encrypt_rec1(sid, &username, &client_key, time_secs, time_millis)
 }
 
-pub fn decrypt_rec1(rec1: &mut [u8], sid: u16, time_secs: u32, time_millis: u32) -> String {
-    let key = &derive_key(sid, time_secs, time_millis);
-    let nonce = &derive_nonce();
+// Decrypts the server's rec1 in place and wipes it once it's parsed. A
+// wrong sid or time makes garbage, which is an error rather than a panic.
+pub fn decrypt_rec1(
+    rec1: &mut [u8],
+    sid: u16,
+    time_secs: u32,
+    time_millis: u32,
+) -> Result<Rec1Record, String> {
+    apply_keystream(rec1, sid, time_secs, time_millis);
+    let record = parse_rec1(rec1, sid);
+    rec1.zeroize();
+    record
+}
+
+// Splits a decrypted "<sid> <username> <key>" record. The key is base64 and
+// the sid a number, so only the username can have spaces in it. The sid has
+// to be the session's.
+pub fn parse_rec1(record: &[u8], session_sid: u16) -> Result<Rec1Record, String> {
+    let record = std::str::from_utf8(record).or(Err("Rec1 isn't valid utf-8"))?;
+    let (sid, rest) = record
+        .split_once(' ')
+        .ok_or("Rec1 is missing the username and key")?;
+    let (username, key) = rest.rsplit_once(' ').ok_or("Rec1 is missing the key")?;
+    let sid = sid
+        .parse::<u16>()
+        .or(Err(format!("Invalid sid '{}' in rec1", sid)))?;
+    if sid != session_sid {
+        return Err(format!("Rec1 is for session {}, not {}", sid, session_sid));
+    }
+    if username.is_empty() || key.is_empty() {
+        return Err(String::from("Rec1 has an empty username or key"));
+    }
+    Ok(Rec1Record {
+        sid,
+        username: username.to_string(),
+        key: key.to_string(),
+    })
+}
+
+// Server side
+
+// Decrypts and parses the rec1 a client sent in MSG_USER_AUTHEN_V3
+pub fn read_client_rec1(
+    rec1: &[u8],
+    sid: u16,
+    time_secs: u32,
+    time_millis: u32,
+) -> Result<Rec1Record, String> {
+    let mut record = rec1.to_vec();
+    decrypt_rec1(&mut record, sid, time_secs, time_millis)
+}
 
-    let mut twofish = TwofishOfb::new(key.into(), nonce.into());
-    twofish.apply_keystream(rec1);
-    String::from_utf8(rec1.to_vec()).unwrap().to_string()
+// Doesn't stop at the first difference, so the time taken says nothing
+// about how much of the key was right
+fn keys_match(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0, |diff, (x, y)| diff | (x ^ y))
+            == 0
+}
+
+pub fn verify_ck1(ck1: &str, password: &str, sid: u16, time_secs: u32, time_millis: u32) -> bool {
+    keys_match(ck1, &gen_ck1(password, sid, time_secs, time_millis))
+}
+
+// Same as verify_ck1, for a password stored as hash_password(password)
+pub fn verify_ck1_hash(
+    ck1: &str,
+    password_hash: &str,
+    sid: u16,
+    time_secs: u32,
+    time_millis: u32,
+) -> bool {
+    keys_match(
+        ck1,
+        &ck1_from_hash(password_hash, sid, time_secs, time_millis),
+    )
+}
+
+// A fresh session key, the same length as a ck1
+pub fn gen_ck2() -> Zeroizing<String> {
+    let mut key = Zeroizing::new([0; 64]);
+    OsRng.fill_bytes(key.as_mut());
+    Zeroizing::new(general_purpose::STANDARD.encode(key.as_ref()))
+}
+
+// The rec1 for MSG_USER_AUTHEN_RSP and the ck2 in it
+pub fn gen_server_rec1(
+    username: &str,
+    sid: u16,
+    time_secs: u32,
+    time_millis: u32,
+) -> (Vec<u8>, Zeroizing<String>) {
+    let server_key = gen_ck2();
+    let rec1 = encrypt_rec1(sid, username, &server_key, time_secs, time_millis);
+    (rec1, server_key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SID: u16 = 1234;
+    const SECS: u32 = 1700000000;
+    const MILLIS: u32 = 250;
+    const PASSWORD_HASH: &str =
+        "a5ftaNFOs/GqlZzl1Jx9xhLh6x2v1zsecFhHSD/WpsgJ8s606N9v+ZhMYpj/AoXKzmYUv42qnwBwEBtsiYmeIg==";
+    // sha512 of the hash followed by "12341700000000250"
+    const CK1: &str =
+        "l6GnAgxA52pBtn3MWaAR8IFPXUgEMp/Tt+6PgK0kAgRJTyE7lxFy7ujdm54VrOXb5NkRFBxQuI9DSL5BCz7g/Q==";
+    // "1234 test user c2VjcmV0" under the key for SID, SECS and MILLIS
+    const REC1: [u8; 23] = [
+        0x6c, 0x73, 0x54, 0x04, 0xd3, 0xf0, 0x8d, 0xc7, 0x14, 0x32, 0xc7, 0x1a, 0x71, 0x81, 0xae,
+        0x42, 0x4a, 0x75, 0x52, 0xd2, 0x5f, 0xb8, 0x62,
+    ];
+
+    #[test]
+    fn derives_ck1() {
+        assert_eq!(hash_password("hunter2"), PASSWORD_HASH);
+        assert_eq!(ck1_from_hash(PASSWORD_HASH, SID, SECS, MILLIS), CK1);
+        assert_eq!(*gen_ck1("hunter2", SID, SECS, MILLIS), CK1);
+
+        assert!(verify_ck1(CK1, "hunter2", SID, SECS, MILLIS));
+        assert!(verify_ck1_hash(CK1, PASSWORD_HASH, SID, SECS, MILLIS));
+        assert!(!verify_ck1(CK1, "hunter3", SID, SECS, MILLIS));
+        assert!(!verify_ck1(CK1, "hunter2", SID, SECS, MILLIS + 1));
+        assert!(!verify_ck1_hash(
+            &CK1[1..],
+            PASSWORD_HASH,
+            SID,
+            SECS,
+            MILLIS
+        ));
+        assert!(!verify_ck1_hash("", PASSWORD_HASH, SID, SECS, MILLIS));
+    }
+
+    #[test]
+    fn encrypts_and_decrypts() {
+        let rec1 = encrypt_rec1(SID, "test user", "c2VjcmV0", SECS, MILLIS);
+        assert_eq!(rec1, REC1);
+
+        let record = read_client_rec1(&REC1, SID, SECS, MILLIS).unwrap();
+        assert_eq!(record.sid, SID);
+        assert_eq!(record.username, "test user");
+        assert_eq!(record.key, "c2VjcmV0");
+
+        // a client's whole rec1, checked the way a server would
+        let rec1 = gen_rec1(
+            String::from("player"),
+            String::from("hunter2"),
+            SID,
+            SECS,
+            MILLIS,
+        );
+        let record = read_client_rec1(&rec1, SID, SECS, MILLIS).unwrap();
+        assert_eq!(record.username, "player");
+        assert!(verify_ck1_hash(
+            &record.key,
+            PASSWORD_HASH,
+            SID,
+            SECS,
+            MILLIS
+        ));
+
+        // decrypting wipes the buffer
+        let mut rec1 = REC1.to_vec();
+        decrypt_rec1(&mut rec1, SID, SECS, MILLIS).unwrap();
+        assert!(rec1.iter().all(|b| *b == 0));
+    }
+
+    #[test]
+    fn rejects_bad_records() {
+        // the wrong time makes garbage
+        assert!(read_client_rec1(&REC1, SID, SECS, MILLIS + 1).is_err());
+
+        assert!(parse_rec1(b"1234 test user c2VjcmV0", SID).is_ok());
+        let err = parse_rec1(b"1235 test user c2VjcmV0", SID).err().unwrap();
+        assert_eq!(err, "Rec1 is for session 1235, not 1234");
+        assert!(parse_rec1(b"1234 c2VjcmV0", SID).is_err());
+        assert!(parse_rec1(b"1234  c2VjcmV0", SID).is_err());
+        assert!(parse_rec1(b"sid test user c2VjcmV0", SID).is_err());
+        assert!(parse_rec1(b"1234 test \xff c2VjcmV0", SID).is_err());
+
+        // a ck1 made with the wrong password
+        let rec1 = gen_rec1(
+            String::from("player"),
+            String::from("letmein"),
+            SID,
+            SECS,
+            MILLIS,
+        );
+        let record = read_client_rec1(&rec1, SID, SECS, MILLIS).unwrap();
+        assert!(!verify_ck1(&record.key, "hunter2", SID, SECS, MILLIS));
+    }
+
+    #[test]
+    fn issues_ck2() {
+        let ck2 = gen_ck2();
+        assert_eq!(
+            general_purpose::STANDARD
+                .decode(ck2.as_bytes())
+                .unwrap()
+                .len(),
+            64
+        );
+        assert_eq!(ck2.len(), CK1.len());
+        assert_ne!(gen_ck2(), ck2);
+
+        let (rec1, ck2) = gen_server_rec1("player", SID, SECS, MILLIS);
+        let mut reply = rec1.clone();
+        let record = decrypt_rec1(&mut reply, SID, SECS, MILLIS).unwrap();
+        assert_eq!(record.username, "player");
+        assert_eq!(record.key, *ck2);
+        assert_eq!(rec1, encrypt_rec1(SID, "player", &ck2, SECS, MILLIS));
+    }
 }
@@ -1,6 +1,8 @@
 use std::{
+    env,
     fs::{self, File},
     io::Read,
+    process,
 };
 
 mod PatchClient;
@@ -9,33 +11,83 @@ use async_process::Command;
 use PatchClient::install_min;
 
 mod WizClient;
+mod bind_helper;
+mod cli;
 mod crypto;
+mod mod_helper;
 mod packet_helper;
 mod table_list_parser;
 
 #[macro_use]
 extern crate num_derive;
 
+use crate::packet_helper::message_helper::get_services_with_overrides;
 use crate::PatchClient::get_ck2;
 
 #[tokio::main]
 async fn main() {
-    install_min().await;
+    let args: Vec<String> = env::args().skip(1).collect();
+    if let Some(res) = cli::run(&args).await {
+        if let Err(e) = res {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+        return;
+    }
 
-    let username = String::from("bighelp25");
-    let password = String::from("bighelp25");
-    let (ck2, uid) = match get_ck2(username.clone(), password) {
-        Ok((ck2, uid)) => (ck2, uid),
-        Err(e) => panic!("{}", e),
+    let options = match cli::launch_options(&args) {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
     };
-    println!("{} {}", ck2, uid);
-
-    let mut launch = Command::new("sh");
-    launch.current_dir("/home/binarybandit/Desktop/Wizard101Launcher/test/Bin");
-    launch.arg("-c");
-    launch.arg(format!(
-        "wine WizardGraphicalClient.exe -L login.us.wizard101.com 12000 -U ..{} {} {}",
-        uid, ck2, username
-    ));
-    launch.spawn().unwrap();
+    let services = match get_services_with_overrides(&options.service_files) {
+        Ok(services) => services,
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    };
+
+    let (username, password) = match cli::credentials() {
+        Ok(credentials) => credentials,
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    };
+
+    install_min(&services, options.verify).await;
+
+    let (record, uid) = match get_ck2(username, password, &services) {
+        Ok((record, uid)) => (record, uid),
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    };
+
+    // The client only takes the ck2 as an argument, so the copy in the
+    // Command can't be wiped. The record wipes its own copy when it's
+    // dropped, both go as soon as the client is started.
+    let mut launch = Command::new("wine");
+    launch.current_dir(&options.client_dir);
+    launch.args([
+        "WizardGraphicalClient.exe",
+        "-L",
+        "login.us.wizard101.com",
+        "12000",
+        "-U",
+    ]);
+    launch.arg(format!("..{}", uid));
+    launch.arg(&record.key);
+    launch.arg(&record.username);
+    let spawned = launch.spawn();
+    drop(launch);
+    drop(record);
+    if let Err(e) = spawned {
+        eprintln!("Couldn't start the client: {}", e);
+        process::exit(1);
+    }
 }
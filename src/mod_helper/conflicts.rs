@@ -0,0 +1,286 @@
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt;
+
+use super::manager::game_file;
+use super::package::{Manifest, Package};
+use super::resolver::resolve;
+use super::vfs::{normalize, path_key};
+use super::xml_patch::Operation;
+
+// Where two or more mods overlap, from coarse to fine:
+//   file:     they change the same game file. Their entries are merged into
+//             it, the last one in load order goes in on top.
+//   entry:    they replace the same wad entry, or one patches an entry
+//             another replaces. Only the last replacement is kept, and
+//             patches apply on top of it.
+//   selector: their xml patches select the same nodes of an entry. Patches
+//             apply in load order, so the last one has the final say.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Level {
+    File,
+    Entry,
+    Selector,
+}
+
+impl fmt::Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Level::File => write!(f, "file"),
+            Level::Entry => write!(f, "entry"),
+            Level::Selector => write!(f, "selector"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Conflict {
+    pub level: Level,
+    // game file for file conflicts, vfs path of the entry otherwise
+    pub path: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub select: Option<String>,
+    // in load order
+    pub mods: Vec<String>,
+    pub winner: String,
+}
+
+impl fmt::Display for Conflict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.level, self.path)?;
+        if let Some(select) = &self.select {
+            write!(f, " {}", select)?;
+        }
+        write!(f, ": {}, {} wins", self.mods.join(" < "), self.winner)
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ConflictReport {
+    pub order: Vec<String>,
+    pub conflicts: Vec<Conflict>,
+}
+
+// Adds `id` to the mods of `key`, keeping them in load order
+fn add<K: Ord>(map: &mut BTreeMap<K, Vec<String>>, key: K, id: &str) {
+    let mods = map.entry(key).or_default();
+    if mods.last().is_none_or(|last| last != id) {
+        mods.push(id.to_string());
+    }
+}
+
+// Keys `path` the way the vfs does, so paths it treats as one file are one
+// here too. The first spelling seen is the one reported.
+fn key(spellings: &mut BTreeMap<String, String>, path: &str) -> String {
+    let key = path_key(path);
+    spellings
+        .entry(key.clone())
+        .or_insert_with(|| normalize(path));
+    key
+}
+
+fn conflicts_in<K>(
+    level: Level,
+    map: BTreeMap<K, Vec<String>>,
+    key: impl Fn(K) -> (String, Option<String>),
+) -> impl Iterator<Item = Conflict> {
+    map.into_iter()
+        .filter(|(_, mods)| mods.len() > 1)
+        .map(move |(k, mods)| {
+            let (path, select) = key(k);
+            Conflict {
+                level,
+                path,
+                select,
+                winner: mods.last().unwrap().clone(),
+                mods,
+            }
+        })
+}
+
+// Every overlap between `packages`, in the load order they'd be applied in
+pub fn find_conflicts(packages: &mut [Package]) -> Result<ConflictReport, String> {
+    let manifests: Vec<&Manifest> = packages.iter().map(|p| p.manifest()).collect();
+    let order = resolve(&manifests).map_err(|errors| {
+        errors
+            .iter()
+            .map(|e| e.to_string())
+            .collect::<Vec<_>>()
+            .join("\n")
+    })?;
+
+    let mut files: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    let mut replaced: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    let mut patched: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    let mut selected: BTreeMap<(String, String), Vec<String>> = BTreeMap::new();
+    // path_key -> first spelling, for files and entries both
+    let mut spellings: BTreeMap<String, String> = BTreeMap::new();
+
+    for id in &order {
+        let package = packages
+            .iter_mut()
+            .find(|p| p.manifest().id == *id)
+            .unwrap();
+        for path in package.manifest().files.keys() {
+            add(&mut replaced, key(&mut spellings, path), id);
+            if let Some(file) = game_file(&normalize(path)) {
+                add(&mut files, key(&mut spellings, &file), id);
+            }
+        }
+        for set in package.patch_sets()? {
+            for patch in set.patches {
+                if let Some(file) = game_file(&normalize(&patch.entry)) {
+                    add(&mut files, key(&mut spellings, &file), id);
+                }
+                let entry = key(&mut spellings, &patch.entry);
+                add(&mut patched, entry.clone(), id);
+                // adding to the same node twice keeps both
+                if !matches!(patch.op, Operation::Add { .. }) {
+                    add(&mut selected, (entry, patch.select), id);
+                }
+            }
+        }
+    }
+
+    // patches go on top of every replacement, whatever the load order
+    let mut entries = replaced;
+    for (entry, patchers) in patched {
+        if let Some(mods) = entries.get_mut(&entry) {
+            for id in patchers {
+                mods.retain(|m| *m != id);
+                mods.push(id);
+            }
+        }
+    }
+
+    let spelled = |k: String| spellings[&k].clone();
+    let mut conflicts: Vec<Conflict> = conflicts_in(Level::File, files, |f| (spelled(f), None))
+        .chain(conflicts_in(Level::Entry, entries, |e| (spelled(e), None)))
+        .chain(conflicts_in(Level::Selector, selected, |(e, s)| {
+            (spelled(e), Some(s))
+        }))
+        .collect();
+    conflicts.sort_by(|a, b| (a.level, &a.path, &a.select).cmp(&(b.level, &b.path, &b.select)));
+    Ok(ConflictReport { order, conflicts })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mod_helper::package::{PackageBuilder, EXTENSION};
+    use crate::mod_helper::version::Version;
+    use std::path::Path;
+
+    const FOO: &str = "Root.wad/GameData/Foo.xml";
+
+    fn package(dir: &Path, id: &str, files: &[&str], patches: &[(&str, &str)]) -> Package {
+        let path = dir.join(format!("{}.{}", id, EXTENSION));
+        let mut builder = PackageBuilder::new(Manifest::new(id, Version::new(1, 0, 0)));
+        for file in files {
+            builder.add_file(file, b"<Foo/>".to_vec());
+        }
+        let patches: Vec<String> = patches
+            .iter()
+            .map(|(select, op)| {
+                format!(
+                    r#"{{"entry": "{}", "select": "{}", "op": "{}", "xml": "<A/>",
+                        "name": "n", "value": "v"}}"#,
+                    FOO, select, op
+                )
+            })
+            .collect();
+        if !patches.is_empty() {
+            let set = format!(r#"{{"patches": [{}]}}"#, patches.join(","));
+            builder.add_patch("p.json", set.into_bytes());
+        }
+        builder.write(&path).unwrap();
+        Package::open(&path).unwrap()
+    }
+
+    #[test]
+    fn finds_overlaps_at_every_level() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut packages = vec![
+            package(
+                dir.path(),
+                "d",
+                &["Root.wad/GameData/Bar.txt"],
+                &[("/Foo", "add")],
+            ),
+            package(dir.path(), "c", &[], &[("/Foo", "set-attribute")]),
+            package(dir.path(), "b", &[FOO], &[("/Foo", "remove")]),
+            package(dir.path(), "a", &[FOO], &[]),
+            package(dir.path(), "e", &["Other.wad/Foo.xml"], &[]),
+        ];
+        let report = find_conflicts(&mut packages).unwrap();
+        assert_eq!(report.order, ["a", "b", "c", "d", "e"]);
+
+        let lines: Vec<String> = report.conflicts.iter().map(|c| c.to_string()).collect();
+        assert_eq!(
+            lines,
+            [
+                "file Data/GameData/Root.wad: a < b < c < d, d wins",
+                "entry Root.wad/GameData/Foo.xml: a < b < c < d, d wins",
+                // adding to a node doesn't fight over it
+                "selector Root.wad/GameData/Foo.xml /Foo: b < c, c wins",
+            ]
+        );
+
+        let json = serde_json::to_value(&report).unwrap();
+        assert_eq!(json["conflicts"][0]["level"], "file");
+        assert!(json["conflicts"][0].get("select").is_none());
+        assert_eq!(json["conflicts"][2]["select"], "/Foo");
+        assert_eq!(json["conflicts"][2]["winner"], "c");
+    }
+
+    #[test]
+    fn patches_win_over_later_replacements() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut packages = vec![
+            package(dir.path(), "a", &[], &[("/Foo", "remove")]),
+            package(dir.path(), "b", &[FOO], &[]),
+        ];
+        let report = find_conflicts(&mut packages).unwrap();
+        let entry = report
+            .conflicts
+            .iter()
+            .find(|c| c.level == Level::Entry)
+            .unwrap();
+        assert_eq!(entry.mods, ["b", "a"]);
+        assert_eq!(entry.winner, "a");
+        assert!(report.conflicts.iter().all(|c| c.level != Level::Selector));
+    }
+
+    #[test]
+    fn paths_differing_only_in_case_conflict() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut packages = vec![
+            package(dir.path(), "a", &[FOO], &[]),
+            package(dir.path(), "b", &["root.wad\\gamedata\\FOO.xml"], &[]),
+        ];
+        let report = find_conflicts(&mut packages).unwrap();
+        let lines: Vec<String> = report.conflicts.iter().map(|c| c.to_string()).collect();
+        assert_eq!(
+            lines,
+            [
+                "file Data/GameData/Root.wad: a < b, b wins",
+                "entry Root.wad/GameData/Foo.xml: a < b, b wins",
+            ]
+        );
+    }
+
+    #[test]
+    fn unresolvable_sets_are_an_error() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut a = Manifest::new("a", Version::new(1, 0, 0));
+        a.dependencies
+            .insert(String::from("missing"), "*".parse().unwrap());
+        let path = dir.path().join("a.midas");
+        PackageBuilder::new(a).write(&path).unwrap();
+        let err = find_conflicts(&mut [Package::open(&path).unwrap()])
+            .err()
+            .unwrap();
+        assert_eq!(err, "a needs missing *, which isn't installed");
+    }
+}
@@ -0,0 +1,902 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use super::package::{Manifest, Package, EXTENSION};
+use super::resolver::{resolve, stack_layers};
+use super::signing::{Policy, TrustStore};
+use super::version::Version;
+use super::vfs::{normalize, path_key, Vfs};
+use super::xml_patch::PatchFailure;
+use crate::packet_helper::message_helper::wad_helper::{
+    diff::{diff, WadDiff},
+    file_crc, Archive,
+};
+use crate::table_list_parser::PatchFile;
+
+// Installs mods into a game dir without losing the original files.
+//
+// Everything lives in <game dir>/Mods: the installed packages, a backup of
+// every game file the enabled mods replaced, state.json tying it together
+// and the keys of the publishers whose packages are trusted. Installing,
+// enabling and disabling only change the state, `apply` then brings the
+// game files in line with it. With nothing enabled that means every backup
+// is put back, giving the vanilla install again.
+//
+// let mut manager = ModManager::open("./test/")?;
+// manager.install("my_mod.midas")?;
+// manager.enable("my_mod")?;
+// let report = manager.apply()?;
+
+pub const MODS_DIR: &str = "Mods";
+// Where the game's wads are, relative to the game dir
+pub const GAME_DATA_DIR: &str = "Data/GameData";
+const STATE_FILE: &str = "state.json";
+const TRUST_FILE: &str = "trusted_keys.json";
+const PACKAGES_DIR: &str = "packages";
+const BACKUP_DIR: &str = "backup";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InstalledMod {
+    pub version: Version,
+    // file name in Mods/packages
+    pub package: String,
+    pub enabled: bool,
+    // trusted publisher that signed the package
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub publisher: Option<String>,
+    // game revision the mod's patches stopped applying at
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub needs_review: Option<String>,
+}
+
+// A game file the enabled mods replaced
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChangedFile {
+    // size and crc of the original, kept in Mods/backup
+    pub size: u64,
+    pub crc: u32,
+    // crc of what the mods made of it
+    pub modded_crc: u32,
+    // mods with files or patches in it
+    pub mods: Vec<String>,
+    // the game doesn't have this file, restoring it means removing it
+    #[serde(default)]
+    pub added: bool,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct State {
+    // what installing an unsigned package does
+    #[serde(default)]
+    pub unsigned: Policy,
+    #[serde(default)]
+    pub mods: BTreeMap<String, InstalledMod>,
+    // by path relative to the game dir, eg. Data/GameData/Root.wad
+    #[serde(default)]
+    pub files: BTreeMap<String, ChangedFile>,
+}
+
+// What installing a package did
+#[derive(Debug)]
+pub struct InstallReport {
+    pub manifest: Manifest,
+    // set when the package isn't signed and the policy is to warn about it
+    pub warning: Option<String>,
+}
+
+#[derive(Debug, Default)]
+pub struct ApplyReport {
+    // unsigned mods that went in, when the policy is to warn about them
+    pub warnings: Vec<String>,
+    // game files written with mods in them
+    pub written: Vec<String>,
+    // game files that are back to their originals
+    pub restored: Vec<String>,
+    pub failed: Vec<PatchFailure>,
+}
+
+#[derive(Debug, Default)]
+pub struct UpdateReport {
+    // modded game files the update replaced
+    pub replaced: Vec<String>,
+    pub apply: ApplyReport,
+    // (mod, vfs path) of the entries the enabled mods replace or patch that
+    // the update changed
+    pub overridden: Vec<(String, String)>,
+    // mods with entries the update changed or patches that don't apply to
+    // the new files anymore
+    pub needs_review: Vec<String>,
+}
+
+pub struct ModManager {
+    game_dir: PathBuf,
+    state: State,
+    trust: TrustStore,
+    // the game's file list, originals are checked against it when it's there
+    file_list: Vec<PatchFile>,
+}
+
+// Game file a vfs path ends up in, eg. Data/GameData/Root.wad
+pub fn game_file(path: &str) -> Option<String> {
+    path.split_once(".wad/")
+        .map(|(wad, _)| format!("{}/{}.wad", GAME_DATA_DIR, wad))
+}
+
+// Vfs paths of every entry a package replaces or patches
+fn entries_of(package: &mut Package) -> Result<BTreeSet<String>, String> {
+    let mut paths: BTreeSet<String> = package.manifest().files.keys().cloned().collect();
+    for set in package.patch_sets()? {
+        paths.extend(set.patches.into_iter().map(|p| p.entry));
+    }
+    Ok(paths)
+}
+
+// Writes next to `dest` first so a failure never leaves half a game file
+fn replace_file(
+    dest: &Path,
+    write: impl FnOnce(&Path) -> Result<(), String>,
+) -> Result<(), String> {
+    let mut tmp = dest.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
+    if let Err(e) = write(&tmp) {
+        let _ = fs::remove_file(&tmp);
+        return Err(e);
+    }
+    fs::rename(&tmp, dest).or(Err(format!("Couldn't replace '{}'", dest.display())))
+}
+
+fn copy_file(from: &Path, to: &Path) -> Result<(), String> {
+    fs::copy(from, to).or(Err(format!(
+        "Couldn't copy '{}' to '{}'",
+        from.display(),
+        to.display()
+    )))?;
+    Ok(())
+}
+
+fn unsigned_warning(id: &str) -> String {
+    format!("{} isn't signed", id)
+}
+
+fn file_size(path: &Path) -> Result<u64, String> {
+    fs::metadata(path)
+        .map(|m| m.len())
+        .or(Err(format!("Couldn't read '{}'", path.display())))
+}
+
+impl ModManager {
+    pub fn open<P: AsRef<Path>>(game_dir: P) -> Result<ModManager, String> {
+        let game_dir = game_dir.as_ref().to_path_buf();
+        let state_path = game_dir.join(MODS_DIR).join(STATE_FILE);
+        let state = match fs::read(&state_path) {
+            Ok(contents) => serde_json::from_slice(&contents)
+                .map_err(|e| format!("Invalid mod state '{}': {}", state_path.display(), e))?,
+            Err(_) => State::default(),
+        };
+        let trust = TrustStore::load(game_dir.join(MODS_DIR).join(TRUST_FILE))?;
+        Ok(ModManager {
+            game_dir,
+            state,
+            trust,
+            file_list: Vec::new(),
+        })
+    }
+
+    pub fn with_file_list(&mut self, file_list: Vec<PatchFile>) -> &mut Self {
+        self.file_list = file_list;
+        self
+    }
+
+    pub fn state(&self) -> &State {
+        &self.state
+    }
+
+    pub fn trust_store(&mut self) -> &mut TrustStore {
+        &mut self.trust
+    }
+
+    pub fn set_policy(&mut self, policy: Policy) {
+        self.state.unsigned = policy;
+    }
+
+    pub fn save(&self) -> Result<(), String> {
+        let dir = self.game_dir.join(MODS_DIR);
+        fs::create_dir_all(&dir).or(Err(format!("Failed to create '{}'", dir.display())))?;
+        self.trust.save()?;
+        let path = dir.join(STATE_FILE);
+        let contents = serde_json::to_string_pretty(&self.state).unwrap();
+        replace_file(&path, |tmp| {
+            fs::write(tmp, &contents).or(Err(format!("Failed to write file '{}'", tmp.display())))
+        })
+    }
+
+    fn package_path(&self, file: &str) -> PathBuf {
+        self.game_dir.join(MODS_DIR).join(PACKAGES_DIR).join(file)
+    }
+
+    fn backup_path(&self, file: &str) -> PathBuf {
+        self.game_dir.join(MODS_DIR).join(BACKUP_DIR).join(file)
+    }
+
+    // Game files the mod changes as of the last apply
+    pub fn changes(&self, id: &str) -> Vec<&str> {
+        self.state
+            .files
+            .iter()
+            .filter(|(_, f)| f.mods.iter().any(|m| m == id))
+            .map(|(path, _)| path.as_str())
+            .collect()
+    }
+
+    // Checks a file that's about to be backed up, or was just restored,
+    // against the game's file list
+    fn check_original(&self, file: &str, size: u64, crc: u32) -> Result<(), String> {
+        match self.file_list.iter().find(|f| f.name() == file) {
+            Some(f) if f.size() as u64 != size || f.crc() != crc => Err(format!(
+                "{} doesn't match the game's file list (size {}, crc {:08x}, expected size {}, crc {:08x})",
+                file,
+                size,
+                crc,
+                f.size(),
+                f.crc()
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    // Copies a package into Mods/packages, replacing an installed version of
+    // the same mod. The mod stays enabled if it was. A signed package has to
+    // be signed by a trusted publisher, an unsigned one is up to the policy.
+    pub fn install<P: AsRef<Path>>(&mut self, path: P) -> Result<InstallReport, String> {
+        let path = path.as_ref();
+        let mut package = Package::open(path)?;
+        package.validate().map_err(|problems| {
+            format!("{} is invalid:\n{}", path.display(), problems.join("\n"))
+        })?;
+        let publisher = package.verify_signature(&self.trust, self.state.unsigned)?;
+        let manifest = package.manifest().clone();
+
+        let file = format!("{}.{}", manifest.id, EXTENSION);
+        let dest = self.package_path(&file);
+        if let Some(parent) = dest.parent() {
+            fs::create_dir_all(parent)
+                .or(Err(format!("Failed to create '{}'", parent.display())))?;
+        }
+        replace_file(&dest, |tmp| copy_file(path, tmp))?;
+
+        let warning = match publisher {
+            None if self.state.unsigned == Policy::Warn => Some(unsigned_warning(&manifest.id)),
+            _ => None,
+        };
+        let enabled = self.state.mods.get(&manifest.id).is_some_and(|m| m.enabled);
+        self.state.mods.insert(
+            manifest.id.clone(),
+            InstalledMod {
+                version: manifest.version,
+                package: file,
+                enabled,
+                publisher,
+                needs_review: None,
+            },
+        );
+        Ok(InstallReport { manifest, warning })
+    }
+
+    // Forgets the mod and deletes its package, apply afterwards to take it
+    // out of the game files
+    pub fn uninstall(&mut self, id: &str) -> Result<(), String> {
+        let installed = self
+            .state
+            .mods
+            .remove(id)
+            .ok_or(format!("{} isn't installed", id))?;
+        let path = self.package_path(&installed.package);
+        fs::remove_file(&path).or(Err(format!("Failed to remove '{}'", path.display())))
+    }
+
+    pub fn set_enabled(&mut self, id: &str, enabled: bool) -> Result<(), String> {
+        match self.state.mods.get_mut(id) {
+            Some(m) => {
+                m.enabled = enabled;
+                Ok(())
+            }
+            None => Err(format!("{} isn't installed", id)),
+        }
+    }
+
+    pub fn enable(&mut self, id: &str) -> Result<(), String> {
+        self.set_enabled(id, true)
+    }
+
+    pub fn disable(&mut self, id: &str) -> Result<(), String> {
+        self.set_enabled(id, false)
+    }
+
+    pub fn disable_all(&mut self) {
+        for m in self.state.mods.values_mut() {
+            m.enabled = false;
+        }
+    }
+
+    // Manifests of every installed mod, by id
+    pub fn manifests(&self) -> Result<Vec<Manifest>, String> {
+        self.state
+            .mods
+            .values()
+            .map(|m| Package::open(self.package_path(&m.package)).map(|p| p.manifest().clone()))
+            .collect()
+    }
+
+    // Packages of the enabled mods, by id
+    pub fn enabled_packages(&self) -> Result<Vec<Package>, String> {
+        self.state
+            .mods
+            .values()
+            .filter(|m| m.enabled)
+            .map(|m| Package::open(self.package_path(&m.package)))
+            .collect()
+    }
+
+    // Checks an installed package is still what was installed, its files
+    // against the manifest and the manifest against its signature. Returns
+    // the warning for an unsigned package, if the policy is to warn.
+    fn check_package(&self, package: &mut Package) -> Result<Option<String>, String> {
+        let id = package.manifest().id.clone();
+        package
+            .validate()
+            .map_err(|problems| format!("{} is invalid:\n{}", id, problems.join("\n")))?;
+        let publisher = package.verify_signature(&self.trust, self.state.unsigned)?;
+        let signer = self.state.mods.get(&id).and_then(|m| m.publisher.as_ref());
+        match (publisher, signer) {
+            (None, Some(signer)) => Err(format!(
+                "{} was signed by {} when it was installed, but isn't anymore",
+                id, signer
+            )),
+            (None, None) if self.state.unsigned == Policy::Warn => Ok(Some(unsigned_warning(&id))),
+            _ => Ok(None),
+        }
+    }
+
+    // Puts the original of a changed file back, checking it's the file that
+    // was backed up
+    fn restore_file(&self, file: &str, changed: &ChangedFile) -> Result<(), String> {
+        let dest = self.game_dir.join(file);
+        let current = file_crc(&dest).ok();
+        if changed.added {
+            if current.is_some() {
+                fs::remove_file(&dest).or(Err(format!("Failed to remove '{}'", dest.display())))?;
+            }
+            return Ok(());
+        }
+        if current == Some(changed.crc) && file_size(&dest)? == changed.size {
+            return Ok(());
+        }
+        if current.is_some() && current != Some(changed.modded_crc) {
+            return Err(format!(
+                "{} was changed by something else since mods were applied",
+                file
+            ));
+        }
+
+        let backup = self.backup_path(file);
+        replace_file(&dest, |tmp| copy_file(&backup, tmp))?;
+        let (size, crc) = (file_size(&dest)?, file_crc(&dest)?);
+        if size != changed.size || crc != changed.crc {
+            return Err(format!(
+                "{} restored from '{}' doesn't match the original",
+                file,
+                backup.display()
+            ));
+        }
+        self.check_original(file, size, crc)
+    }
+
+    fn back_up(&mut self, file: &str) -> Result<(), String> {
+        let src = self.game_dir.join(file);
+        let (size, crc) = (file_size(&src)?, file_crc(&src)?);
+        self.check_original(file, size, crc)?;
+
+        let backup = self.backup_path(file);
+        if let Some(parent) = backup.parent() {
+            fs::create_dir_all(parent)
+                .or(Err(format!("Failed to create '{}'", parent.display())))?;
+        }
+        replace_file(&backup, |tmp| copy_file(&src, tmp))?;
+        self.state.files.insert(
+            file.to_string(),
+            ChangedFile {
+                size,
+                crc,
+                modded_crc: crc,
+                mods: Vec::new(),
+                added: false,
+            },
+        );
+        // recorded right away, a backup nobody knows about is no backup
+        self.save()
+    }
+
+    // Brings the game files in line with the enabled mods: every changed file
+    // goes back to its original, then the wads the enabled mods touch are
+    // rebuilt on top of the originals, backing up the ones that weren't
+    // changed before. Originals are checked against their backup and, if
+    // there is one, the game's file list. Packages are checked the same way
+    // they were when they were installed.
+    pub fn apply(&mut self) -> Result<ApplyReport, String> {
+        let mut report = ApplyReport::default();
+        let mut packages = self.enabled_packages()?;
+        for package in &mut packages {
+            report.warnings.extend(self.check_package(package)?);
+        }
+        {
+            let manifests: Vec<&Manifest> = packages.iter().map(|p| p.manifest()).collect();
+            resolve(&manifests).map_err(|errors| {
+                errors
+                    .iter()
+                    .map(|e| e.to_string())
+                    .collect::<Vec<_>>()
+                    .join("\n")
+            })?;
+        }
+
+        for (file, changed) in &self.state.files {
+            self.restore_file(file, changed)?;
+        }
+
+        // path_key of a game file -> mods that go into it, however they
+        // spell it
+        let mut touched: BTreeMap<String, BTreeSet<String>> = BTreeMap::new();
+        for package in &mut packages {
+            let id = package.manifest().id.clone();
+            for path in entries_of(package)? {
+                if let Some(wad) = game_file(&normalize(&path)) {
+                    touched
+                        .entry(path_key(&wad))
+                        .or_default()
+                        .insert(id.clone());
+                }
+            }
+        }
+
+        let mut built = Vec::new();
+        if !packages.is_empty() {
+            let mut vfs = Vfs::new();
+            vfs.add_base(self.game_dir.join(GAME_DATA_DIR))?;
+            report.failed = stack_layers(&mut vfs, &mut packages)?.failed;
+            for wad in vfs.modified_wads() {
+                let file = format!("{}/{}", GAME_DATA_DIR, wad);
+                let mut tmp = self.game_dir.join(&file).into_os_string();
+                tmp.push(".modded");
+                let tmp = PathBuf::from(tmp);
+                if let Some(parent) = tmp.parent() {
+                    fs::create_dir_all(parent)
+                        .or(Err(format!("Failed to create '{}'", parent.display())))?;
+                }
+                if let Err(e) = vfs.build_wad(&wad, &tmp) {
+                    let _ = fs::remove_file(&tmp);
+                    return Err(e);
+                }
+                built.push((file, tmp));
+            }
+            // the vfs keeps the game wads open until here
+        }
+
+        for (file, tmp) in built {
+            let dest = self.game_dir.join(&file);
+            if !self.state.files.contains_key(&file) {
+                if dest.exists() {
+                    self.back_up(&file)?;
+                } else {
+                    self.state.files.insert(
+                        file.clone(),
+                        ChangedFile {
+                            size: 0,
+                            crc: 0,
+                            modded_crc: 0,
+                            mods: Vec::new(),
+                            added: true,
+                        },
+                    );
+                    self.save()?;
+                }
+            }
+            fs::rename(&tmp, &dest).or(Err(format!("Couldn't replace '{}'", dest.display())))?;
+            let changed = self.state.files.get_mut(&file).unwrap();
+            changed.modded_crc = file_crc(&dest)?;
+            changed.mods = touched
+                .remove(&path_key(&file))
+                .unwrap_or_default()
+                .into_iter()
+                .collect();
+            report.written.push(file);
+        }
+
+        let written: BTreeSet<&String> = report.written.iter().collect();
+        let unchanged: Vec<String> = self
+            .state
+            .files
+            .keys()
+            .filter(|f| !written.contains(f))
+            .cloned()
+            .collect();
+        for file in unchanged {
+            let changed = self.state.files.remove(&file).unwrap();
+            let backup = self.backup_path(&file);
+            if !changed.added {
+                fs::remove_file(&backup)
+                    .or(Err(format!("Failed to remove '{}'", backup.display())))?;
+            }
+            report.restored.push(file);
+        }
+
+        // mods that apply cleanly again don't need looking at anymore
+        for (id, m) in self.state.mods.iter_mut().filter(|(_, m)| m.enabled) {
+            if !report.failed.iter().any(|f| f.mod_name == *id) {
+                m.needs_review = None;
+            }
+        }
+
+        self.save()?;
+        Ok(report)
+    }
+
+    // Changed files whose original is still what the game's file list has,
+    // the mods on them are up to date and the patcher should leave them be
+    pub fn current_files(&self) -> Vec<String> {
+        self.state
+            .files
+            .iter()
+            .filter(|(file, changed)| {
+                !changed.added && self.check_original(file, changed.size, changed.crc).is_ok()
+            })
+            .map(|(file, _)| file.clone())
+            .collect()
+    }
+
+    // Changed files whose original isn't what the game's file list has
+    // anymore, the patcher has to put the new original in for the mods to go
+    // back on top of
+    pub fn outdated_files(&self) -> Vec<String> {
+        self.state
+            .files
+            .iter()
+            .filter(|(file, changed)| {
+                !changed.added
+                    && self
+                        .check_original(file, changed.size, changed.crc)
+                        .is_err()
+            })
+            .map(|(file, _)| file.clone())
+            .collect()
+    }
+
+    // Run after the patcher: every modded file it replaced is the new
+    // original, so it's backed up as such and the mods are applied again on
+    // top of it. Mods replacing or patching entries the update changed, and
+    // mods whose patches no longer apply, are marked as needing a review at
+    // `revision`.
+    pub fn reapply_after_update(&mut self, revision: &str) -> Result<UpdateReport, String> {
+        let mut report = UpdateReport::default();
+        let mut updates: Vec<(String, WadDiff)> = Vec::new();
+        let changed: Vec<(String, ChangedFile)> = self
+            .state
+            .files
+            .iter()
+            .filter(|(_, c)| !c.added)
+            .map(|(f, c)| (f.clone(), c.clone()))
+            .collect();
+        for (file, changed) in changed {
+            let path = self.game_dir.join(&file);
+            if !path.exists() || file_crc(&path)? == changed.modded_crc {
+                continue;
+            }
+            // what the update did to the original, before the backup of it
+            // is replaced
+            let update = diff(
+                &Archive::open(self.backup_path(&file))?,
+                &Archive::open(&path)?,
+            );
+            self.state.files.remove(&file);
+            self.back_up(&file)?;
+            report.replaced.push(file.clone());
+            if !update.is_empty() {
+                updates.push((file, update));
+            }
+        }
+        if report.replaced.is_empty() {
+            return Ok(report);
+        }
+
+        for mut package in self.enabled_packages()? {
+            let id = package.manifest().id.clone();
+            for path in entries_of(&mut package)? {
+                // matched the way the vfs matches them, whatever the case
+                let key = path_key(&path);
+                let changed = match (game_file(&key), key.split_once(".wad/")) {
+                    (Some(file), Some((_, entry))) => updates.iter().any(|(f, update)| {
+                        path_key(f) == path_key(&file)
+                            && update.changes.iter().any(|c| path_key(&c.name) == entry)
+                    }),
+                    _ => false,
+                };
+                if changed {
+                    report.overridden.push((id.clone(), path));
+                }
+            }
+        }
+
+        report.apply = self.apply()?;
+        let flagged = report
+            .overridden
+            .iter()
+            .map(|(id, _)| id)
+            .chain(report.apply.failed.iter().map(|f| &f.mod_name));
+        for id in flagged {
+            if let Some(m) = self.state.mods.get_mut(id) {
+                m.needs_review = Some(revision.to_string());
+                if !report.needs_review.contains(id) {
+                    report.needs_review.push(id.clone());
+                }
+            }
+        }
+        self.save()?;
+        Ok(report)
+    }
+
+    // Disables every mod and puts all the original game files back
+    pub fn restore(&mut self) -> Result<ApplyReport, String> {
+        self.disable_all();
+        self.apply()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mod_helper::package::PackageBuilder;
+    use crate::mod_helper::signing::PublisherKey;
+    use crate::packet_helper::message_helper::wad_helper::fixtures::{write_wad, ENTRIES};
+
+    const ROOT: &str = "Data/GameData/Root.wad";
+
+    fn game(dir: &Path) -> PathBuf {
+        let game_dir = dir.join("game");
+        let game_data = game_dir.join(GAME_DATA_DIR);
+        fs::create_dir_all(&game_data).unwrap();
+        write_wad(&game_data, "Root.wad", ENTRIES);
+        game_dir
+    }
+
+    fn package(dir: &Path, id: &str, files: &[(&str, &[u8])]) -> PathBuf {
+        let path = dir.join(format!("{}.{}", id, EXTENSION));
+        let mut builder = PackageBuilder::new(Manifest::new(id, Version::new(1, 0, 0)));
+        for (file, data) in files {
+            builder.add_file(file, data.to_vec());
+        }
+        builder.write(&path).unwrap();
+        path
+    }
+
+    fn entry(game_dir: &Path, file: &str, name: &str) -> Vec<u8> {
+        Archive::open(game_dir.join(file))
+            .unwrap()
+            .read_file(name)
+            .unwrap()
+    }
+
+    #[test]
+    fn applies_and_restores_the_originals() {
+        let dir = tempfile::tempdir().unwrap();
+        let game_dir = game(dir.path());
+        let original = fs::read(game_dir.join(ROOT)).unwrap();
+        let mod_path = package(
+            dir.path(),
+            "my_mod",
+            &[
+                ("Root.wad/GameData/Bar.txt", b"modded"),
+                ("New.wad/Extra.txt", b"extra"),
+            ],
+        );
+
+        let mut manager = ModManager::open(&game_dir).unwrap();
+        manager.install(&mod_path).unwrap();
+        // installing alone doesn't touch the game
+        assert!(manager.apply().unwrap().written.is_empty());
+        manager.enable("my_mod").unwrap();
+        let report = manager.apply().unwrap();
+        assert_eq!(report.written, ["Data/GameData/New.wad", ROOT]);
+        assert_eq!(entry(&game_dir, ROOT, "GameData/Bar.txt"), b"modded");
+        assert_eq!(entry(&game_dir, ROOT, "GameData/Foo.xml"), ENTRIES[0].1);
+        assert_eq!(manager.changes("my_mod"), ["Data/GameData/New.wad", ROOT]);
+        assert!(manager.state().files["Data/GameData/New.wad"].added);
+        assert!(manager.backup_path(ROOT).exists());
+
+        // the state outlives the manager
+        let mut manager = ModManager::open(&game_dir).unwrap();
+        assert!(manager.state().mods["my_mod"].enabled);
+        let report = manager.restore().unwrap();
+        assert_eq!(report.restored, ["Data/GameData/New.wad", ROOT]);
+        assert_eq!(fs::read(game_dir.join(ROOT)).unwrap(), original);
+        assert!(!game_dir.join("Data/GameData/New.wad").exists());
+        assert!(!manager.backup_path(ROOT).exists());
+        assert!(manager.state().files.is_empty());
+
+        manager.uninstall("my_mod").unwrap();
+        assert!(manager.state().mods.is_empty());
+        assert!(manager.enable("my_mod").is_err());
+    }
+
+    #[test]
+    fn checks_originals_against_the_file_list() {
+        let dir = tempfile::tempdir().unwrap();
+        let game_dir = game(dir.path());
+        let original = fs::read(game_dir.join(ROOT)).unwrap();
+        let mod_path = package(dir.path(), "m", &[("Root.wad/GameData/Bar.txt", b"modded")]);
+
+        let mut manager = ModManager::open(&game_dir).unwrap();
+        manager.install(&mod_path).unwrap();
+        manager.enable("m").unwrap();
+        manager.with_file_list(vec![PatchFile::new(ROOT, 1, 2)]);
+        let err = manager.apply().unwrap_err();
+        assert!(
+            err.contains("doesn't match the game's file list"),
+            "{}",
+            err
+        );
+        assert_eq!(fs::read(game_dir.join(ROOT)).unwrap(), original);
+
+        let crc = file_crc(game_dir.join(ROOT)).unwrap();
+        manager.with_file_list(vec![PatchFile::new(ROOT, original.len() as u32, crc)]);
+        manager.apply().unwrap();
+        assert_eq!(manager.current_files(), [ROOT]);
+    }
+
+    #[test]
+    fn leaves_files_changed_by_something_else() {
+        let dir = tempfile::tempdir().unwrap();
+        let game_dir = game(dir.path());
+        let mod_path = package(dir.path(), "m", &[("Root.wad/GameData/Bar.txt", b"modded")]);
+
+        let mut manager = ModManager::open(&game_dir).unwrap();
+        manager.install(&mod_path).unwrap();
+        manager.enable("m").unwrap();
+        manager.apply().unwrap();
+
+        fs::write(game_dir.join(ROOT), b"someone else's").unwrap();
+        let err = manager.restore().unwrap_err();
+        assert!(err.contains("changed by something else"), "{}", err);
+        assert_eq!(fs::read(game_dir.join(ROOT)).unwrap(), b"someone else's");
+    }
+
+    #[test]
+    fn reapplies_after_an_update() {
+        let dir = tempfile::tempdir().unwrap();
+        let game_dir = game(dir.path());
+        // spelled differently from the wad, it's still the same entry
+        let bar = package(
+            dir.path(),
+            "bar",
+            &[("root.wad/gamedata/BAR.txt", b"modded")],
+        );
+        let foo = package(
+            dir.path(),
+            "foo",
+            &[("Root.wad/GameData/Foo.xml", b"<Foo/>")],
+        );
+
+        let mut manager = ModManager::open(&game_dir).unwrap();
+        for (path, id) in [(&bar, "bar"), (&foo, "foo")] {
+            manager.install(path).unwrap();
+            manager.enable(id).unwrap();
+        }
+        manager.apply().unwrap();
+        // nothing was updated, nothing to do
+        let report = manager.reapply_after_update("r1").unwrap();
+        assert!(report.replaced.is_empty());
+
+        // the patcher puts a new vanilla wad in, with Bar.txt changed
+        let mut updated = ENTRIES.to_vec();
+        updated[1] = ("GameData/Bar.txt", b"updated", false);
+        let new_root = write_wad(&dir.path().join("update"), "Root.wad", &updated);
+        fs::copy(&new_root, game_dir.join(ROOT)).unwrap();
+
+        let report = manager.reapply_after_update("r2").unwrap();
+        assert_eq!(report.replaced, [ROOT]);
+        assert_eq!(manager.changes("bar"), [ROOT]);
+        assert_eq!(
+            report.overridden,
+            [(
+                String::from("bar"),
+                String::from("root.wad/gamedata/BAR.txt")
+            )]
+        );
+        assert_eq!(report.needs_review, ["bar"]);
+        assert_eq!(
+            manager.state().mods["bar"].needs_review.as_deref(),
+            Some("r2")
+        );
+        assert_eq!(manager.state().mods["foo"].needs_review, None);
+
+        // the mods are back on top of the new files, and the new files are
+        // what gets restored
+        assert_eq!(entry(&game_dir, ROOT, "GameData/Bar.txt"), b"modded");
+        assert_eq!(entry(&game_dir, ROOT, "GameData/Foo.xml"), b"<Foo/>");
+        manager.restore().unwrap();
+        assert_eq!(
+            fs::read(game_dir.join(ROOT)).unwrap(),
+            fs::read(&new_root).unwrap()
+        );
+    }
+
+    #[test]
+    fn warns_about_unsigned_packages() {
+        let dir = tempfile::tempdir().unwrap();
+        let game_dir = game(dir.path());
+        let mod_path = package(
+            dir.path(),
+            "my_mod",
+            &[("Root.wad/GameData/Bar.txt", b"modded")],
+        );
+
+        let mut manager = ModManager::open(&game_dir).unwrap();
+        let installed = manager.install(&mod_path).unwrap();
+        assert_eq!(installed.warning.as_deref(), Some("my_mod isn't signed"));
+        manager.enable("my_mod").unwrap();
+        assert_eq!(manager.apply().unwrap().warnings, ["my_mod isn't signed"]);
+
+        manager.set_policy(Policy::Allow);
+        assert!(manager.install(&mod_path).unwrap().warning.is_none());
+        assert!(manager.apply().unwrap().warnings.is_empty());
+
+        // installed before the policy changed
+        manager.set_policy(Policy::Deny);
+        assert!(manager.install(&mod_path).is_err());
+        assert!(manager.apply().is_err());
+        assert_eq!(entry(&game_dir, ROOT, "GameData/Bar.txt"), b"modded");
+    }
+
+    #[test]
+    fn checks_signatures_when_applying() {
+        let dir = tempfile::tempdir().unwrap();
+        let game_dir = game(dir.path());
+        let key = PublisherKey::generate("midas");
+        let signed = dir.path().join("signed.midas");
+        let mut builder = PackageBuilder::new(Manifest::new("my_mod", Version::new(1, 0, 0)));
+        builder.add_file("Root.wad/GameData/Bar.txt", b"modded".to_vec());
+
+        let mut manager = ModManager::open(&game_dir).unwrap();
+        manager
+            .trust_store()
+            .trust("midas", &key.public_key())
+            .unwrap();
+        builder.sign(key);
+        builder.write(&signed).unwrap();
+        let installed = manager.install(&signed).unwrap();
+        assert!(installed.warning.is_none());
+        assert_eq!(
+            manager.state().mods["my_mod"].publisher.as_deref(),
+            Some("midas")
+        );
+        manager.enable("my_mod").unwrap();
+        assert!(manager.apply().unwrap().warnings.is_empty());
+
+        // swapped for an unsigned package behind the manager's back
+        let unsigned = package(
+            dir.path(),
+            "my_mod",
+            &[("Root.wad/GameData/Bar.txt", b"evil")],
+        );
+        fs::copy(&unsigned, manager.package_path("my_mod.midas")).unwrap();
+        let err = manager.apply().unwrap_err();
+        assert!(err.contains("was signed by midas"), "{}", err);
+
+        // or signed by the publisher's key, but no longer trusted
+        fs::copy(&signed, manager.package_path("my_mod.midas")).unwrap();
+        manager.trust_store().untrust("midas").unwrap();
+        assert!(manager.apply().is_err());
+        assert_eq!(entry(&game_dir, ROOT, "GameData/Bar.txt"), b"modded");
+    }
+}
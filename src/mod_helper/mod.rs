@@ -0,0 +1,9 @@
+pub mod conflicts;
+pub mod manager;
+pub mod package;
+pub mod repository;
+pub mod resolver;
+pub mod signing;
+pub mod version;
+pub mod vfs;
+pub mod xml_patch;
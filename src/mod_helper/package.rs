@@ -0,0 +1,622 @@
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+use super::signing::{ManifestSignature, Policy, PublisherKey, TrustStore, SIGNATURE};
+use super::version::{Version, VersionReq};
+use super::vfs::BASE_LAYER;
+use super::xml_patch::PatchSet;
+use crate::packet_helper::message_helper::wad_helper::{extract::entry_path, find_files};
+
+// A .midas package is a zip with the manifest at its root, the files it
+// replaces under `files/<wad>/<entry>` and its xml patches under `patches/`.
+// Signed packages also have the manifest's signature next to it.
+pub const EXTENSION: &str = "midas";
+pub const MANIFEST: &str = "manifest.json";
+const FILES_DIR: &str = "files/";
+const PATCHES_DIR: &str = "patches/";
+
+// Nothing in a package gets inflated past this
+const MAX_FILE_SIZE: u64 = 1 << 30;
+
+pub fn sha256_hex(data: &[u8]) -> String {
+    Sha256::digest(data)
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+// Ids name the mod's layer in the vfs, so the game's own can't be one
+pub fn is_valid_id(id: &str) -> bool {
+    !id.is_empty()
+        && id != BASE_LAYER
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.'))
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Manifest {
+    pub id: String,
+    pub version: Version,
+    #[serde(default)]
+    pub name: String,
+    #[serde(default)]
+    pub description: String,
+    #[serde(default)]
+    pub authors: Vec<String>,
+    // game revision the mod was made against, eg. "V_r726845.WizardDev"
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub game_revision: Option<String>,
+    // vfs path (`<wad>/<entry>`) of every file the mod replaces or adds, and
+    // the sha256 of its contents
+    #[serde(default)]
+    pub files: BTreeMap<String, String>,
+    // patch files in the package and their sha256, applied sorted by name
+    #[serde(default)]
+    pub patches: BTreeMap<String, String>,
+    // mod id -> version constraint
+    #[serde(default)]
+    pub dependencies: BTreeMap<String, VersionReq>,
+    // mods (and versions of them) this one can't be used with
+    #[serde(default)]
+    pub conflicts: BTreeMap<String, VersionReq>,
+    // ordering hints, only used when the other mod is there too
+    #[serde(default)]
+    pub load_before: Vec<String>,
+    #[serde(default)]
+    pub load_after: Vec<String>,
+}
+
+impl Manifest {
+    pub fn new(id: &str, version: Version) -> Manifest {
+        Manifest {
+            id: id.to_string(),
+            version,
+            name: String::new(),
+            description: String::new(),
+            authors: Vec::new(),
+            game_revision: None,
+            files: BTreeMap::new(),
+            patches: BTreeMap::new(),
+            dependencies: BTreeMap::new(),
+            conflicts: BTreeMap::new(),
+            load_before: Vec::new(),
+            load_after: Vec::new(),
+        }
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Manifest, String> {
+        let path = path.as_ref();
+        let contents =
+            fs::read(path).or(Err(format!("Couldn't read manifest '{}'", path.display())))?;
+        Manifest::parse(&contents)
+            .map_err(|e| format!("Invalid manifest '{}': {}", path.display(), e))
+    }
+
+    pub fn parse(data: &[u8]) -> Result<Manifest, String> {
+        serde_json::from_slice(data).map_err(|e| e.to_string())
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).unwrap()
+    }
+
+    // Everything wrong with the manifest itself, the package contents are
+    // checked by `Package::validate`
+    pub fn problems(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        if self.id == BASE_LAYER {
+            problems.push(format!(
+                "Mod id '{}' is reserved for the game's files",
+                self.id
+            ));
+        } else if !is_valid_id(&self.id) {
+            problems.push(format!(
+                "Invalid mod id '{}', only letters, digits, '_', '-' and '.' are allowed",
+                self.id
+            ));
+        }
+        for path in self.files.keys() {
+            // has to name an entry inside a wad and stay inside the package
+            let in_wad = path
+                .split_once('/')
+                .is_some_and(|(wad, entry)| wad.ends_with(".wad") && !entry.is_empty());
+            if !in_wad || entry_path(Path::new(""), path).is_err() {
+                problems.push(format!("Invalid file path '{}'", path));
+            }
+        }
+        for path in self.patches.keys() {
+            if !path.starts_with(PATCHES_DIR) || entry_path(Path::new(""), path).is_err() {
+                problems.push(format!("Invalid patch path '{}'", path));
+            }
+        }
+        let others = [
+            ("dependency", self.dependencies.keys().collect::<Vec<_>>()),
+            ("conflict", self.conflicts.keys().collect()),
+            ("load_before", self.load_before.iter().collect()),
+            ("load_after", self.load_after.iter().collect()),
+        ];
+        for (what, ids) in others {
+            for id in ids {
+                if !is_valid_id(id) {
+                    problems.push(format!("Invalid {} id '{}'", what, id));
+                } else if *id == self.id {
+                    problems.push(format!("A mod can't name itself as a {}", what));
+                }
+            }
+        }
+        problems
+    }
+}
+
+pub struct Package {
+    path: PathBuf,
+    manifest: Manifest,
+    zip: zip::ZipArchive<fs::File>,
+}
+
+impl Package {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Package, String> {
+        let path = path.as_ref();
+        let file = fs::File::open(path).or(Err(format!("Couldn't open '{}'", path.display())))?;
+        let zip = zip::ZipArchive::new(file)
+            .map_err(|e| format!("'{}' isn't a mod package: {}", path.display(), e))?;
+        let mut package = Package {
+            path: path.to_path_buf(),
+            manifest: Manifest::new("", Version::new(0, 0, 0)),
+            zip,
+        };
+        let manifest = package.read(MANIFEST)?;
+        package.manifest = Manifest::parse(&manifest)
+            .map_err(|e| format!("Invalid manifest in '{}': {}", path.display(), e))?;
+        Ok(package)
+    }
+
+    pub fn manifest(&self) -> &Manifest {
+        &self.manifest
+    }
+
+    // Every file stored in the package, manifest included
+    pub fn names(&self) -> Vec<String> {
+        self.zip.file_names().map(String::from).collect()
+    }
+
+    pub fn read(&mut self, name: &str) -> Result<Vec<u8>, String> {
+        let path = self.path.display().to_string();
+        let mut file = self
+            .zip
+            .by_name(name)
+            .map_err(|_| format!("{} doesn't exist in {}", name, path))?;
+        let size = file.size();
+        if size > MAX_FILE_SIZE {
+            return Err(format!("{} in {} is too big", name, path));
+        }
+        // the size is only what the zip says, reading stops just past it
+        let mut data = Vec::new();
+        file.by_ref()
+            .take(size + 1)
+            .read_to_end(&mut data)
+            .map_err(|e| format!("Couldn't read {} from {}: {}", name, path, e))?;
+        if data.len() as u64 > size {
+            return Err(format!(
+                "{} in {} is bigger than its size of {}",
+                name, path, size
+            ));
+        }
+        Ok(data)
+    }
+
+    // Contents of a replaced file, by its vfs path
+    pub fn read_file(&mut self, path: &str) -> Result<Vec<u8>, String> {
+        self.read(&format!("{}{}", FILES_DIR, path))
+    }
+
+    // Every replaced file with its vfs path, to go in a vfs layer
+    pub fn files(&mut self) -> Result<Vec<(String, Vec<u8>)>, String> {
+        let paths: Vec<String> = self.manifest.files.keys().cloned().collect();
+        paths
+            .into_iter()
+            .map(|p| self.read_file(&p).map(|d| (p, d)))
+            .collect()
+    }
+
+    // The package's xml patches, in the order the manifest has them
+    pub fn patch_sets(&mut self) -> Result<Vec<PatchSet>, String> {
+        let names: Vec<String> = self.manifest.patches.keys().cloned().collect();
+        let mut sets = Vec::new();
+        for name in names {
+            let mut set: PatchSet = serde_json::from_slice(&self.read(&name)?)
+                .map_err(|e| format!("Invalid patch file {}: {}", name, e))?;
+            set.name = self.manifest.id.clone();
+            sets.push(set);
+        }
+        Ok(sets)
+    }
+
+    pub fn signature(&mut self) -> Result<Option<ManifestSignature>, String> {
+        if !self.names().iter().any(|n| n == SIGNATURE) {
+            return Ok(None);
+        }
+        let data = self.read(SIGNATURE)?;
+        serde_json::from_slice(&data)
+            .map(Some)
+            .map_err(|e| format!("Invalid signature in '{}': {}", self.path.display(), e))
+    }
+
+    // Checks who signed the manifest. Returns the trusted publisher, or None
+    // for an unsigned package that `policy` lets through.
+    pub fn verify_signature(
+        &mut self,
+        store: &TrustStore,
+        policy: Policy,
+    ) -> Result<Option<String>, String> {
+        let signature = match self.signature()? {
+            Some(signature) => signature,
+            None if policy == Policy::Deny => {
+                return Err(format!(
+                    "'{}' isn't signed, and unsigned packages aren't allowed",
+                    self.path.display()
+                ))
+            }
+            None => return Ok(None),
+        };
+        let manifest = self.read(MANIFEST)?;
+        store
+            .verify(&manifest, &signature)
+            .map(Some)
+            .map_err(|e| format!("'{}': {}", self.path.display(), e))
+    }
+
+    // Checks the manifest and that the package holds exactly the files it
+    // lists, with matching checksums
+    pub fn validate(&mut self) -> Result<(), Vec<String>> {
+        let mut problems = self.manifest.problems();
+
+        let mut listed = vec![String::from(MANIFEST), String::from(SIGNATURE)];
+        let files = self
+            .manifest
+            .files
+            .iter()
+            .map(|(p, h)| (format!("{}{}", FILES_DIR, p), h.clone()));
+        let patches = self
+            .manifest
+            .patches
+            .iter()
+            .map(|(p, h)| (p.clone(), h.clone()));
+        for (name, expected) in files.chain(patches).collect::<Vec<_>>() {
+            match self.read(&name) {
+                Ok(data) => {
+                    let actual = sha256_hex(&data);
+                    if !actual.eq_ignore_ascii_case(&expected) {
+                        problems.push(format!(
+                            "Checksum mismatch for {}: expected {}, got {}",
+                            name, expected, actual
+                        ));
+                    } else if name.starts_with(PATCHES_DIR)
+                        && serde_json::from_slice::<PatchSet>(&data).is_err()
+                    {
+                        problems.push(format!("{} isn't a valid patch file", name));
+                    }
+                }
+                Err(e) => problems.push(e),
+            }
+            listed.push(name);
+        }
+
+        for name in self.names() {
+            if !name.ends_with('/') && !listed.contains(&name) {
+                problems.push(format!("{} isn't listed in the manifest", name));
+            }
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(problems)
+        }
+    }
+}
+
+// Builds a package, filling in the checksums of the manifest
+//
+// let mut builder = PackageBuilder::new(Manifest::new("my_mod", version));
+// builder.add_file("Root.wad/GameData/Foo.xml", data);
+// builder.write("my_mod.midas")?;
+pub struct PackageBuilder {
+    manifest: Manifest,
+    files: BTreeMap<String, Vec<u8>>,
+    patches: BTreeMap<String, Vec<u8>>,
+    key: Option<PublisherKey>,
+}
+
+impl PackageBuilder {
+    // Files and patches listed in `manifest` are dropped, they come from what
+    // gets added
+    pub fn new(mut manifest: Manifest) -> PackageBuilder {
+        manifest.files.clear();
+        manifest.patches.clear();
+        PackageBuilder {
+            manifest,
+            files: BTreeMap::new(),
+            patches: BTreeMap::new(),
+            key: None,
+        }
+    }
+
+    // A mod source directory: `manifest.json`, the replaced files under
+    // `files/<wad>/<entry>` and patch files under `patches/`
+    pub fn from_dir<P: AsRef<Path>>(dir: P) -> Result<PackageBuilder, String> {
+        let dir = dir.as_ref();
+        let mut builder = PackageBuilder::new(Manifest::load(dir.join(MANIFEST))?);
+
+        for (sub, is_patch) in [(FILES_DIR, false), (PATCHES_DIR, true)] {
+            let sub_dir = dir.join(sub);
+            if !sub_dir.is_dir() {
+                continue;
+            }
+            let files = find_files(&sub_dir)?;
+            for (name, path) in files {
+                let data =
+                    fs::read(&path).or(Err(format!("Couldn't read '{}'", path.display())))?;
+                if is_patch {
+                    builder.add_patch(&name, data);
+                } else {
+                    builder.add_file(&name, data);
+                }
+            }
+        }
+        Ok(builder)
+    }
+
+    pub fn add_file(&mut self, path: &str, data: Vec<u8>) -> &mut Self {
+        self.files.insert(path.replace('\\', "/"), data);
+        self
+    }
+
+    // `name` is relative to `patches/`, patches apply sorted by name
+    pub fn add_patch(&mut self, name: &str, data: Vec<u8>) -> &mut Self {
+        self.patches
+            .insert(format!("{}{}", PATCHES_DIR, name.replace('\\', "/")), data);
+        self
+    }
+
+    // Signs the manifest with the publisher's key when writing
+    pub fn sign(&mut self, key: PublisherKey) -> &mut Self {
+        self.key = Some(key);
+        self
+    }
+
+    // Writes the package and returns its manifest, checksums included
+    pub fn write<P: AsRef<Path>>(mut self, out: P) -> Result<Manifest, String> {
+        let out = out.as_ref();
+        for (path, data) in &self.files {
+            self.manifest.files.insert(path.clone(), sha256_hex(data));
+        }
+        for (name, data) in &self.patches {
+            serde_json::from_slice::<PatchSet>(data)
+                .map_err(|e| format!("Invalid patch file {}: {}", name, e))?;
+            self.manifest.patches.insert(name.clone(), sha256_hex(data));
+        }
+        let problems = self.manifest.problems();
+        if !problems.is_empty() {
+            return Err(problems.join("\n"));
+        }
+
+        let write_err =
+            |e: zip::result::ZipError| format!("Failed to write to '{}': {}", out.display(), e);
+        let file =
+            fs::File::create(out).or(Err(format!("Failed to create file '{}'", out.display())))?;
+        let mut zip = zip::ZipWriter::new(file);
+        let manifest = self.manifest.to_json().into_bytes();
+        let signature = self
+            .key
+            .as_ref()
+            .map(|k| serde_json::to_vec_pretty(&k.sign(&manifest)).unwrap());
+        let signature = signature.as_ref().map(|s| (String::from(SIGNATURE), s));
+        let files = self
+            .files
+            .iter()
+            .map(|(p, d)| (format!("{}{}", FILES_DIR, p), d));
+        let patches = self.patches.iter().map(|(p, d)| (p.clone(), d));
+        for (name, data) in [(String::from(MANIFEST), &manifest)]
+            .into_iter()
+            .chain(signature)
+            .chain(files)
+            .chain(patches)
+        {
+            let options = zip::write::FileOptions::default()
+                .compression_method(zip::CompressionMethod::Deflated)
+                .large_file(data.len() as u64 >= u32::MAX as u64);
+            zip.start_file(name.as_str(), options).map_err(write_err)?;
+            zip.write_all(data).map_err(|e| write_err(e.into()))?;
+        }
+        zip.finish().map_err(write_err)?;
+        Ok(self.manifest)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PATCH: &[u8] = br#"{"patches": [{"entry": "Root.wad/GameData/Foo.xml",
+        "select": "/Foo", "op": "remove"}]}"#;
+
+    fn manifest(id: &str) -> Manifest {
+        let mut manifest = Manifest::new(id, Version::new(1, 2, 0));
+        manifest.authors.push(String::from("someone"));
+        manifest
+            .dependencies
+            .insert(String::from("lib"), ">=1.0, <2".parse().unwrap());
+        manifest
+    }
+
+    fn build(dir: &Path, id: &str) -> PathBuf {
+        let path = dir.join(format!("{}.{}", id, EXTENSION));
+        let mut builder = PackageBuilder::new(manifest(id));
+        builder
+            .add_file("Root.wad\\GameData/Foo.xml", b"<Foo/>".to_vec())
+            .add_patch("fix.json", PATCH.to_vec());
+        builder.write(&path).unwrap();
+        path
+    }
+
+    // A zip with exactly `files` in it
+    fn zip(path: &Path, files: &[(&str, &[u8])]) {
+        let mut zip = zip::ZipWriter::new(fs::File::create(path).unwrap());
+        for (name, data) in files {
+            zip.start_file(*name, zip::write::FileOptions::default())
+                .unwrap();
+            zip.write_all(data).unwrap();
+        }
+        zip.finish().unwrap();
+    }
+
+    // Sets the uncompressed size of `name` in the zip's local and central
+    // headers
+    fn set_size(path: &Path, name: &str, size: u32) {
+        let mut data = fs::read(path).unwrap();
+        for i in 0..data.len().saturating_sub(46) {
+            let (name_at, size_at) = match &data[i..i + 4] {
+                b"PK\x03\x04" => (30, 22),
+                b"PK\x01\x02" => (46, 24),
+                _ => continue,
+            };
+            let len_at = if name_at == 30 { 26 } else { 28 };
+            let len = u16::from_le_bytes([data[i + len_at], data[i + len_at + 1]]) as usize;
+            if data.get(i + name_at..i + name_at + len) == Some(name.as_bytes()) {
+                data[i + size_at..i + size_at + 4].copy_from_slice(&size.to_le_bytes());
+            }
+        }
+        fs::write(path, data).unwrap();
+    }
+
+    #[test]
+    fn builds_and_reads_packages() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut package = Package::open(build(dir.path(), "my_mod")).unwrap();
+        package.validate().unwrap();
+
+        let manifest = package.manifest().clone();
+        assert_eq!(manifest.id, "my_mod");
+        assert_eq!(manifest.version, Version::new(1, 2, 0));
+        assert_eq!(
+            manifest.files.get("Root.wad/GameData/Foo.xml").unwrap(),
+            &sha256_hex(b"<Foo/>")
+        );
+        assert!(manifest.patches.contains_key("patches/fix.json"));
+        assert!(manifest.dependencies["lib"].matches(&Version::new(1, 9, 0)));
+
+        assert_eq!(
+            package.files().unwrap(),
+            [(
+                String::from("Root.wad/GameData/Foo.xml"),
+                b"<Foo/>".to_vec()
+            )]
+        );
+        let sets = package.patch_sets().unwrap();
+        assert_eq!(sets[0].name, "my_mod");
+        assert_eq!(sets[0].patches.len(), 1);
+        assert!(package.signature().unwrap().is_none());
+        assert!(package.read("files/Missing.xml").is_err());
+    }
+
+    #[test]
+    fn builds_from_a_directory() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("src");
+        fs::create_dir_all(src.join("files/Root.wad/GameData")).unwrap();
+        fs::create_dir_all(src.join("patches")).unwrap();
+        fs::write(src.join(MANIFEST), manifest("dir_mod").to_json()).unwrap();
+        fs::write(src.join("files/Root.wad/GameData/Foo.xml"), b"<Foo/>").unwrap();
+        fs::write(src.join("patches/fix.json"), PATCH).unwrap();
+
+        let out = dir.path().join("dir_mod.midas");
+        let manifest = PackageBuilder::from_dir(&src).unwrap().write(&out).unwrap();
+        assert_eq!(manifest.files.len(), 1);
+        assert_eq!(manifest.patches.len(), 1);
+        Package::open(&out).unwrap().validate().unwrap();
+    }
+
+    #[test]
+    fn manifest_problems() {
+        assert!(manifest("ok-mod_1.0").problems().is_empty());
+
+        let mut bad = manifest("bad id");
+        bad.files
+            .insert(String::from("Root.wad/../../evil.dll"), String::new());
+        bad.files
+            .insert(String::from("NotAWad/Foo.xml"), String::new());
+        bad.patches.insert(String::from("fix.json"), String::new());
+        bad.load_after.push(String::from("bad id"));
+        bad.conflicts.insert(String::from("a/b"), VersionReq::any());
+        let problems = bad.problems();
+        assert_eq!(problems.len(), 6, "{:?}", problems);
+        assert!(problems[0].starts_with("Invalid mod id"));
+
+        let problems = manifest(BASE_LAYER).problems();
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].contains("reserved"), "{}", problems[0]);
+
+        let mut itself = manifest("loop");
+        itself.load_before.push(String::from("loop"));
+        assert_eq!(
+            itself.problems(),
+            ["A mod can't name itself as a load_before"]
+        );
+        assert!(PackageBuilder::new(manifest("bad id"))
+            .write(tempfile::tempdir().unwrap().path().join("x.midas"))
+            .is_err());
+    }
+
+    #[test]
+    fn validate_checks_contents() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut listed = manifest("my_mod");
+        listed.files.insert(
+            String::from("Root.wad/GameData/Foo.xml"),
+            sha256_hex(b"<Foo/>"),
+        );
+        listed
+            .files
+            .insert(String::from("Root.wad/GameData/Gone.xml"), String::new());
+        let path = dir.path().join("my_mod.midas");
+        zip(
+            &path,
+            &[
+                (MANIFEST, listed.to_json().as_bytes()),
+                ("files/Root.wad/GameData/Foo.xml", b"<Bar/>"),
+                ("files/Root.wad/Bin/evil.dll", b"MZ"),
+            ],
+        );
+
+        let problems = Package::open(&path).unwrap().validate().unwrap_err();
+        assert_eq!(problems.len(), 3, "{:?}", problems);
+        assert!(problems[0].starts_with("Checksum mismatch"));
+        assert!(problems[1].contains("doesn't exist"));
+        assert!(problems[2].contains("evil.dll isn't listed"));
+
+        // a file inflating to more than the zip says isn't read past that
+        let big = vec![b'a'; 4096];
+        zip(
+            &path,
+            &[
+                (MANIFEST, listed.to_json().as_bytes()),
+                ("files/Root.wad/GameData/Foo.xml", &big),
+            ],
+        );
+        set_size(&path, "files/Root.wad/GameData/Foo.xml", 16);
+        let mut package = Package::open(&path).unwrap();
+        let err = package.read_file("Root.wad/GameData/Foo.xml").unwrap_err();
+        assert!(err.contains("bigger than its size of 16"), "{}", err);
+
+        fs::write(&path, b"not a zip").unwrap();
+        assert!(Package::open(&path).is_err());
+        zip(&path, &[("readme.txt", b"hi")]);
+        assert!(Package::open(&path).is_err());
+    }
+}
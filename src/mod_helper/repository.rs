@@ -0,0 +1,464 @@
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use super::manager::{InstallReport, ModManager};
+use super::package::{is_valid_id, sha256_hex, Package, EXTENSION};
+use super::version::{Version, VersionReq};
+
+// A mod repository is anything serving an index.json next to the packages:
+// an http(s) url, a file:// url or a plain directory.
+//
+// { "mods": [
+//   { "id": "my_mod", "version": "1.2.0", "name": "My mod",
+//     "url": "my_mod-1.2.0.midas", "sha256": "..." }
+// ] }
+//
+// Urls that aren't absolute are relative to the repository. The last index
+// fetched is cached, so searching and installing from the cache still works
+// offline.
+pub const INDEX_FILE: &str = "index.json";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IndexEntry {
+    pub id: String,
+    pub version: Version,
+    #[serde(default)]
+    pub name: String,
+    #[serde(default)]
+    pub description: String,
+    pub url: String,
+    pub sha256: String,
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct RepoIndex {
+    #[serde(default)]
+    pub mods: Vec<IndexEntry>,
+}
+
+impl RepoIndex {
+    pub fn parse(data: &[u8]) -> Result<RepoIndex, String> {
+        serde_json::from_slice(data).map_err(|e| format!("Invalid repository index: {}", e))
+    }
+
+    // Newest version of every mod with all of `words` in its id, name or
+    // description
+    pub fn search(&self, words: &[String]) -> Vec<&IndexEntry> {
+        let words: Vec<String> = words.iter().map(|w| w.to_lowercase()).collect();
+        let mut newest: BTreeMap<&str, &IndexEntry> = BTreeMap::new();
+        for entry in &self.mods {
+            let text = format!("{} {} {}", entry.id, entry.name, entry.description).to_lowercase();
+            if words.iter().all(|w| text.contains(w.as_str()))
+                && newest
+                    .get(entry.id.as_str())
+                    .is_none_or(|e| e.version < entry.version)
+            {
+                newest.insert(&entry.id, entry);
+            }
+        }
+        newest.into_values().collect()
+    }
+
+    // Newest version of `id` that matches `req`
+    pub fn best(&self, id: &str, req: &VersionReq) -> Option<&IndexEntry> {
+        self.mods
+            .iter()
+            .filter(|e| e.id == id && req.matches(&e.version))
+            .max_by_key(|e| e.version)
+    }
+}
+
+async fn http_get(client: &Client, url: &str) -> Result<Vec<u8>, String> {
+    let res = client
+        .get(url)
+        .send()
+        .await
+        .map_err(|e| format!("Failed to GET from '{}': {}", url, e))?;
+    if !res.status().is_success() {
+        return Err(format!("GET '{}' returned {}", url, res.status()));
+    }
+    res.bytes()
+        .await
+        .map(|b| b.to_vec())
+        .map_err(|e| format!("Error while downloading '{}': {}", url, e))
+}
+
+fn is_http(url: &str) -> bool {
+    url.starts_with("http://") || url.starts_with("https://")
+}
+
+pub struct Repository {
+    url: String,
+    cache_dir: PathBuf,
+    client: Client,
+}
+
+impl Repository {
+    pub fn new<P: AsRef<Path>>(url: &str, cache_dir: P) -> Repository {
+        Repository {
+            url: url.trim_end_matches('/').to_string(),
+            cache_dir: cache_dir.as_ref().to_path_buf(),
+            client: Client::new(),
+        }
+    }
+
+    // `url` as seen from the repository. A remote repository can only point
+    // at other http(s) urls, never at local files.
+    fn resolve(&self, url: &str) -> Result<String, String> {
+        if is_http(url) {
+            return Ok(url.to_string());
+        }
+        if url.contains("://") || Path::new(url).is_absolute() {
+            if is_http(&self.url) {
+                return Err(format!(
+                    "{} points at '{}', only http(s) and relative urls are allowed",
+                    self.url, url
+                ));
+            }
+            return Ok(url.to_string());
+        }
+        Ok(format!("{}/{}", self.url, url))
+    }
+
+    async fn fetch(&self, url: &str) -> Result<Vec<u8>, String> {
+        let url = self.resolve(url)?;
+        if is_http(&url) {
+            return http_get(&self.client, &url).await;
+        }
+        let path = url.strip_prefix("file://").unwrap_or(&url);
+        fs::read(path).or(Err(format!("Couldn't read '{}'", path)))
+    }
+
+    // Every repository gets its own cached index
+    fn cache_path(&self) -> PathBuf {
+        let name: String = sha256_hex(self.url.as_bytes()).chars().take(16).collect();
+        self.cache_dir.join(format!("{}.json", name))
+    }
+
+    fn package_path(&self, entry: &IndexEntry) -> PathBuf {
+        self.cache_dir
+            .join("packages")
+            .join(format!("{}-{}.{}", entry.id, entry.version, EXTENSION))
+    }
+
+    // Fetches the index and caches it, or falls back to the cached one.
+    // The flag says whether the index came from the cache.
+    pub async fn index(&self) -> Result<(RepoIndex, bool), String> {
+        let fetched = self.fetch(INDEX_FILE).await;
+        let fetch_err = match fetched.and_then(|d| RepoIndex::parse(&d)) {
+            Ok(index) => {
+                fs::create_dir_all(&self.cache_dir).or(Err(format!(
+                    "Failed to create '{}'",
+                    self.cache_dir.display()
+                )))?;
+                let path = self.cache_path();
+                fs::write(&path, serde_json::to_string_pretty(&index).unwrap())
+                    .or(Err(format!("Failed to write file '{}'", path.display())))?;
+                return Ok((index, false));
+            }
+            Err(e) => e,
+        };
+
+        match fs::read(self.cache_path()) {
+            Ok(cached) => Ok((RepoIndex::parse(&cached)?, true)),
+            Err(_) => Err(format!(
+                "{}, and there's no cached index for {}",
+                fetch_err, self.url
+            )),
+        }
+    }
+
+    // Downloads a package into the cache unless it's there already, checking
+    // it against the index's checksum either way
+    pub async fn download(&self, entry: &IndexEntry) -> Result<PathBuf, String> {
+        if !is_valid_id(&entry.id) {
+            return Err(format!("Invalid mod id '{}' in the index", entry.id));
+        }
+        let path = self.package_path(entry);
+        if let Ok(cached) = fs::read(&path) {
+            if sha256_hex(&cached).eq_ignore_ascii_case(&entry.sha256) {
+                return Ok(path);
+            }
+        }
+
+        let data = self.fetch(&entry.url).await?;
+        let actual = sha256_hex(&data);
+        if !actual.eq_ignore_ascii_case(&entry.sha256) {
+            return Err(format!(
+                "Checksum mismatch for {} {} from '{}': expected {}, got {}",
+                entry.id,
+                entry.version,
+                self.resolve(&entry.url)?,
+                entry.sha256,
+                actual
+            ));
+        }
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)
+                .or(Err(format!("Failed to create '{}'", parent.display())))?;
+        }
+        fs::write(&path, data).or(Err(format!("Failed to write file '{}'", path.display())))?;
+        Ok(path)
+    }
+
+    // Installs the newest matching version of every wanted mod that isn't
+    // installed at that version already, and whatever they depend on that
+    // isn't installed in a version they accept
+    pub async fn install(
+        &self,
+        index: &RepoIndex,
+        manager: &mut ModManager,
+        wanted: &[(String, VersionReq)],
+    ) -> Result<Vec<InstallReport>, String> {
+        let mut installed = Vec::new();
+        let mut queue: Vec<(String, VersionReq, bool)> = wanted
+            .iter()
+            .rev()
+            .map(|(id, req)| (id.clone(), req.clone(), true))
+            .collect();
+
+        while let Some((id, req, explicit)) = queue.pop() {
+            let current = manager.state().mods.get(&id).map(|m| m.version);
+            if !explicit && current.is_some_and(|v| req.matches(&v)) {
+                continue;
+            }
+            let entry = index
+                .best(&id, &req)
+                .ok_or(format!("{} {} isn't in {}", id, req, self.url))?;
+            if current.is_some_and(|v| v >= entry.version) {
+                continue;
+            }
+
+            let path = self.download(entry).await?;
+            let manifest = Package::open(&path)?.manifest().clone();
+            if manifest.id != entry.id || manifest.version != entry.version {
+                return Err(format!(
+                    "{} in {} is really {} {}",
+                    entry.url, self.url, manifest.id, manifest.version
+                ));
+            }
+            for (dep, req) in &manifest.dependencies {
+                queue.push((dep.clone(), req.clone(), false));
+            }
+            installed.push(manager.install(&path)?);
+        }
+        Ok(installed)
+    }
+
+    // Installs every installed mod's newest version in the index that the
+    // installed mods depending on it still accept
+    pub async fn upgrade(
+        &self,
+        index: &RepoIndex,
+        manager: &mut ModManager,
+    ) -> Result<Vec<InstallReport>, String> {
+        let manifests = manager.manifests()?;
+        let wanted: Vec<(String, VersionReq)> = manifests
+            .iter()
+            .map(|m| {
+                let req = manifests
+                    .iter()
+                    .filter_map(|d| d.dependencies.get(&m.id))
+                    .fold(VersionReq::any(), |req, r| req.and(r));
+                (m.id.clone(), req)
+            })
+            .filter(|(id, req)| index.best(id, req).is_some())
+            .collect();
+        self.install(index, manager, &wanted).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mod_helper::package::{Manifest, PackageBuilder};
+
+    // id, version and (dependency, constraint) pairs
+    type Mod<'a> = (&'a str, &'a str, &'a [(&'a str, &'a str)]);
+
+    // A repository directory with a package for every mod
+    fn repo(dir: &Path, mods: &[Mod]) -> RepoIndex {
+        let mut index = RepoIndex::default();
+        for (id, version, deps) in mods {
+            let mut manifest = Manifest::new(id, version.parse().unwrap());
+            manifest.description = format!("The {} mod", id);
+            for (dep, req) in *deps {
+                manifest
+                    .dependencies
+                    .insert(dep.to_string(), req.parse().unwrap());
+            }
+            let file = format!("{}-{}.{}", id, version, EXTENSION);
+            PackageBuilder::new(manifest.clone())
+                .write(dir.join(&file))
+                .unwrap();
+            index.mods.push(IndexEntry {
+                id: id.to_string(),
+                version: manifest.version,
+                name: id.to_string(),
+                description: manifest.description,
+                sha256: sha256_hex(&fs::read(dir.join(&file)).unwrap()),
+                url: file,
+            });
+        }
+        fs::write(dir.join(INDEX_FILE), serde_json::to_vec(&index).unwrap()).unwrap();
+        index
+    }
+
+    fn versions(manager: &ModManager) -> Vec<(String, String)> {
+        manager
+            .state()
+            .mods
+            .iter()
+            .map(|(id, m)| (id.clone(), m.version.to_string()))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn caches_the_index() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo_dir = dir.path().join("repo");
+        fs::create_dir_all(&repo_dir).unwrap();
+        repo(
+            &repo_dir,
+            &[("a", "1.0", &[]), ("a", "1.1", &[]), ("b", "2.0", &[])],
+        );
+
+        let url = format!("file://{}", repo_dir.display());
+        let repository = Repository::new(&url, dir.path().join("cache"));
+        let (index, cached) = repository.index().await.unwrap();
+        assert!(!cached);
+        let found: Vec<_> = index
+            .search(&[String::from("MOD")])
+            .iter()
+            .map(|e| format!("{} {}", e.id, e.version))
+            .collect();
+        assert_eq!(found, ["a 1.1.0", "b 2.0.0"]);
+        assert_eq!(index.search(&[String::from("b")]).len(), 1);
+
+        fs::remove_dir_all(&repo_dir).unwrap();
+        let (index, cached) = repository.index().await.unwrap();
+        assert!(cached);
+        assert_eq!(index.mods.len(), 3);
+        let other = Repository::new("/nowhere", dir.path().join("cache"));
+        assert!(other.index().await.is_err());
+    }
+
+    #[test]
+    fn remote_repositories_only_point_at_http() {
+        let remote = Repository::new("https://mods.example/repo/", "cache");
+        assert_eq!(
+            remote.resolve("a-1.0.0.midas").unwrap(),
+            "https://mods.example/repo/a-1.0.0.midas"
+        );
+        assert_eq!(
+            remote.resolve("http://cdn.example/a.midas").unwrap(),
+            "http://cdn.example/a.midas"
+        );
+        for url in ["file:///etc/passwd", "/etc/passwd", "ftp://mods.example/a"] {
+            let err = remote.resolve(url).unwrap_err();
+            assert!(err.contains("only http(s) and relative urls"), "{}", err);
+        }
+
+        let local = Repository::new("/srv/repo", "cache");
+        assert_eq!(local.resolve("/other/a.midas").unwrap(), "/other/a.midas");
+        assert_eq!(local.resolve("a.midas").unwrap(), "/srv/repo/a.midas");
+    }
+
+    #[tokio::test]
+    async fn installs_with_dependencies() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo_dir = dir.path().join("repo");
+        fs::create_dir_all(&repo_dir).unwrap();
+        let mut index = repo(
+            &repo_dir,
+            &[
+                ("app", "1.0", &[("lib", "^1")]),
+                ("lib", "1.0", &[]),
+                ("lib", "1.5", &[]),
+                ("lib", "2.0", &[]),
+            ],
+        );
+        let repository = Repository::new(&repo_dir.to_string_lossy(), dir.path().join("cache"));
+        let mut manager = ModManager::open(dir.path().join("game")).unwrap();
+
+        let wanted = [(String::from("app"), VersionReq::any())];
+        let installed = repository
+            .install(&index, &mut manager, &wanted)
+            .await
+            .unwrap();
+        assert_eq!(installed.len(), 2);
+        assert_eq!(
+            versions(&manager),
+            [
+                (String::from("app"), String::from("1.0.0")),
+                (String::from("lib"), String::from("1.5.0")),
+            ]
+        );
+
+        // a package that isn't what the index says it is
+        index.mods[3].sha256 = sha256_hex(b"something else");
+        let wanted = [(String::from("lib"), "^2".parse().unwrap())];
+        let err = repository
+            .install(&index, &mut manager, &wanted)
+            .await
+            .unwrap_err();
+        assert!(
+            err.starts_with("Checksum mismatch for lib 2.0.0"),
+            "{}",
+            err
+        );
+        let wanted = [(String::from("lib"), "^3".parse().unwrap())];
+        assert!(repository
+            .install(&index, &mut manager, &wanted)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn upgrades_within_what_dependents_accept() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo_dir = dir.path().join("repo");
+        fs::create_dir_all(&repo_dir).unwrap();
+        repo(
+            &repo_dir,
+            &[("app", "1.0", &[("lib", "~1.0")]), ("lib", "1.0.0", &[])],
+        );
+        let repository = Repository::new(&repo_dir.to_string_lossy(), dir.path().join("cache"));
+        let mut manager = ModManager::open(dir.path().join("game")).unwrap();
+        let (index, _) = repository.index().await.unwrap();
+        let wanted = [(String::from("app"), VersionReq::any())];
+        repository
+            .install(&index, &mut manager, &wanted)
+            .await
+            .unwrap();
+
+        repo(
+            &repo_dir,
+            &[
+                ("app", "1.0", &[("lib", "~1.0")]),
+                ("lib", "1.0.0", &[]),
+                ("lib", "1.0.3", &[]),
+                ("lib", "1.2.0", &[]),
+                ("other", "1.0", &[]),
+            ],
+        );
+        let (index, _) = repository.index().await.unwrap();
+        let upgraded = repository.upgrade(&index, &mut manager).await.unwrap();
+        assert_eq!(upgraded.len(), 1);
+        assert_eq!(
+            versions(&manager),
+            [
+                (String::from("app"), String::from("1.0.0")),
+                (String::from("lib"), String::from("1.0.3")),
+            ]
+        );
+        assert!(repository
+            .upgrade(&index, &mut manager)
+            .await
+            .unwrap()
+            .is_empty());
+    }
+}
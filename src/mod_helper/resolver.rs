@@ -0,0 +1,407 @@
+use dependency_graph::{DependencyGraph, Node, Step};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+
+use super::package::{Manifest, Package};
+use super::version::VersionReq;
+use super::vfs::Vfs;
+use super::xml_patch::{apply_patches, PatchReport};
+
+// Name of the layer with the patched xml entries, mod ids can't have spaces
+pub const PATCH_LAYER: &str = "xml patches";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EdgeKind {
+    DependsOn,
+    LoadsAfter,
+}
+
+impl fmt::Display for EdgeKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EdgeKind::DependsOn => write!(f, "depends on"),
+            EdgeKind::LoadsAfter => write!(f, "loads after"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ResolveError {
+    Duplicate(String),
+    Missing {
+        id: String,
+        dependency: String,
+        req: VersionReq,
+    },
+    WrongVersion {
+        id: String,
+        dependency: String,
+        req: VersionReq,
+        found: String,
+    },
+    Conflict {
+        id: String,
+        other: String,
+        req: VersionReq,
+    },
+    // every mod on the cycle and how it points to the next one
+    Cycle(Vec<(String, EdgeKind)>),
+}
+
+impl fmt::Display for ResolveError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ResolveError::Duplicate(id) => write!(f, "{} is installed more than once", id),
+            ResolveError::Missing {
+                id,
+                dependency,
+                req,
+            } => write!(
+                f,
+                "{} needs {} {}, which isn't installed",
+                id, dependency, req
+            ),
+            ResolveError::WrongVersion {
+                id,
+                dependency,
+                req,
+                found,
+            } => write!(
+                f,
+                "{} needs {} {}, but {} is installed",
+                id, dependency, req, found
+            ),
+            ResolveError::Conflict { id, other, req } => {
+                write!(f, "{} conflicts with {} {}", id, other, req)
+            }
+            ResolveError::Cycle(steps) => {
+                write!(f, "Load order cycle: ")?;
+                for (id, kind) in steps {
+                    write!(f, "{} {} ", id, kind)?;
+                }
+                write!(f, "{}", steps.first().map_or("", |(id, _)| id.as_str()))
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Edge {
+    id: String,
+    kind: EdgeKind,
+    req: VersionReq,
+}
+
+// A mod as the dependency graph sees it, its edges are its dependencies plus
+// the ordering hints of both sides
+struct ModNode<'a> {
+    manifest: &'a Manifest,
+    edges: Vec<Edge>,
+}
+
+impl Node for ModNode<'_> {
+    type DependencyType = Edge;
+
+    fn dependencies(&self) -> &[Edge] {
+        &self.edges
+    }
+
+    fn matches(&self, dependency: &Edge) -> bool {
+        self.manifest.id == dependency.id && dependency.req.matches(&self.manifest.version)
+    }
+}
+
+// Finds a cycle among `left`, the mods the graph couldn't order
+fn find_cycle(nodes: &[ModNode], left: &BTreeSet<String>) -> Vec<(String, EdgeKind)> {
+    let edges: BTreeMap<&str, Vec<&Edge>> = nodes
+        .iter()
+        .filter(|n| left.contains(&n.manifest.id))
+        .map(|n| {
+            let edges = n.edges.iter().filter(|e| left.contains(&e.id)).collect();
+            (n.manifest.id.as_str(), edges)
+        })
+        .collect();
+
+    // every mod left has an edge to another one left, so following the first
+    // edge from anywhere has to come back around
+    let mut path: Vec<(String, EdgeKind)> = Vec::new();
+    let mut current = match left.iter().next() {
+        Some(id) => id.as_str(),
+        None => return path,
+    };
+    loop {
+        if let Some(start) = path.iter().position(|(id, _)| id == current) {
+            return path.split_off(start);
+        }
+        let edge = match edges.get(current).and_then(|e| e.first()) {
+            Some(edge) => edge,
+            None => return path,
+        };
+        path.push((current.to_string(), edge.kind));
+        current = edge.id.as_str();
+    }
+}
+
+// Orders `mods` so every mod comes after what it depends on and the load
+// before/after hints hold. Mods that are free to go anywhere are ordered by
+// id, so the same set of mods always gives the same order. Returns the ids in
+// load order, or everything that stands in the way.
+pub fn resolve(mods: &[&Manifest]) -> Result<Vec<String>, Vec<ResolveError>> {
+    let mut errors = Vec::new();
+
+    let mut by_id: BTreeMap<&str, &Manifest> = BTreeMap::new();
+    for m in mods {
+        if by_id.insert(&m.id, m).is_some() {
+            errors.push(ResolveError::Duplicate(m.id.clone()));
+        }
+    }
+
+    for m in by_id.values() {
+        for (other, req) in &m.conflicts {
+            if by_id
+                .get(other.as_str())
+                .is_some_and(|o| req.matches(&o.version))
+            {
+                errors.push(ResolveError::Conflict {
+                    id: m.id.clone(),
+                    other: other.clone(),
+                    req: req.clone(),
+                });
+            }
+        }
+    }
+
+    // the graph takes the last ready node first, so with the mods in reverse
+    // id order the smallest id goes first
+    let nodes: Vec<ModNode> = by_id
+        .values()
+        .rev()
+        .map(|m| {
+            let mut edges: Vec<Edge> = m
+                .dependencies
+                .iter()
+                .map(|(id, req)| Edge {
+                    id: id.clone(),
+                    kind: EdgeKind::DependsOn,
+                    req: req.clone(),
+                })
+                .collect();
+            let after = m.load_after.iter().chain(
+                by_id
+                    .values()
+                    .filter(|o| o.load_before.contains(&m.id))
+                    .map(|o| &o.id),
+            );
+            for id in after {
+                // hints only order mods that are there
+                if by_id.contains_key(id.as_str()) && !edges.iter().any(|e| e.id == *id) {
+                    edges.push(Edge {
+                        id: id.clone(),
+                        kind: EdgeKind::LoadsAfter,
+                        req: VersionReq::any(),
+                    });
+                }
+            }
+            ModNode { manifest: m, edges }
+        })
+        .collect();
+
+    for node in nodes.iter().rev() {
+        for edge in node.edges.iter().filter(|e| e.kind == EdgeKind::DependsOn) {
+            let error = match by_id.get(edge.id.as_str()) {
+                None => ResolveError::Missing {
+                    id: node.manifest.id.clone(),
+                    dependency: edge.id.clone(),
+                    req: edge.req.clone(),
+                },
+                Some(dep) if !edge.req.matches(&dep.version) => ResolveError::WrongVersion {
+                    id: node.manifest.id.clone(),
+                    dependency: edge.id.clone(),
+                    req: edge.req.clone(),
+                    found: dep.version.to_string(),
+                },
+                Some(_) => continue,
+            };
+            errors.push(error);
+        }
+    }
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+
+    let mut order = Vec::new();
+    for step in DependencyGraph::from(nodes.as_slice()) {
+        if let Step::Resolved(node) = step {
+            order.push(node.manifest.id.clone());
+        }
+    }
+
+    if order.len() < nodes.len() {
+        let left: BTreeSet<String> = by_id
+            .keys()
+            .filter(|id| !order.iter().any(|o| o == *id))
+            .map(|id| id.to_string())
+            .collect();
+        return Err(vec![ResolveError::Cycle(find_cycle(&nodes, &left))]);
+    }
+    Ok(order)
+}
+
+// Stacks the packages over `vfs` in load order: each package's files in a
+// layer named after it, then one layer with the xml patches of all of them
+// applied, also in load order, on top of everything.
+pub fn stack_layers(vfs: &mut Vfs, packages: &mut [Package]) -> Result<PatchReport, String> {
+    let manifests: Vec<&Manifest> = packages.iter().map(|p| p.manifest()).collect();
+    let order = resolve(&manifests).map_err(|errors| {
+        errors
+            .iter()
+            .map(|e| e.to_string())
+            .collect::<Vec<_>>()
+            .join("\n")
+    })?;
+
+    let mut patch_sets = Vec::new();
+    for id in &order {
+        let package = packages
+            .iter_mut()
+            .find(|p| p.manifest().id == *id)
+            .unwrap();
+        vfs.add_data(id, package.files()?)?;
+        patch_sets.extend(package.patch_sets()?);
+    }
+
+    let (patched, report) = apply_patches(vfs, &patch_sets);
+    vfs.add_data(PATCH_LAYER, patched)?;
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mod_helper::package::PackageBuilder;
+    use crate::mod_helper::version::Version;
+
+    fn manifest(id: &str, version: &str) -> Manifest {
+        Manifest::new(id, version.parse().unwrap())
+    }
+
+    fn depends(mut m: Manifest, id: &str, req: &str) -> Manifest {
+        m.dependencies.insert(id.to_string(), req.parse().unwrap());
+        m
+    }
+
+    fn order(mods: &[Manifest]) -> Result<Vec<String>, Vec<ResolveError>> {
+        resolve(&mods.iter().collect::<Vec<_>>())
+    }
+
+    #[test]
+    fn orders_dependencies_first() {
+        let mods = [
+            depends(manifest("zeta", "1.0"), "lib", "^1"),
+            manifest("beta", "1.0"),
+            manifest("lib", "1.4"),
+            depends(manifest("alpha", "1.0"), "zeta", "*"),
+        ];
+        assert_eq!(order(&mods).unwrap(), ["beta", "lib", "zeta", "alpha"]);
+
+        // the input order doesn't matter
+        let mut reversed = mods.clone();
+        reversed.reverse();
+        assert_eq!(order(&reversed).unwrap(), order(&mods).unwrap());
+    }
+
+    #[test]
+    fn follows_load_hints() {
+        let mut a = manifest("a", "1.0");
+        a.load_after.push(String::from("c"));
+        let b = manifest("b", "1.0");
+        let mut c = manifest("c", "1.0");
+        c.load_before.push(String::from("b"));
+        // hints about mods that aren't there are ignored
+        c.load_after.push(String::from("missing"));
+        assert_eq!(order(&[a, b, c]).unwrap(), ["c", "a", "b"]);
+    }
+
+    #[test]
+    fn explains_what_is_wrong() {
+        let mut bad = depends(manifest("a", "1.0"), "missing", ">=2");
+        bad = depends(bad, "lib", "^2");
+        let mut c = manifest("c", "1.0");
+        c.conflicts
+            .insert(String::from("lib"), "<2".parse().unwrap());
+        let mods = [
+            bad,
+            manifest("lib", "1.5"),
+            c,
+            manifest("d", "1.0"),
+            manifest("d", "1.1"),
+        ];
+        let errors = order(&mods).unwrap_err();
+        let messages: Vec<String> = errors.iter().map(|e| e.to_string()).collect();
+        assert_eq!(
+            messages,
+            [
+                "d is installed more than once",
+                "c conflicts with lib <2",
+                "a needs lib ^2, but 1.5.0 is installed",
+                "a needs missing >=2, which isn't installed",
+            ]
+        );
+    }
+
+    #[test]
+    fn reports_cycles() {
+        let a = depends(manifest("a", "1.0"), "b", "*");
+        let mut b = manifest("b", "1.0");
+        b.load_after.push(String::from("a"));
+        let errors = order(&[a, b, manifest("c", "1.0")]).unwrap_err();
+        assert_eq!(
+            errors,
+            [ResolveError::Cycle(vec![
+                (String::from("a"), EdgeKind::DependsOn),
+                (String::from("b"), EdgeKind::LoadsAfter),
+            ])]
+        );
+        assert_eq!(
+            errors[0].to_string(),
+            "Load order cycle: a depends on b loads after a"
+        );
+    }
+
+    #[test]
+    fn stacks_packages_in_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let entry = "Root.wad/GameData/Foo.xml";
+        let mut packages = Vec::new();
+        for (id, text, patch) in [
+            ("late", "<Foo>late</Foo>", "<Late/>"),
+            ("early", "<Foo>early</Foo>", "<Early/>"),
+        ] {
+            let mut m = Manifest::new(id, Version::new(1, 0, 0));
+            if id == "late" {
+                m.load_after.push(String::from("early"));
+            }
+            let patch = format!(
+                r#"{{"patches": [{{"entry": "{}", "select": "/Foo", "op": "add", "xml": "{}"}}]}}"#,
+                entry, patch
+            );
+            let path = dir.path().join(format!("{}.midas", id));
+            let mut builder = PackageBuilder::new(m);
+            builder
+                .add_file(entry, text.as_bytes().to_vec())
+                .add_patch("foo.json", patch.into_bytes());
+            builder.write(&path).unwrap();
+            packages.push(Package::open(&path).unwrap());
+        }
+
+        let mut vfs = Vfs::new();
+        let report = stack_layers(&mut vfs, &mut packages).unwrap();
+        assert_eq!(report.applied, 2);
+        assert_eq!(vfs.providers(entry), ["early", "late", PATCH_LAYER]);
+        // the patches go on top of the last mod's file, in load order
+        assert_eq!(
+            vfs.read(entry).unwrap(),
+            b"<Foo>late<Early/><Late/></Foo>\n"
+        );
+    }
+}
@@ -0,0 +1,315 @@
+use base64::{engine::general_purpose, Engine as _};
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use rand_core::OsRng;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+// Packages are signed by signing their manifest.json with a publisher's
+// ed25519 key. The manifest has the sha256 of every file and patch in the
+// package, so once the checksums check out the signature covers all of it.
+// The signature goes next to the manifest:
+//
+// manifest.sig: { "publisher": "midas", "key": "<base64 public key>",
+//                 "signature": "<base64 signature of manifest.json>" }
+pub const SIGNATURE: &str = "manifest.sig";
+
+// What to do with packages that aren't signed at all
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Policy {
+    Allow,
+    #[default]
+    Warn,
+    Deny,
+}
+
+impl FromStr for Policy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Policy, String> {
+        match s {
+            "allow" => Ok(Policy::Allow),
+            "warn" => Ok(Policy::Warn),
+            "deny" => Ok(Policy::Deny),
+            _ => Err(format!(
+                "Invalid policy '{}', expected allow, warn or deny",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Policy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Policy::Allow => write!(f, "allow"),
+            Policy::Warn => write!(f, "warn"),
+            Policy::Deny => write!(f, "deny"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ManifestSignature {
+    pub publisher: String,
+    pub key: String,
+    pub signature: String,
+}
+
+fn decode_key(key: &str) -> Result<VerifyingKey, String> {
+    let bytes: [u8; 32] = general_purpose::STANDARD
+        .decode(key.trim())
+        .ok()
+        .and_then(|b| b.try_into().ok())
+        .ok_or(format!("Invalid public key '{}'", key))?;
+    VerifyingKey::from_bytes(&bytes).or(Err(format!("Invalid public key '{}'", key)))
+}
+
+// A publisher's secret key, kept in a json file of its own
+#[derive(Serialize, Deserialize)]
+pub struct PublisherKey {
+    pub publisher: String,
+    secret_key: String,
+}
+
+impl PublisherKey {
+    pub fn generate(publisher: &str) -> PublisherKey {
+        let key = SigningKey::generate(&mut OsRng);
+        PublisherKey {
+            publisher: publisher.to_string(),
+            secret_key: general_purpose::STANDARD.encode(key.to_bytes()),
+        }
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<PublisherKey, String> {
+        let path = path.as_ref();
+        let contents =
+            fs::read(path).or(Err(format!("Couldn't read key file '{}'", path.display())))?;
+        let key: PublisherKey = serde_json::from_slice(&contents)
+            .map_err(|e| format!("Invalid key file '{}': {}", path.display(), e))?;
+        key.signing_key()
+            .map_err(|e| format!("Invalid key file '{}': {}", path.display(), e))?;
+        Ok(key)
+    }
+
+    // Only the owner can read the file, it holds the secret key
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), String> {
+        let path = path.as_ref();
+        let write_err = |_| format!("Failed to write file '{}'", path.display());
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+            options.mode(0o600);
+            // mode only applies to new files
+            if path.exists() {
+                fs::set_permissions(path, fs::Permissions::from_mode(0o600)).map_err(write_err)?;
+            }
+        }
+        let mut file = options.open(path).map_err(write_err)?;
+        file.write_all(serde_json::to_string_pretty(self).unwrap().as_bytes())
+            .map_err(write_err)
+    }
+
+    fn signing_key(&self) -> Result<SigningKey, String> {
+        let bytes: [u8; 32] = general_purpose::STANDARD
+            .decode(&self.secret_key)
+            .ok()
+            .and_then(|b| b.try_into().ok())
+            .ok_or("the secret key isn't 32 bytes of base64")?;
+        Ok(SigningKey::from_bytes(&bytes))
+    }
+
+    // Base64 of the public half, what goes in trust stores
+    pub fn public_key(&self) -> String {
+        let key = self.signing_key().unwrap();
+        general_purpose::STANDARD.encode(key.verifying_key().to_bytes())
+    }
+
+    pub fn sign(&self, manifest: &[u8]) -> ManifestSignature {
+        let key = self.signing_key().unwrap();
+        ManifestSignature {
+            publisher: self.publisher.clone(),
+            key: self.public_key(),
+            signature: general_purpose::STANDARD.encode(key.sign(manifest).to_bytes()),
+        }
+    }
+}
+
+// Publishers whose packages are trusted, by name
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct TrustStore {
+    #[serde(skip)]
+    path: PathBuf,
+    #[serde(default)]
+    pub keys: BTreeMap<String, String>,
+}
+
+impl TrustStore {
+    // A missing file is an empty store
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<TrustStore, String> {
+        let path = path.as_ref();
+        let mut store = match fs::read(path) {
+            Ok(contents) => serde_json::from_slice(&contents)
+                .map_err(|e| format!("Invalid trust store '{}': {}", path.display(), e))?,
+            Err(_) => TrustStore::default(),
+        };
+        store.path = path.to_path_buf();
+        Ok(store)
+    }
+
+    pub fn save(&self) -> Result<(), String> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)
+                .or(Err(format!("Failed to create '{}'", parent.display())))?;
+        }
+        fs::write(&self.path, serde_json::to_string_pretty(self).unwrap()).or(Err(format!(
+            "Failed to write file '{}'",
+            self.path.display()
+        )))
+    }
+
+    pub fn trust(&mut self, publisher: &str, key: &str) -> Result<(), String> {
+        decode_key(key)?;
+        self.keys
+            .insert(publisher.to_string(), key.trim().to_string());
+        Ok(())
+    }
+
+    pub fn untrust(&mut self, publisher: &str) -> Result<(), String> {
+        self.keys
+            .remove(publisher)
+            .map(|_| ())
+            .ok_or(format!("{} isn't a trusted publisher", publisher))
+    }
+
+    // Checks `signature` against the manifest it came with. Returns the
+    // trusted publisher that signed it.
+    pub fn verify(&self, manifest: &[u8], signature: &ManifestSignature) -> Result<String, String> {
+        let publisher = self
+            .keys
+            .iter()
+            .find(|(_, key)| **key == signature.key.trim())
+            .map(|(name, _)| name.clone())
+            .ok_or(match self.keys.get(&signature.publisher) {
+                Some(_) => format!(
+                    "Signed with a key that isn't {}'s trusted key (key {})",
+                    signature.publisher, signature.key
+                ),
+                None => format!(
+                    "Signed by {}, who isn't a trusted publisher (key {})",
+                    signature.publisher, signature.key
+                ),
+            })?;
+
+        let key = decode_key(&signature.key)?;
+        let bytes: [u8; 64] = general_purpose::STANDARD
+            .decode(signature.signature.trim())
+            .ok()
+            .and_then(|b| b.try_into().ok())
+            .ok_or("The signature isn't 64 bytes of base64")?;
+        key.verify(manifest, &Signature::from_bytes(&bytes))
+            .or(Err(format!(
+                "The signature by {} doesn't match the manifest, the package was changed after it was signed",
+                publisher
+            )))?;
+        Ok(publisher)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MANIFEST: &[u8] = br#"{"id":"my_mod","version":"1.0.0"}"#;
+
+    fn store(key: &PublisherKey) -> TrustStore {
+        let mut store = TrustStore::default();
+        store.trust(&key.publisher, &key.public_key()).unwrap();
+        store
+    }
+
+    #[test]
+    fn verifies_signatures() {
+        let key = PublisherKey::generate("midas");
+        let store = store(&key);
+        let signature = key.sign(MANIFEST);
+        assert_eq!(store.verify(MANIFEST, &signature).unwrap(), "midas");
+
+        // changed after signing
+        let err = store
+            .verify(br#"{"id":"my_mod","version":"1.0.1"}"#, &signature)
+            .unwrap_err();
+        assert!(err.contains("changed after it was signed"), "{}", err);
+
+        // someone else claiming to be midas
+        let other = PublisherKey::generate("midas");
+        let err = store.verify(MANIFEST, &other.sign(MANIFEST)).unwrap_err();
+        assert!(err.contains("isn't midas's trusted key"), "{}", err);
+
+        let stranger = PublisherKey::generate("stranger");
+        let err = store
+            .verify(MANIFEST, &stranger.sign(MANIFEST))
+            .unwrap_err();
+        assert!(err.contains("isn't a trusted publisher"), "{}", err);
+
+        // the trusted key with a signature that isn't from it
+        let mut forged = other.sign(MANIFEST);
+        forged.key = key.public_key();
+        assert!(store.verify(MANIFEST, &forged).is_err());
+    }
+
+    #[test]
+    fn saves_keys_for_the_owner_only() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("midas.key");
+        fs::write(&path, "old").unwrap();
+        let key = PublisherKey::generate("midas");
+        key.save(&path).unwrap();
+
+        let loaded = PublisherKey::load(&path).unwrap();
+        assert_eq!(loaded.publisher, "midas");
+        assert_eq!(loaded.public_key(), key.public_key());
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        fs::write(&path, r#"{"publisher":"midas","secret_key":"c2hvcnQ="}"#).unwrap();
+        assert!(PublisherKey::load(&path).is_err());
+    }
+
+    #[test]
+    fn trust_store_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("Mods").join("trusted_keys.json");
+        let key = PublisherKey::generate("midas");
+        let mut store = TrustStore::load(&path).unwrap();
+        assert!(store.keys.is_empty());
+        assert!(store.trust("midas", "not a key").is_err());
+        store.trust("midas", &key.public_key()).unwrap();
+        store.save().unwrap();
+
+        let mut store = TrustStore::load(&path).unwrap();
+        assert_eq!(store.keys["midas"], key.public_key());
+        store.untrust("midas").unwrap();
+        assert!(store.untrust("midas").is_err());
+    }
+
+    #[test]
+    fn parses_policies() {
+        for policy in [Policy::Allow, Policy::Warn, Policy::Deny] {
+            assert_eq!(policy.to_string().parse::<Policy>().unwrap(), policy);
+        }
+        assert!("sometimes".parse::<Policy>().is_err());
+        assert_eq!(Policy::default(), Policy::Warn);
+    }
+}
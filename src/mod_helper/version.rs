@@ -0,0 +1,260 @@
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+use std::str::FromStr;
+
+// `major.minor.patch`, missing parts count as 0
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Version {
+    pub major: u32,
+    pub minor: u32,
+    pub patch: u32,
+}
+
+impl Version {
+    pub fn new(major: u32, minor: u32, patch: u32) -> Version {
+        Version {
+            major,
+            minor,
+            patch,
+        }
+    }
+}
+
+// Number of parts that were given, `^1.2` and `^1.2.0` don't mean the same
+fn parse_parts(s: &str) -> Result<(Version, usize), String> {
+    let err = || format!("Invalid version '{}'", s);
+    let parts: Vec<&str> = s.trim().split('.').collect();
+    if parts.is_empty() || parts.len() > 3 {
+        return Err(err());
+    }
+    let mut nums = [0; 3];
+    for (i, part) in parts.iter().enumerate() {
+        nums[i] = part.parse::<u32>().or(Err(err()))?;
+    }
+    Ok((Version::new(nums[0], nums[1], nums[2]), parts.len()))
+}
+
+impl FromStr for Version {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Version, String> {
+        parse_parts(s).map(|(v, _)| v)
+    }
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)
+    }
+}
+
+impl Serialize for Version {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+impl<'de> Deserialize<'de> for Version {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Version, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Op {
+    Exact,
+    Greater,
+    GreaterEq,
+    Less,
+    LessEq,
+    // same left-most non-zero part, like cargo
+    Caret,
+    // same major and minor (or just major if that's all there is)
+    Tilde,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Comparator {
+    op: Op,
+    version: Version,
+    parts: usize,
+}
+
+impl Comparator {
+    // None when the bound would be past the largest version there is
+    fn upper_bound(&self) -> Option<Version> {
+        let v = self.version;
+        let next = |n: u32| n.checked_add(1);
+        Some(match (self.op, self.parts) {
+            (Op::Tilde, 1) => Version::new(next(v.major)?, 0, 0),
+            (Op::Tilde, _) => Version::new(v.major, next(v.minor)?, 0),
+            (_, 1) => Version::new(next(v.major)?, 0, 0),
+            _ if v.major > 0 => Version::new(next(v.major)?, 0, 0),
+            (_, 2) => Version::new(0, next(v.minor)?, 0),
+            _ if v.minor > 0 => Version::new(0, next(v.minor)?, 0),
+            _ => Version::new(0, 0, next(v.patch)?),
+        })
+    }
+
+    fn matches(&self, v: &Version) -> bool {
+        match self.op {
+            Op::Exact => *v == self.version,
+            Op::Greater => *v > self.version,
+            Op::GreaterEq => *v >= self.version,
+            Op::Less => *v < self.version,
+            Op::LessEq => *v <= self.version,
+            Op::Caret | Op::Tilde => {
+                *v >= self.version && self.upper_bound().is_none_or(|bound| *v < bound)
+            }
+        }
+    }
+}
+
+// Version constraint like `>=1.2, <2`. A bare version means `^version`, `*`
+// (or nothing) matches everything.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct VersionReq {
+    comparators: Vec<Comparator>,
+    text: String,
+}
+
+impl VersionReq {
+    pub fn any() -> VersionReq {
+        VersionReq {
+            comparators: Vec::new(),
+            text: String::from("*"),
+        }
+    }
+
+    pub fn matches(&self, v: &Version) -> bool {
+        self.comparators.iter().all(|c| c.matches(v))
+    }
+
+    // Matches the versions both `self` and `other` match
+    pub fn and(&self, other: &VersionReq) -> VersionReq {
+        if self.comparators.is_empty() {
+            return other.clone();
+        }
+        if other.comparators.is_empty() {
+            return self.clone();
+        }
+        VersionReq {
+            comparators: [self.comparators.clone(), other.comparators.clone()].concat(),
+            text: format!("{}, {}", self.text, other.text),
+        }
+    }
+}
+
+impl FromStr for VersionReq {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<VersionReq, String> {
+        let s = s.trim();
+        if s.is_empty() || s == "*" {
+            return Ok(VersionReq::any());
+        }
+
+        let mut comparators = Vec::new();
+        for part in s.split(',') {
+            let part = part.trim();
+            let (op, rest) = [
+                (">=", Op::GreaterEq),
+                ("<=", Op::LessEq),
+                (">", Op::Greater),
+                ("<", Op::Less),
+                ("=", Op::Exact),
+                ("^", Op::Caret),
+                ("~", Op::Tilde),
+            ]
+            .iter()
+            .find_map(|(prefix, op)| part.strip_prefix(prefix).map(|r| (*op, r)))
+            .unwrap_or((Op::Caret, part));
+            let (version, parts) =
+                parse_parts(rest).map_err(|_| format!("Invalid version constraint '{}'", s))?;
+            comparators.push(Comparator { op, version, parts });
+        }
+        Ok(VersionReq {
+            comparators,
+            text: s.to_string(),
+        })
+    }
+}
+
+impl fmt::Display for VersionReq {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.text)
+    }
+}
+
+impl Serialize for VersionReq {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.text)
+    }
+}
+
+impl<'de> Deserialize<'de> for VersionReq {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<VersionReq, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn v(s: &str) -> Version {
+        s.parse().unwrap()
+    }
+
+    fn req(s: &str) -> VersionReq {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn parses_versions() {
+        assert_eq!(v("1.2.3"), Version::new(1, 2, 3));
+        assert_eq!(v("1.2"), Version::new(1, 2, 0));
+        assert_eq!(v(" 4 "), Version::new(4, 0, 0));
+        assert_eq!(Version::new(1, 2, 3).to_string(), "1.2.3");
+        for bad in ["", "1.2.3.4", "1.x", "-1", "v1"] {
+            assert!(bad.parse::<Version>().is_err(), "{}", bad);
+        }
+        assert!(v("1.10.0") > v("1.9.9"));
+        let json = serde_json::to_string(&v("2.0.1")).unwrap();
+        assert_eq!(json, "\"2.0.1\"");
+        assert_eq!(serde_json::from_str::<Version>(&json).unwrap(), v("2.0.1"));
+    }
+
+    #[test]
+    fn matches_constraints() {
+        let cases = [
+            ("*", "0.0.1", true),
+            ("", "9.9.9", true),
+            ("1.2", "1.9.0", true),
+            ("1.2", "2.0.0", false),
+            ("^0.2", "0.2.5", true),
+            ("^0.2", "0.3.0", false),
+            ("^0.0.3", "0.0.4", false),
+            ("~1.2", "1.2.9", true),
+            ("~1.2", "1.3.0", false),
+            ("~1", "1.9.0", true),
+            ("=1.0.0", "1.0.1", false),
+            (">=1.0, <2", "1.5.0", true),
+            (">=1.0, <2", "2.0.0", false),
+            (">1", "1.0.0", false),
+            ("<=1.1", "1.1.0", true),
+            // nothing above these to bound them
+            ("^4294967295", "4294967295.7.0", true),
+            ("~1.4294967295", "1.4294967295.1", true),
+            ("^0.0.4294967295", "0.0.4294967294", false),
+        ];
+        for (r, version, expected) in cases {
+            assert_eq!(req(r).matches(&v(version)), expected, "{} {}", r, version);
+        }
+        assert!(">=x".parse::<VersionReq>().is_err());
+        assert_eq!(req(">=1, <2").to_string(), ">=1, <2");
+        assert_eq!(VersionReq::any().to_string(), "*");
+    }
+}
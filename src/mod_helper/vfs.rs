@@ -0,0 +1,374 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use crate::packet_helper::message_helper::wad_helper::{
+    find_files, find_wads, writer::Writer, Archive, Entry,
+};
+
+// One view of what the game sees, stacked from layers. Paths are
+// `<wad>/<entry>`, eg. `Root.wad/Locale/English/Adventures.lang`, so every file
+// can be traced back to the wad it has to end up in.
+//
+// Layers added later win over earlier ones: the base game wads go in first,
+// then mod wads and loose directories in load order.
+//
+// let mut vfs = Vfs::new();
+// vfs.add_base("Data/GameData")?;
+// vfs.add_dir("my mod", "mods/my_mod")?;
+// let data = vfs.read("Root.wad/GameMessages.xml")?;
+
+// Name of the layer with the game's own wads
+pub const BASE_LAYER: &str = "base";
+
+enum Source {
+    // index into `Layer::wads`
+    WadEntry(usize, Entry),
+    File(PathBuf),
+    Data(Vec<u8>),
+}
+
+struct Layer {
+    name: String,
+    wads: Vec<Mutex<Archive>>,
+    // keyed by `path_key`
+    files: BTreeMap<String, Source>,
+}
+
+#[derive(Default)]
+pub struct Vfs {
+    // bottom first
+    layers: Vec<Layer>,
+    // `path_key` of every file and directory -> how it's spelled, the first
+    // spelling seen wins so mods can't change the case of the game's paths
+    names: BTreeMap<String, String>,
+}
+
+pub(crate) fn normalize(path: &str) -> String {
+    path.replace('\\', "/").trim_matches('/').to_string()
+}
+
+// The game doesn't care about case or slash direction, neither does the vfs
+pub(crate) fn path_key(path: &str) -> String {
+    normalize(path).to_ascii_lowercase()
+}
+
+impl Vfs {
+    pub fn new() -> Vfs {
+        Default::default()
+    }
+
+    fn push(&mut self, name: &str) -> Result<Layer, String> {
+        if name == BASE_LAYER {
+            return Err(format!("'{}' is the name of the game's layer", name));
+        }
+        self.layer(name)
+    }
+
+    fn layer(&self, name: &str) -> Result<Layer, String> {
+        if self.layers.iter().any(|l| l.name == name) {
+            return Err(format!("There already is a layer named '{}'", name));
+        }
+        Ok(Layer {
+            name: name.to_string(),
+            wads: Vec::new(),
+            files: BTreeMap::new(),
+        })
+    }
+
+    // Adds `path` to `layer`, remembering how it and its directories are spelled
+    fn insert(&mut self, layer: &mut Layer, path: &str, source: Source) {
+        let path = normalize(path);
+        let mut end = 0;
+        for part in path.split('/') {
+            end += part.len();
+            self.names
+                .entry(path[..end].to_ascii_lowercase())
+                .or_insert_with(|| path[..end].to_string());
+            end += 1;
+        }
+        layer.files.insert(path.to_ascii_lowercase(), source);
+    }
+
+    fn mount_wad(&mut self, layer: &mut Layer, archive: Archive, mount: &str) {
+        let i = layer.wads.len();
+        for entry in archive.entries() {
+            let path = format!("{}/{}", mount, entry.name);
+            self.insert(layer, &path, Source::WadEntry(i, entry.clone()));
+        }
+        layer.wads.push(Mutex::new(archive));
+    }
+
+    // Mounts every wad under `dir` (the game's Data/GameData) at its path
+    // relative to `dir`
+    pub fn add_base<P: AsRef<Path>>(&mut self, dir: P) -> Result<(), String> {
+        let dir = dir.as_ref();
+        let mut layer = self.layer(BASE_LAYER)?;
+        for path in find_wads(dir)? {
+            let name = path.strip_prefix(dir).unwrap_or(&path).to_string_lossy();
+            let archive = Archive::open(&path)?;
+            self.mount_wad(&mut layer, archive, &normalize(&name));
+        }
+        // always the bottom layer, whenever it's added
+        self.layers.insert(0, layer);
+        Ok(())
+    }
+
+    // A mod wad whose entries go into the wad mounted at `mount`, eg. "Root.wad"
+    pub fn add_wad<P: AsRef<Path>>(
+        &mut self,
+        name: &str,
+        path: P,
+        mount: &str,
+    ) -> Result<(), String> {
+        let mut layer = self.push(name)?;
+        let archive = Archive::open(path)?;
+        self.mount_wad(&mut layer, archive, &normalize(mount));
+        self.layers.push(layer);
+        Ok(())
+    }
+
+    // A loose directory laid out like the vfs, eg. `<dir>/Root.wad/Foo.xml`
+    pub fn add_dir<P: AsRef<Path>>(&mut self, name: &str, dir: P) -> Result<(), String> {
+        let mut layer = self.push(name)?;
+        let files = find_files(dir)?;
+        for (path, file) in files {
+            self.insert(&mut layer, &path, Source::File(file));
+        }
+        self.layers.push(layer);
+        Ok(())
+    }
+
+    // Files that only exist in memory, eg. the output of xml patches
+    pub fn add_data(&mut self, name: &str, files: Vec<(String, Vec<u8>)>) -> Result<(), String> {
+        let mut layer = self.push(name)?;
+        for (path, data) in files {
+            self.insert(&mut layer, &path, Source::Data(data));
+        }
+        self.layers.push(layer);
+        Ok(())
+    }
+
+    fn find(&self, path: &str) -> Option<(&Layer, &Source)> {
+        let key = path_key(path);
+        self.layers
+            .iter()
+            .rev()
+            .find_map(|l| l.files.get(&key).map(|s| (l, s)))
+    }
+
+    // Name of the layer the game would get `path` from
+    pub fn which_layer(&self, path: &str) -> Option<&str> {
+        self.find(path).map(|(l, _)| l.name.as_str())
+    }
+
+    // Every layer that has `path`, bottom first
+    pub fn providers(&self, path: &str) -> Vec<&str> {
+        let key = path_key(path);
+        self.layers
+            .iter()
+            .filter(|l| l.files.contains_key(&key))
+            .map(|l| l.name.as_str())
+            .collect()
+    }
+
+    pub fn read(&self, path: &str) -> Result<Vec<u8>, String> {
+        match self.find(path) {
+            Some((layer, Source::WadEntry(i, entry))) => layer.wads[*i].lock().unwrap().read(entry),
+            Some((_, Source::File(file))) => {
+                fs::read(file).or(Err(format!("Couldn't read '{}'", file.display())))
+            }
+            Some((_, Source::Data(data))) => Ok(data.clone()),
+            None => Err(format!("{} doesn't exist", path)),
+        }
+    }
+
+    fn spelling(&self, key: &str) -> String {
+        self.names
+            .get(key)
+            .cloned()
+            .unwrap_or_else(|| key.to_string())
+    }
+
+    // What's directly in `dir`, directories end with a '/'
+    pub fn list(&self, dir: &str) -> Vec<String> {
+        let dir = path_key(dir);
+        let prefix = if dir.is_empty() {
+            dir
+        } else {
+            format!("{}/", dir)
+        };
+
+        let mut names = BTreeMap::new();
+        for layer in &self.layers {
+            for key in layer.files.keys() {
+                if let Some(rest) = key.strip_prefix(&prefix) {
+                    let (sub, slash) = match rest.split_once('/') {
+                        Some((sub, _)) => (sub, "/"),
+                        None => (rest, ""),
+                    };
+                    let key = format!("{}{}", prefix, sub);
+                    let name = self.spelling(&key)[prefix.len()..].to_string();
+                    names.insert(key, format!("{}{}", name, slash));
+                }
+            }
+        }
+        names.into_values().collect()
+    }
+
+    // Every file under `dir`, at any depth
+    pub fn files(&self, dir: &str) -> Vec<String> {
+        let dir = path_key(dir);
+        let prefix = if dir.is_empty() {
+            dir
+        } else {
+            format!("{}/", dir)
+        };
+
+        let mut keys = BTreeSet::new();
+        for layer in &self.layers {
+            keys.extend(layer.files.keys().filter(|k| k.starts_with(&prefix)));
+        }
+        keys.into_iter().map(|k| self.spelling(k)).collect()
+    }
+
+    // Mounted wads that something above the base layer puts files into
+    pub fn modified_wads(&self) -> Vec<String> {
+        let mut wads = BTreeSet::new();
+        for layer in self.layers.iter().filter(|l| l.name != BASE_LAYER) {
+            for key in layer.files.keys() {
+                if let Some((wad, _)) = key.split_once(".wad/") {
+                    wads.insert(format!("{}.wad", wad));
+                }
+            }
+        }
+        wads.into_iter().map(|k| self.spelling(&k)).collect()
+    }
+
+    // Writes the wad mounted at `wad` as the game would see it. Entries that
+    // come from the base wad are copied over untouched, the rest are
+    // compressed unless the base had them stored.
+    pub fn build_wad<P: AsRef<Path>>(&self, wad: &str, out: P) -> Result<(), String> {
+        let prefix = format!("{}/", path_key(wad));
+        let base = self.layers.iter().find(|l| l.name == BASE_LAYER);
+
+        let mut overrides = Vec::new();
+        for path in self.files(wad) {
+            if self.which_layer(&path) != Some(BASE_LAYER) {
+                let data = self.read(&path)?;
+                overrides.push((path, data));
+            }
+        }
+
+        let base_archive = base.and_then(|b| {
+            b.files.iter().find_map(|(key, source)| match source {
+                Source::WadEntry(i, _) if key.starts_with(&prefix) => Some(&b.wads[*i]),
+                _ => None,
+            })
+        });
+        let mut guard = base_archive.map(|a| a.lock().unwrap());
+
+        let mut writer = match guard.as_deref_mut() {
+            Some(archive) => Writer::from_archive(archive),
+            None => Writer::new(),
+        };
+        for (path, contents) in overrides {
+            // spelled like the entry it replaces, if there is one
+            let key = path_key(&path);
+            let (name, compress) = match base.and_then(|b| b.files.get(&key)) {
+                Some(Source::WadEntry(_, e)) => (e.name.clone(), e.compressed),
+                _ => (path[prefix.len()..].to_string(), true),
+            };
+            writer.add(&name, contents, compress);
+        }
+        writer.write(out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet_helper::message_helper::wad_helper::fixtures::{write_wad, ENTRIES};
+
+    fn vfs(dir: &Path) -> Vfs {
+        let game_data = dir.join("GameData");
+        fs::create_dir_all(&game_data).unwrap();
+        write_wad(&game_data, "Root.wad", ENTRIES);
+        let mut vfs = Vfs::new();
+        vfs.add_base(&game_data).unwrap();
+        vfs
+    }
+
+    #[test]
+    fn layers_stack_in_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut vfs = vfs(dir.path());
+        vfs.add_data(
+            "a",
+            vec![(String::from("Root.wad/GameData/Bar.txt"), b"a".to_vec())],
+        )
+        .unwrap();
+        vfs.add_data(
+            "b",
+            vec![(String::from("Root.wad/GameData/Bar.txt"), b"b".to_vec())],
+        )
+        .unwrap();
+
+        assert_eq!(vfs.read("Root.wad/GameData/Bar.txt").unwrap(), b"b");
+        assert_eq!(vfs.read("Root.wad/GameData/Foo.xml").unwrap(), ENTRIES[0].1);
+        assert_eq!(
+            vfs.which_layer("Root.wad/GameData/Foo.xml"),
+            Some(BASE_LAYER)
+        );
+        assert_eq!(
+            vfs.providers("Root.wad/GameData/Bar.txt"),
+            [BASE_LAYER, "a", "b"]
+        );
+        assert_eq!(vfs.list(""), ["Root.wad/"]);
+        assert_eq!(vfs.list("Root.wad"), ["GameData/", "Locale/"]);
+        assert_eq!(vfs.modified_wads(), ["Root.wad"]);
+        assert!(vfs.read("Root.wad/Missing.xml").is_err());
+    }
+
+    #[test]
+    fn paths_ignore_case_and_slashes() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut vfs = vfs(dir.path());
+        let mod_dir = dir.path().join("mod");
+        fs::create_dir_all(mod_dir.join("root.WAD/gamedata")).unwrap();
+        fs::write(mod_dir.join("root.WAD/gamedata/FOO.xml"), b"<Foo/>").unwrap();
+        vfs.add_dir("mod", &mod_dir).unwrap();
+
+        for path in [
+            "Root.wad/GameData/Foo.xml",
+            "root.wad\\gamedata\\foo.XML",
+            "/ROOT.WAD/GAMEDATA/FOO.XML",
+        ] {
+            assert_eq!(vfs.read(path).unwrap(), b"<Foo/>");
+            assert_eq!(vfs.which_layer(path), Some("mod"));
+        }
+        // spelled the way the game spells them
+        assert_eq!(vfs.list("root.wad/gamedata"), ["Bar.txt", "Foo.xml"]);
+        assert_eq!(vfs.modified_wads(), ["Root.wad"]);
+
+        // the override replaces the base entry instead of adding a second one
+        let out = dir.path().join("Root.wad");
+        vfs.build_wad("ROOT.wad", &out).unwrap();
+        let mut built = Archive::open(&out).unwrap();
+        let names: Vec<_> = built.entries().iter().map(|e| e.name.clone()).collect();
+        assert_eq!(names.len(), ENTRIES.len());
+        assert!(names.contains(&String::from("GameData/Foo.xml")));
+        assert_eq!(built.read_file("GameData/Foo.xml").unwrap(), b"<Foo/>");
+    }
+
+    #[test]
+    fn base_name_is_reserved() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut vfs = vfs(dir.path());
+        assert!(vfs.add_data(BASE_LAYER, Vec::new()).is_err());
+        assert!(vfs.add_data("mod", Vec::new()).is_ok());
+        assert!(vfs.add_data("mod", Vec::new()).is_err());
+        assert!(vfs.add_base(dir.path().join("GameData")).is_err());
+    }
+}
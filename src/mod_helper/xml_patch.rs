@@ -0,0 +1,715 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+use super::vfs::Vfs;
+use crate::packet_helper::message_helper::wad_helper::index::decode_text;
+
+// Patch files are json, a list of operations on xml entries of the vfs:
+//
+// { "patches": [
+//   { "entry": "Root.wad/GameData/Foo.xml", "select": "/Objects/Class[@Name='Bar']/Speed",
+//     "op": "replace", "xml": "<Speed>2</Speed>" },
+//   { "entry": "...", "select": "//Spell[Name='Fire']", "op": "set-attribute",
+//     "name": "Cost", "value": "3" },
+//   { "entry": "...", "select": "/Objects", "op": "add", "xml": "<Class Name='New'/>" },
+//   { "entry": "...", "select": "/Objects/Class[2]", "op": "remove" }
+// ] }
+//
+// Selectors are a subset of XPath: `/` and `//` steps, `*`, and `[n]`,
+// `[@attr]`, `[@attr='v']`, `[child='v']` and `[text()='v']` predicates.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "kebab-case")]
+pub enum Operation {
+    // appends `xml` to the children of every match
+    Add { xml: String },
+    Replace { xml: String },
+    Remove,
+    SetAttribute { name: String, value: String },
+}
+
+impl fmt::Display for Operation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Operation::Add { .. } => write!(f, "add"),
+            Operation::Replace { .. } => write!(f, "replace"),
+            Operation::Remove => write!(f, "remove"),
+            Operation::SetAttribute { name, .. } => write!(f, "set-attribute {}", name),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Patch {
+    pub entry: String,
+    pub select: String,
+    #[serde(flatten)]
+    pub op: Operation,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct PatchSet {
+    // mod the patches come from, used in the report
+    #[serde(skip)]
+    pub name: String,
+    pub patches: Vec<Patch>,
+}
+
+impl PatchSet {
+    pub fn load<P: AsRef<Path>>(name: &str, path: P) -> Result<PatchSet, String> {
+        let path = path.as_ref();
+        let contents = fs::read_to_string(path).or(Err(format!(
+            "Couldn't read patch file '{}'",
+            path.display()
+        )))?;
+        let mut set: PatchSet = serde_json::from_str(&contents)
+            .map_err(|e| format!("Invalid patch file '{}': {}", path.display(), e))?;
+        set.name = name.to_string();
+        Ok(set)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct PatchFailure {
+    pub mod_name: String,
+    pub entry: String,
+    pub select: String,
+    pub op: String,
+    pub reason: String,
+}
+
+impl fmt::Display for PatchFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}: {} {} in {}: {}",
+            self.mod_name, self.op, self.select, self.entry, self.reason
+        )
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct PatchReport {
+    pub applied: usize,
+    pub failed: Vec<PatchFailure>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Node {
+    Element(Element),
+    Text(String),
+    Comment(String),
+    Pi(String, Option<String>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Element {
+    name: String,
+    attributes: Vec<(String, String)>,
+    children: Vec<Node>,
+}
+
+impl Element {
+    fn child_elements(&self) -> impl Iterator<Item = (usize, &Element)> {
+        self.children
+            .iter()
+            .enumerate()
+            .filter_map(|(i, n)| match n {
+                Node::Element(e) => Some((i, e)),
+                _ => None,
+            })
+    }
+
+    fn attribute(&self, name: &str) -> Option<&str> {
+        self.attributes
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, v)| v.as_str())
+    }
+
+    fn text(&self) -> String {
+        self.children
+            .iter()
+            .filter_map(|n| match n {
+                Node::Text(t) => Some(t.as_str()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn at(&self, path: &[usize]) -> &Element {
+        match path.split_first() {
+            Some((i, rest)) => match &self.children[*i] {
+                Node::Element(e) => e.at(rest),
+                _ => unreachable!(),
+            },
+            None => self,
+        }
+    }
+
+    fn at_mut(&mut self, path: &[usize]) -> &mut Element {
+        match path.split_first() {
+            Some((i, rest)) => match &mut self.children[*i] {
+                Node::Element(e) => e.at_mut(rest),
+                _ => unreachable!(),
+            },
+            None => self,
+        }
+    }
+}
+
+fn convert(node: roxmltree::Node) -> Option<Node> {
+    Some(match node.node_type() {
+        roxmltree::NodeType::Element => Node::Element(Element {
+            name: node.tag_name().name().to_string(),
+            attributes: node
+                .attributes()
+                .map(|a| (a.name().to_string(), a.value().to_string()))
+                .collect(),
+            children: node.children().filter_map(convert).collect(),
+        }),
+        roxmltree::NodeType::Text => Node::Text(node.text()?.to_string()),
+        roxmltree::NodeType::Comment => Node::Comment(node.text()?.to_string()),
+        roxmltree::NodeType::PI => {
+            let pi = node.pi()?;
+            Node::Pi(pi.target.to_string(), pi.value.map(String::from))
+        }
+        roxmltree::NodeType::Root => return None,
+    })
+}
+
+fn parse_options() -> roxmltree::ParsingOptions {
+    roxmltree::ParsingOptions {
+        allow_dtd: true,
+        ..Default::default()
+    }
+}
+
+// The document is kept as an unnamed element holding the root element and
+// whatever comments are around it, so selectors can start from it
+struct Document {
+    declaration: bool,
+    root: Element,
+}
+
+impl Document {
+    fn parse(text: &str) -> Result<Document, String> {
+        let doc = roxmltree::Document::parse_with_options(text, parse_options())
+            .map_err(|e| e.to_string())?;
+        Ok(Document {
+            declaration: text.trim_start().starts_with("<?xml"),
+            root: Element {
+                name: String::new(),
+                attributes: Vec::new(),
+                children: doc.root().children().filter_map(convert).collect(),
+            },
+        })
+    }
+
+    fn serialize(&self) -> String {
+        let mut out = String::new();
+        if self.declaration {
+            out.push_str("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+        }
+        for node in &self.root.children {
+            write_node(node, &mut out);
+            if !matches!(node, Node::Text(_)) {
+                out.push('\n');
+            }
+        }
+        out
+    }
+}
+
+// Nodes of an xml fragment, which can have more than one top level element
+fn parse_fragment(xml: &str) -> Result<Vec<Node>, String> {
+    let wrapped = format!("<fragment>{}</fragment>", xml);
+    let doc = roxmltree::Document::parse_with_options(&wrapped, parse_options())
+        .map_err(|e| format!("Invalid xml: {}", e))?;
+    Ok(doc.root_element().children().filter_map(convert).collect())
+}
+
+fn escape(s: &str, attribute: bool) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' if attribute => out.push_str("&quot;"),
+            _ => out.push(c),
+        }
+    }
+    out
+}
+
+fn write_node(node: &Node, out: &mut String) {
+    match node {
+        Node::Text(t) => out.push_str(&escape(t, false)),
+        Node::Comment(c) => out.push_str(&format!("<!--{}-->", c)),
+        Node::Pi(target, Some(value)) => out.push_str(&format!("<?{} {}?>", target, value)),
+        Node::Pi(target, None) => out.push_str(&format!("<?{}?>", target)),
+        Node::Element(e) => {
+            out.push('<');
+            out.push_str(&e.name);
+            for (name, value) in &e.attributes {
+                out.push_str(&format!(" {}=\"{}\"", name, escape(value, true)));
+            }
+            if e.children.is_empty() {
+                out.push_str("/>");
+                return;
+            }
+            out.push('>');
+            for child in &e.children {
+                write_node(child, out);
+            }
+            out.push_str(&format!("</{}>", e.name));
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Predicate {
+    Position(usize),
+    HasAttribute(String),
+    Attribute(String, String),
+    Child(String, String),
+    Text(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Step {
+    descendant: bool,
+    // None for `*`
+    name: Option<String>,
+    predicates: Vec<Predicate>,
+}
+
+impl Step {
+    fn matches(&self, e: &Element) -> bool {
+        self.name.as_ref().is_none_or(|n| *n == e.name)
+    }
+
+    fn accepts(&self, e: &Element, position: usize) -> bool {
+        self.predicates.iter().all(|p| match p {
+            Predicate::Position(n) => position == *n,
+            Predicate::HasAttribute(a) => e.attribute(a).is_some(),
+            Predicate::Attribute(a, v) => e.attribute(a) == Some(v.as_str()),
+            Predicate::Child(c, v) => e
+                .child_elements()
+                .any(|(_, c2)| c2.name == *c && c2.text() == *v),
+            Predicate::Text(v) => e.text() == *v,
+        })
+    }
+}
+
+fn parse_selector(selector: &str) -> Result<Vec<Step>, String> {
+    let err = |why: &str| format!("Invalid selector '{}': {}", selector, why);
+    let chars: Vec<char> = selector.trim().chars().collect();
+    let mut i = 0;
+    let mut steps = Vec::new();
+
+    let is_name = |c: char| c.is_alphanumeric() || matches!(c, '_' | '-' | '.' | ':');
+
+    while i < chars.len() {
+        let descendant = if chars[i..].starts_with(&['/', '/']) {
+            i += 2;
+            true
+        } else if chars[i] == '/' {
+            i += 1;
+            false
+        } else if i == 0 {
+            false
+        } else {
+            return Err(err("expected '/'"));
+        };
+
+        let start = i;
+        while i < chars.len() && (is_name(chars[i]) || chars[i] == '*') {
+            i += 1;
+        }
+        let name: String = chars[start..i].iter().collect();
+        let name = match name.as_str() {
+            "" => return Err(err("missing element name")),
+            "*" => None,
+            _ if name.contains('*') => return Err(err("'*' has to be a whole name")),
+            _ => Some(name),
+        };
+
+        let mut predicates = Vec::new();
+        while i < chars.len() && chars[i] == '[' {
+            let end = chars[i..]
+                .iter()
+                .position(|c| *c == ']')
+                .map(|p| p + i)
+                .ok_or_else(|| err("unclosed '['"))?;
+            let inner: String = chars[i + 1..end].iter().collect();
+            predicates.push(
+                parse_predicate(inner.trim())
+                    .ok_or_else(|| err(&format!("bad predicate [{}]", inner)))?,
+            );
+            i = end + 1;
+        }
+
+        steps.push(Step {
+            descendant,
+            name,
+            predicates,
+        });
+    }
+
+    if steps.is_empty() {
+        return Err(err("it's empty"));
+    }
+    Ok(steps)
+}
+
+fn parse_literal(s: &str) -> Option<String> {
+    let s = s.trim();
+    let quote = s.chars().next()?;
+    if (quote == '\'' || quote == '"') && s.len() >= 2 && s.ends_with(quote) {
+        Some(s[1..s.len() - 1].to_string())
+    } else {
+        None
+    }
+}
+
+fn parse_predicate(p: &str) -> Option<Predicate> {
+    if let Ok(n) = p.parse::<usize>() {
+        return (n > 0).then_some(Predicate::Position(n));
+    }
+    match p.split_once('=') {
+        Some((lhs, rhs)) => {
+            let lhs = lhs.trim();
+            let value = parse_literal(rhs)?;
+            if let Some(attr) = lhs.strip_prefix('@') {
+                Some(Predicate::Attribute(attr.to_string(), value))
+            } else if lhs == "text()" {
+                Some(Predicate::Text(value))
+            } else if !lhs.is_empty() {
+                Some(Predicate::Child(lhs.to_string(), value))
+            } else {
+                None
+            }
+        }
+        None => p
+            .strip_prefix('@')
+            .filter(|a| !a.is_empty())
+            .map(|a| Predicate::HasAttribute(a.to_string())),
+    }
+}
+
+// Element `base` and every element under it with their paths, in document order
+fn descendants(base: &Element, path: &[usize], out: &mut Vec<Vec<usize>>) {
+    for (i, child) in base.child_elements() {
+        let mut child_path = path.to_vec();
+        child_path.push(i);
+        out.push(child_path.clone());
+        descendants(child, &child_path, out);
+    }
+}
+
+// Paths (child indices from the document) of the elements `steps` select
+fn select(doc: &Element, steps: &[Step]) -> Vec<Vec<usize>> {
+    let mut current: Vec<Vec<usize>> = vec![Vec::new()];
+    for step in steps {
+        let mut next = Vec::new();
+        for ctx in &current {
+            let parents = if step.descendant {
+                let mut all = vec![ctx.clone()];
+                descendants(doc.at(ctx), ctx, &mut all);
+                all
+            } else {
+                vec![ctx.clone()]
+            };
+            // positions count the siblings that pass the name test
+            for parent in parents {
+                let mut position = 0;
+                for (i, child) in doc.at(&parent).child_elements() {
+                    if !step.matches(child) {
+                        continue;
+                    }
+                    position += 1;
+                    if step.accepts(child, position) {
+                        let mut path = parent.clone();
+                        path.push(i);
+                        next.push(path);
+                    }
+                }
+            }
+        }
+        next.sort();
+        next.dedup();
+        current = next;
+    }
+    current
+}
+
+fn is_blank(node: &Node) -> bool {
+    matches!(node, Node::Text(t) if t.trim().is_empty())
+}
+
+// Appends `nodes` after the last child, indented like the first one if the
+// element is laid out over several lines
+fn add_children(e: &mut Element, nodes: Vec<Node>) {
+    let indent = match e.children.first() {
+        Some(first) if is_blank(first) && e.children.len() > 1 => Some(first.clone()),
+        _ => None,
+    };
+    let at = match e.children.last() {
+        Some(last) if is_blank(last) && indent.is_some() => e.children.len() - 1,
+        _ => e.children.len(),
+    };
+    let mut added = Vec::new();
+    for node in nodes {
+        if let (Some(indent), Node::Element(_)) = (&indent, &node) {
+            added.push(indent.clone());
+        }
+        added.push(node);
+    }
+    e.children.splice(at..at, added);
+}
+
+fn apply(doc: &mut Document, patch: &Patch) -> Result<usize, String> {
+    let steps = parse_selector(&patch.select)?;
+    let matches = select(&doc.root, &steps);
+    if matches.is_empty() {
+        return Err(String::from("selector matched nothing"));
+    }
+
+    // everything that can fail is checked before the document is touched
+    let nodes = match &patch.op {
+        Operation::Add { xml } | Operation::Replace { xml } => parse_fragment(xml)?,
+        _ => Vec::new(),
+    };
+    let root_matched = matches.iter().any(|p| p.len() == 1);
+    match &patch.op {
+        Operation::Remove if root_matched => {
+            return Err(String::from("can't remove the root element"))
+        }
+        Operation::Replace { .. }
+            if root_matched
+                && nodes
+                    .iter()
+                    .filter(|n| matches!(n, Node::Element(_)))
+                    .count()
+                    != 1 =>
+        {
+            return Err(String::from(
+                "the root element has to be replaced by one element",
+            ))
+        }
+        _ => (),
+    }
+
+    // later matches first, so removing nodes doesn't move the ones left to do
+    for path in matches.iter().rev() {
+        match &patch.op {
+            Operation::Add { .. } => add_children(doc.root.at_mut(path), nodes.clone()),
+            Operation::Replace { .. } => {
+                let (i, parent) = path.split_last().unwrap();
+                doc.root
+                    .at_mut(parent)
+                    .children
+                    .splice(*i..*i + 1, nodes.clone());
+            }
+            Operation::Remove => {
+                let (i, parent) = path.split_last().unwrap();
+                let children = &mut doc.root.at_mut(parent).children;
+                children.remove(*i);
+                // along with the indentation it had
+                if *i > 0 && is_blank(&children[*i - 1]) {
+                    children.remove(*i - 1);
+                }
+            }
+            Operation::SetAttribute { name, value } => {
+                let e = doc.root.at_mut(path);
+                match e.attributes.iter_mut().find(|(n, _)| n == name) {
+                    Some((_, v)) => *v = value.clone(),
+                    None => e.attributes.push((name.clone(), value.clone())),
+                }
+            }
+        }
+    }
+    Ok(matches.len())
+}
+
+// Applies the patch sets in load order, each patch on top of the ones before
+// it. Entries are read from `vfs` once and returned patched, ready to go in a
+// layer above everything they were read from. Patches that fail are skipped
+// and reported, the rest still apply.
+pub fn apply_patches(vfs: &Vfs, sets: &[PatchSet]) -> (Vec<(String, Vec<u8>)>, PatchReport) {
+    let mut report = PatchReport::default();
+    // parsed entries, and whether anything was applied to them
+    let mut docs: BTreeMap<String, Result<(Document, bool), String>> = BTreeMap::new();
+
+    for set in sets {
+        for patch in &set.patches {
+            let fail = |reason: String| PatchFailure {
+                mod_name: set.name.clone(),
+                entry: patch.entry.clone(),
+                select: patch.select.clone(),
+                op: patch.op.to_string(),
+                reason,
+            };
+
+            let doc = docs.entry(patch.entry.clone()).or_insert_with(|| {
+                let data = vfs.read(&patch.entry)?;
+                let doc = Document::parse(&decode_text(&data))
+                    .map_err(|e| format!("entry isn't valid xml: {}", e))?;
+                Ok((doc, false))
+            });
+            let (doc, changed) = match doc {
+                Ok(doc) => doc,
+                Err(e) => {
+                    report.failed.push(fail(e.clone()));
+                    continue;
+                }
+            };
+
+            match apply(doc, patch) {
+                Ok(_) => {
+                    *changed = true;
+                    report.applied += 1;
+                }
+                Err(e) => report.failed.push(fail(e)),
+            }
+        }
+    }
+
+    let files = docs
+        .into_iter()
+        .filter_map(|(entry, doc)| match doc {
+            Ok((doc, true)) => Some((entry, doc.serialize().into_bytes())),
+            _ => None,
+        })
+        .collect();
+    (files, report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ENTRY: &str = "Root.wad/GameData/Spells.xml";
+    const SPELLS: &str = "<?xml version=\"1.0\" encoding=\"UTF-8\"?>
+<Spells>
+  <Spell Name=\"Fire\"><Cost>1</Cost></Spell>
+  <Spell Name=\"Ice\"><Cost>2</Cost></Spell>
+  <Spell Name=\"Storm\" Rare=\"1\"><Cost>3</Cost></Spell>
+</Spells>";
+
+    fn vfs() -> Vfs {
+        let mut vfs = Vfs::new();
+        vfs.add_data(
+            "game",
+            vec![(String::from(ENTRY), SPELLS.as_bytes().to_vec())],
+        )
+        .unwrap();
+        vfs
+    }
+
+    fn set(name: &str, patches: &str) -> PatchSet {
+        let mut set: PatchSet = serde_json::from_str(patches).unwrap();
+        set.name = name.to_string();
+        set
+    }
+
+    fn patched(sets: &[PatchSet]) -> (String, PatchReport) {
+        let (files, report) = apply_patches(&vfs(), sets);
+        let text = files
+            .into_iter()
+            .find(|(entry, _)| entry == ENTRY)
+            .map(|(_, data)| String::from_utf8(data).unwrap())
+            .unwrap_or_default();
+        (text, report)
+    }
+
+    #[test]
+    fn applies_every_operation() {
+        let (text, report) = patched(&[set(
+            "mod",
+            r#"{"patches": [
+                {"entry": "Root.wad/GameData/Spells.xml", "select": "//Spell[@Name='Fire']/Cost",
+                 "op": "replace", "xml": "<Cost>5</Cost>"},
+                {"entry": "Root.wad/GameData/Spells.xml", "select": "/Spells/Spell[2]",
+                 "op": "set-attribute", "name": "School", "value": "Ice"},
+                {"entry": "Root.wad/GameData/Spells.xml", "select": "/Spells/Spell[@Rare]",
+                 "op": "remove"},
+                {"entry": "Root.wad/GameData/Spells.xml", "select": "/Spells",
+                 "op": "add", "xml": "<Spell Name='Life'/>"}
+            ]}"#,
+        )]);
+        assert!(report.failed.is_empty(), "{:?}", report.failed);
+        assert_eq!(report.applied, 4);
+        assert_eq!(
+            text,
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>
+<Spells>
+  <Spell Name=\"Fire\"><Cost>5</Cost></Spell>
+  <Spell Name=\"Ice\" School=\"Ice\"><Cost>2</Cost></Spell>
+  <Spell Name=\"Life\"/>
+</Spells>
+"
+        );
+    }
+
+    #[test]
+    fn selectors() {
+        let doc = Document::parse(SPELLS).unwrap();
+        let count = |selector: &str| select(&doc.root, &parse_selector(selector).unwrap()).len();
+        assert_eq!(count("/Spells/Spell"), 3);
+        assert_eq!(count("//Cost"), 3);
+        assert_eq!(count("//Cost[text()='2']"), 1);
+        assert_eq!(count("/Spells/*[3]"), 1);
+        assert_eq!(count("//Spell[Cost='3']"), 1);
+        assert_eq!(count("//Spell[@Name='Wind']"), 0);
+        assert!(parse_selector("Spells[").is_err());
+    }
+
+    #[test]
+    fn failures_are_reported_and_skipped() {
+        let (text, report) = patched(&[set(
+            "mod",
+            r#"{"patches": [
+                {"entry": "Root.wad/GameData/Spells.xml", "select": "//Nothing", "op": "remove"},
+                {"entry": "Root.wad/GameData/Spells.xml", "select": "/Spells", "op": "remove"},
+                {"entry": "Root.wad/GameData/Spells.xml", "select": "/Spells",
+                 "op": "add", "xml": "<Broken>"},
+                {"entry": "Root.wad/GameData/Missing.xml", "select": "/A", "op": "remove"},
+                {"entry": "Root.wad/GameData/Spells.xml", "select": "//Spell[1]/Cost",
+                 "op": "replace", "xml": "<Cost>9</Cost>"}
+            ]}"#,
+        )]);
+        assert_eq!(report.applied, 1);
+        let reasons: Vec<_> = report.failed.iter().map(|f| f.reason.as_str()).collect();
+        assert_eq!(reasons[0], "selector matched nothing");
+        assert_eq!(reasons[1], "can't remove the root element");
+        assert!(reasons[2].starts_with("Invalid xml"), "{}", reasons[2]);
+        assert!(reasons[3].contains("doesn't exist"), "{}", reasons[3]);
+        assert!(report.failed.iter().all(|f| f.mod_name == "mod"));
+        assert!(text.contains("<Cost>9</Cost>"));
+        assert!(text.contains("<Cost>2</Cost>"));
+    }
+
+    #[test]
+    fn later_sets_apply_on_top() {
+        let cost = |value: &str| {
+            format!(
+                r#"{{"patches": [{{"entry": "Root.wad/GameData/Spells.xml",
+                    "select": "//Spell[@Name='Ice']", "op": "set-attribute",
+                    "name": "Cost", "value": "{}"}}]}}"#,
+                value
+            )
+        };
+        let (text, report) = patched(&[set("a", &cost("4")), set("b", &cost("7"))]);
+        assert_eq!(report.applied, 2);
+        assert!(text.contains("<Spell Name=\"Ice\" Cost=\"7\">"), "{}", text);
+
+        // nothing applied, nothing written
+        let (files, _) = apply_patches(&vfs(), &[]);
+        assert!(files.is_empty());
+    }
+}
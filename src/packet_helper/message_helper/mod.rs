@@ -1,6 +1,7 @@
 pub mod wad_helper;
 
 use std::collections::HashMap;
+use std::fs;
 use std::str;
 
 extern crate flame;
@@ -115,11 +116,11 @@ impl Service {
     }
 }
 
-fn get_messages_xml() -> Vec<(String, Vec<u8>)> {
+fn get_messages_xml() -> Result<Vec<(String, Vec<u8>)>, String> {
     let mut file_list = wad_helper::FileList::get_file_list(
         r#"/home/binarybandit/Desktop/Wizard101Launcher/test/Data/GameData/Root.wad"#,
-    );
-    file_list.get_files_with_ext("Messages.xml")
+    )?;
+    Ok(file_list.get_files_with_ext("Messages.xml"))
 }
 
 fn get_value_from_name(node: roxmltree::Node, name: String) -> String {
@@ -134,104 +135,368 @@ fn get_value_from_name(node: roxmltree::Node, name: String) -> String {
     String::from("-1")
 }
 
-pub fn get_services() -> HashMap<u8, Service> {
+fn parse_service(xml_data_str: &str) -> Result<Service, String> {
+    let doc = roxmltree::Document::parse(xml_data_str)
+        .map_err(|e| format!("Invalid service xml: {}", e))?;
+
+    let root_node = doc.root_element();
+    let prot_info_node = root_node
+        .first_element_child()
+        .and_then(|n| n.first_element_child())
+        .ok_or(String::from("Service xml has no _ProtocolInfo record"))?;
+    let svc_id = get_value_from_name(prot_info_node, String::from("ServiceID"))
+        .parse::<u8>()
+        .or(Err(String::from("Service xml has an invalid ServiceID")))?;
+    let svc_type = get_value_from_name(prot_info_node, String::from("ProtocolType"));
+    let svc_ver = get_value_from_name(prot_info_node, String::from("ProtocolVersion"));
+    let svc_desc = get_value_from_name(prot_info_node, String::from("ProtocolDescription"));
+
+    let mut msgs = Vec::new();
+
+    let first_msg_node = prot_info_node
+        .parent_element()
+        .and_then(|n| n.next_sibling_element())
+        .ok_or(format!("Service {} has no messages", svc_id))?;
+
+    for node in first_msg_node.next_siblings() {
+        let inode = match node.first_element_child() {
+            Some(n) => n,
+            None => continue,
+        };
+
+        let msg_desc = get_value_from_name(inode, String::from("_MsgDescription"));
+        let msg_handler = get_value_from_name(inode, String::from("_MsgHandler"));
+        let msg_acc_lvl = get_value_from_name(inode, String::from("_MsgAccessLvl"));
+        let msg_order = get_value_from_name(inode, String::from("_MsgOrder"));
+
+        let mut args = Vec::new();
+
+        let first_arg_node = inode
+            .first_element_child()
+            .and_then(|n| n.next_sibling_element())
+            .ok_or(format!(
+                "Message {} has an incomplete record",
+                node.tag_name().name()
+            ))?;
+
+        for arg in first_arg_node.next_siblings() {
+            //println!("test {}", arg.tag_name().name());
+            if !arg.tag_name().name().contains("_Msg") && !arg.tag_name().name().is_empty() {
+                args.push(MessageField::new(
+                    arg.tag_name().name().to_string(),
+                    match arg.attribute("TYPE") {
+                        Some(t) => t.to_string(),
+                        None => String::from("Object has no typename...?"),
+                    },
+                ))
+            }
+        }
+        let msg = Message::new(
+            node.tag_name().name().to_string(),
+            msg_desc,
+            msg_handler,
+            msg_acc_lvl,
+            msg_order.parse::<i32>().or(Err(format!(
+                "Message {} has an invalid _MsgOrder",
+                node.tag_name().name()
+            )))?,
+            args,
+        );
+        msgs.push(msg);
+    }
+
+    if msgs.is_empty() {
+        return Err(format!("Service {} has no messages", svc_id));
+    }
+
+    if msgs[0].msg_order > 0 {
+        msgs.sort_by_key(|k| k.msg_order);
+    } else {
+        msgs.sort_by_key(|k| k.name.clone());
+    }
+
+    Ok(Service::new(
+        svc_id,
+        svc_type,
+        svc_ver.parse::<i32>().or(Err(format!(
+            "Service {} has an invalid ProtocolVersion",
+            svc_id
+        )))?,
+        svc_desc,
+        msgs,
+    ))
+}
+
+pub fn get_services() -> Result<HashMap<u8, Service>, String> {
     let mut ret: HashMap<u8, Service> = HashMap::new();
-    let messages = get_messages_xml();
+    let messages = get_messages_xml()?;
     for val in messages.iter() {
-        let xml_data_str = str::from_utf8(&val.1).unwrap();
-        let doc = roxmltree::Document::parse(xml_data_str).unwrap();
+        let xml_data_str =
+            str::from_utf8(&val.1).or(Err(format!("{} isn't valid utf-8", val.0)))?;
+        let mut svc = parse_service(xml_data_str).map_err(|e| format!("{}: {}", val.0, e))?;
 
-        let prot_info_node = doc
-            .root()
-            .first_child()
-            .unwrap()
-            .first_element_child()
-            .unwrap()
-            .first_element_child()
-            .unwrap();
-        let svc_id = get_value_from_name(prot_info_node, String::from("ServiceID"))
-            .parse::<u8>()
-            .unwrap();
-        let svc_type = get_value_from_name(prot_info_node, String::from("ProtocolType"));
-        let svc_ver = get_value_from_name(prot_info_node, String::from("ProtocolVersion"));
-        let svc_desc = get_value_from_name(prot_info_node, String::from("ProtocolDescription"));
-
-        let mut msgs = Vec::new();
-
-        for node in doc
-            .root()
-            .first_child()
-            .unwrap()
-            .first_element_child()
-            .unwrap()
-            .next_sibling_element()
-            .unwrap()
-            .next_siblings()
-        {
-            let inode = match node.first_element_child() {
-                Some(n) => n,
-                None => continue,
-            };
-
-            let msg_desc = get_value_from_name(inode, String::from("_MsgDescription"));
-            let msg_handler = get_value_from_name(inode, String::from("_MsgHandler"));
-            let msg_acc_lvl = get_value_from_name(inode, String::from("_MsgAccessLvl"));
-            let msg_order = get_value_from_name(inode, String::from("_MsgOrder"));
-
-            let mut args = Vec::new();
-
-            for arg in inode
-                .first_element_child()
-                .unwrap()
-                .next_sibling_element()
-                .unwrap()
-                .next_siblings()
-            {
-                //println!("test {}", arg.tag_name().name());
-                if arg.tag_name().name().find("_Msg") == None && arg.tag_name().name().len() != 0 {
-                    args.push(MessageField::new(
-                        arg.tag_name().name().to_string(),
-                        match arg.attribute("TYPE") {
-                            Some(t) => t.to_string(),
-                            None => String::from("Object has no typename...?"),
-                        },
-                    ))
-                }
+        match ret.get_mut(&svc.id) {
+            Some(existing) => {
+                println!("Service {} already exists! Appending messages... (this probably shouldn't happen!)", svc.id);
+                existing.messages.append(&mut svc.messages);
+            }
+            None => {
+                //println!("{:#?}", svc);
+                ret.insert(svc.id, svc);
             }
-            let msg = Message::new(
-                node.tag_name().name().to_string(),
-                msg_desc,
-                msg_handler,
-                msg_acc_lvl,
-                msg_order.parse::<i32>().unwrap(),
-                args,
-            );
-            msgs.push(msg);
         }
+    }
+    Ok(ret)
+}
 
-        if msgs.get(0).unwrap().msg_order > 0 {
-            msgs.sort_by_key(|k| k.msg_order);
-        } else {
-            msgs.sort_by_key(|k| k.name.clone());
+// A problem found while layering extra service definitions on top of the ones
+// from Root.wad. Nothing is merged if any of these are found.
+#[derive(Debug)]
+pub enum ServiceConflict {
+    // the extra file reuses a service id for a different protocol
+    ServiceName {
+        id: u8,
+        existing: String,
+        new: String,
+    },
+    // the extra file redefines a message that the service already has
+    DuplicateMessage {
+        id: u8,
+        message: String,
+    },
+    // message names have to be unique across services, otherwise the serializer
+    // can't tell which service to send it on
+    MessageInOtherService {
+        message: String,
+        existing_id: u8,
+        new_id: u8,
+    },
+}
+
+impl std::fmt::Display for ServiceConflict {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ServiceConflict::ServiceName { id, existing, new } => write!(
+                f,
+                "Service {} is already defined as {}, can't redefine it as {}",
+                id, existing, new
+            ),
+            ServiceConflict::DuplicateMessage { id, message } => {
+                write!(f, "Message {} already exists in service {}", message, id)
+            }
+            ServiceConflict::MessageInOtherService {
+                message,
+                existing_id,
+                new_id,
+            } => write!(
+                f,
+                "Message {} for service {} is already defined by service {}",
+                message, new_id, existing_id
+            ),
         }
+    }
+}
 
-        match ret.get_mut(&svc_id) {
-            Some(svc) => {
-                println!("Service {} already exists! Appending messages... (this probably shouldn't happen!)", svc_id);
-                svc.messages.append(&mut msgs);
+// Layers extra services over `base`. A service with a new id is added as is,
+// a service with an existing id (and the same ProtocolType) has its messages
+// appended after the existing ones so the message ids the game uses don't shift.
+pub fn merge_services(
+    base: &mut HashMap<u8, Service>,
+    extra: Vec<Service>,
+) -> Result<(), Vec<ServiceConflict>> {
+    let mut conflicts = Vec::new();
+    let mut message_table = Service::message_table(base);
+    // protocol of every service id so far, so two extra files can't define
+    // the same new service differently either
+    let mut protocols: HashMap<u8, String> = base
+        .iter()
+        .map(|(id, svc)| (*id, svc.name.clone()))
+        .collect();
+
+    for svc in &extra {
+        let existing = protocols.entry(svc.id).or_insert_with(|| svc.name.clone());
+        if *existing != svc.name {
+            conflicts.push(ServiceConflict::ServiceName {
+                id: svc.id,
+                existing: existing.clone(),
+                new: svc.name.clone(),
+            });
+            continue;
+        }
+
+        for msg in &svc.messages {
+            match message_table.get(&msg.name) {
+                Some(id) if *id == svc.id => conflicts.push(ServiceConflict::DuplicateMessage {
+                    id: svc.id,
+                    message: msg.name.clone(),
+                }),
+                Some(id) => conflicts.push(ServiceConflict::MessageInOtherService {
+                    message: msg.name.clone(),
+                    existing_id: *id,
+                    new_id: svc.id,
+                }),
+                None => {
+                    message_table.insert(msg.name.clone(), svc.id);
+                }
             }
+        }
+    }
+
+    if !conflicts.is_empty() {
+        return Err(conflicts);
+    }
+
+    for mut svc in extra {
+        match base.get_mut(&svc.id) {
+            Some(existing) => existing.messages.append(&mut svc.messages),
             None => {
-                let svc = Service::new(
-                    svc_id,
-                    svc_type,
-                    svc_ver.parse::<i32>().unwrap(),
-                    svc_desc,
-                    msgs,
-                );
-                //println!("{:#?}", svc);
-                ret.insert(svc_id, svc);
+                base.insert(svc.id, svc);
             }
         }
     }
-    ret
+    Ok(())
+}
+
+pub fn load_service_file(path: &str) -> Result<Service, String> {
+    let contents =
+        fs::read_to_string(path).or(Err(format!("Couldn't read service file '{}'", path)))?;
+    parse_service(&contents).map_err(|e| format!("{}: {}", path, e))
+}
+
+// Same as `get_services`, with the service files in `paths` (eg. for a private
+// server) layered on top in order.
+pub fn get_services_with_overrides(paths: &[String]) -> Result<HashMap<u8, Service>, String> {
+    let mut extra = Vec::new();
+    for path in paths {
+        extra.push(load_service_file(path)?);
+    }
+    let mut ret = get_services()?;
+
+    match merge_services(&mut ret, extra) {
+        Ok(()) => Ok(ret),
+        Err(conflicts) => Err(conflicts
+            .iter()
+            .map(|c| c.to_string())
+            .collect::<Vec<String>>()
+            .join("\n")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn service(id: u8, protocol: &str, messages: &[&str]) -> Service {
+        let mut xml = format!(
+            "<Messages><_ProtocolInfo><RECORD>\
+             <ServiceID TYPE=\"UBYT\">{}</ServiceID>\
+             <ProtocolType TYPE=\"STR\">{}</ProtocolType>\
+             <ProtocolVersion TYPE=\"INT\">1</ProtocolVersion>\
+             <ProtocolDescription TYPE=\"STR\">test</ProtocolDescription>\
+             </RECORD></_ProtocolInfo>",
+            id, protocol
+        );
+        for (i, msg) in messages.iter().enumerate() {
+            xml += &format!(
+                "<{0}><RECORD><_MsgName TYPE=\"STR\">{0}</_MsgName>\
+                 <_MsgOrder TYPE=\"UBYT\">{1}</_MsgOrder>\
+                 <Value TYPE=\"INT\"></Value></RECORD></{0}>",
+                msg,
+                i + 1
+            );
+        }
+        xml += "</Messages>";
+        parse_service(&xml).unwrap()
+    }
+
+    fn base() -> HashMap<u8, Service> {
+        let mut base = HashMap::new();
+        base.insert(5, service(5, "GAME", &["MSG_A", "MSG_B"]));
+        base
+    }
+
+    fn message_names(svc: &Service) -> Vec<&str> {
+        svc.messages.iter().map(|m| m.name.as_str()).collect()
+    }
+
+    #[test]
+    fn parses_service_xml() {
+        let svc = service(7, "TEST", &["MSG_X", "MSG_Y"]);
+        assert_eq!(svc.id, 7);
+        assert_eq!(svc.name, "TEST");
+        assert_eq!(message_names(&svc), ["MSG_X", "MSG_Y"]);
+        assert_eq!(svc.messages[0].args.len(), 1);
+        assert_eq!(svc.messages[0].args[0].typename, "INT");
+    }
+
+    #[test]
+    fn appends_to_existing_and_adds_new_services() {
+        let mut services = base();
+        let extra = vec![
+            service(5, "GAME", &["MSG_C"]),
+            service(9, "PRIVATE", &["MSG_P"]),
+        ];
+        merge_services(&mut services, extra).unwrap();
+        assert_eq!(message_names(&services[&5]), ["MSG_A", "MSG_B", "MSG_C"]);
+        assert_eq!(message_names(&services[&9]), ["MSG_P"]);
+    }
+
+    #[test]
+    fn rejects_other_protocol_for_existing_id() {
+        let mut services = base();
+        let err = merge_services(&mut services, vec![service(5, "LOGIN", &["MSG_C"])]).unwrap_err();
+        assert!(matches!(
+            err.as_slice(),
+            [ServiceConflict::ServiceName { id: 5, .. }]
+        ));
+        assert_eq!(message_names(&services[&5]), ["MSG_A", "MSG_B"]);
+    }
+
+    #[test]
+    fn rejects_extra_files_disagreeing_on_new_id() {
+        let mut services = base();
+        let extra = vec![
+            service(9, "PRIVATE", &["MSG_P"]),
+            service(9, "OTHER", &["MSG_Q"]),
+        ];
+        let err = merge_services(&mut services, extra).unwrap_err();
+        assert!(matches!(
+            err.as_slice(),
+            [ServiceConflict::ServiceName { id: 9, .. }]
+        ));
+        assert!(!services.contains_key(&9));
+    }
+
+    #[test]
+    fn merges_extra_files_agreeing_on_new_id() {
+        let mut services = base();
+        let extra = vec![
+            service(9, "PRIVATE", &["MSG_P"]),
+            service(9, "PRIVATE", &["MSG_Q"]),
+        ];
+        merge_services(&mut services, extra).unwrap();
+        assert_eq!(message_names(&services[&9]), ["MSG_P", "MSG_Q"]);
+    }
+
+    #[test]
+    fn rejects_duplicate_messages() {
+        let mut services = base();
+        let err = merge_services(&mut services, vec![service(5, "GAME", &["MSG_B"])]).unwrap_err();
+        assert!(matches!(
+            err.as_slice(),
+            [ServiceConflict::DuplicateMessage { id: 5, .. }]
+        ));
+
+        let extra = vec![service(9, "PRIVATE", &["MSG_A"])];
+        let err = merge_services(&mut services, extra).unwrap_err();
+        assert!(matches!(
+            err.as_slice(),
+            [ServiceConflict::MessageInOtherService {
+                existing_id: 5,
+                new_id: 9,
+                ..
+            }]
+        ));
+        assert_eq!(services.len(), 1);
+    }
 }
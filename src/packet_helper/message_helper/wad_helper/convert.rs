@@ -0,0 +1,302 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{Read, Write};
+use std::path::Path;
+
+use super::{find_files, writer::Writer, Archive};
+
+// Sidecar that keeps what zip/tar can't carry about a wad: its header and which
+// entries were compressed. Converting back with it gives an equivalent wad.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Manifest {
+    pub version: u32,
+    pub flags: u8,
+    pub entries: BTreeMap<String, ManifestEntry>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ManifestEntry {
+    pub compressed: bool,
+}
+
+impl Manifest {
+    pub fn from_archive(archive: &Archive) -> Manifest {
+        Manifest {
+            version: archive.version(),
+            flags: archive.flags(),
+            entries: archive
+                .entries()
+                .iter()
+                .map(|e| {
+                    (
+                        e.name.clone(),
+                        ManifestEntry {
+                            compressed: e.compressed,
+                        },
+                    )
+                })
+                .collect(),
+        }
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Manifest, String> {
+        let path = path.as_ref();
+        let contents = fs::read_to_string(path)
+            .or(Err(format!("Couldn't read manifest '{}'", path.display())))?;
+        serde_json::from_str(&contents)
+            .map_err(|e| format!("Invalid manifest '{}': {}", path.display(), e))
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), String> {
+        let path = path.as_ref();
+        let contents = serde_json::to_string_pretty(self).unwrap();
+        fs::write(path, contents).or(Err(format!("Failed to write file '{}'", path.display())))
+    }
+}
+
+// Writer for a converted wad that takes the header and compression flags from
+// the manifest if there is one
+struct Target<'a> {
+    writer: Writer<'a>,
+    manifest: Option<&'a Manifest>,
+}
+
+impl<'a> Target<'a> {
+    fn new(manifest: Option<&'a Manifest>, level: i32) -> Target<'a> {
+        let mut writer = Writer::new();
+        writer.compression_level(level);
+        if let Some(manifest) = manifest {
+            writer.version(manifest.version).flags(manifest.flags);
+        }
+        Target { writer, manifest }
+    }
+
+    // `compress_default` is used for entries the manifest doesn't know about
+    fn compress(&self, name: &str, compress_default: bool) -> bool {
+        self.manifest
+            .and_then(|m| m.entries.get(name))
+            .map_or(compress_default, |e| e.compressed)
+    }
+
+    fn add(&mut self, name: &str, data: Vec<u8>, compress_default: bool) {
+        let compress = self.compress(name, compress_default);
+        self.writer.add(name, data, compress);
+    }
+
+    fn add_file(&mut self, name: &str, path: &Path, compress_default: bool) {
+        let compress = self.compress(name, compress_default);
+        self.writer.add_file(name, path, compress);
+    }
+}
+
+pub fn wad_to_zip(archive: &mut Archive, out: &Path) -> Result<(), String> {
+    let write_err =
+        |e: zip::result::ZipError| format!("Failed to write to '{}': {}", out.display(), e);
+    let file =
+        fs::File::create(out).or(Err(format!("Failed to create file '{}'", out.display())))?;
+    let mut zip = zip::ZipWriter::new(file);
+
+    for entry in archive.entries().to_vec() {
+        let data = archive.read(&entry)?;
+        // stored wad entries stay stored in the zip, so the zip alone is
+        // enough to get the compression flags back
+        let method = if entry.compressed {
+            zip::CompressionMethod::Deflated
+        } else {
+            zip::CompressionMethod::Stored
+        };
+        let options = zip::write::FileOptions::default()
+            .compression_method(method)
+            .large_file(data.len() as u64 >= u32::MAX as u64);
+        zip.start_file(entry.name.as_str(), options)
+            .map_err(write_err)?;
+        zip.write_all(&data).map_err(|e| write_err(e.into()))?;
+    }
+    zip.finish().map_err(write_err)?;
+    Ok(())
+}
+
+pub fn wad_to_tar(archive: &mut Archive, out: &Path) -> Result<(), String> {
+    let write_err = |e: std::io::Error| format!("Failed to write to '{}': {}", out.display(), e);
+    let file =
+        fs::File::create(out).or(Err(format!("Failed to create file '{}'", out.display())))?;
+    let mut tar = tar::Builder::new(file);
+
+    for entry in archive.entries().to_vec() {
+        let data = archive.read(&entry)?;
+        let mut header = tar::Header::new_gnu();
+        header.set_size(data.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        tar.append_data(&mut header, &entry.name, data.as_slice())
+            .map_err(write_err)?;
+    }
+    tar.into_inner().map_err(write_err)?;
+    Ok(())
+}
+
+pub fn zip_to_wad(
+    path: &Path,
+    out: &Path,
+    manifest: Option<&Manifest>,
+    level: i32,
+) -> Result<(), String> {
+    let read_err = |e: zip::result::ZipError| format!("Couldn't read '{}': {}", path.display(), e);
+    let file = fs::File::open(path).or(Err(format!("Couldn't open '{}'", path.display())))?;
+    let mut zip = zip::ZipArchive::new(file).map_err(read_err)?;
+
+    let mut target = Target::new(manifest, level);
+    for i in 0..zip.len() {
+        let mut file = zip.by_index(i).map_err(read_err)?;
+        if file.is_dir() {
+            continue;
+        }
+        let name = file.name().to_string();
+        let compressed = file.compression() != zip::CompressionMethod::Stored;
+        let mut data = Vec::new();
+        file.read_to_end(&mut data)
+            .map_err(|e| format!("Couldn't read {} from '{}': {}", name, path.display(), e))?;
+        target.add(&name, data, compressed);
+    }
+    target.writer.write(out)
+}
+
+pub fn tar_to_wad(
+    path: &Path,
+    out: &Path,
+    manifest: Option<&Manifest>,
+    level: i32,
+) -> Result<(), String> {
+    let read_err = |e: std::io::Error| format!("Couldn't read '{}': {}", path.display(), e);
+    let file = fs::File::open(path).or(Err(format!("Couldn't open '{}'", path.display())))?;
+    let mut tar = tar::Archive::new(file);
+
+    let mut target = Target::new(manifest, level);
+    for entry in tar.entries().map_err(read_err)? {
+        let mut entry = entry.map_err(read_err)?;
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+        let name = entry
+            .path()
+            .map_err(read_err)?
+            .to_string_lossy()
+            .replace('\\', "/");
+        let mut data = Vec::new();
+        entry.read_to_end(&mut data).map_err(read_err)?;
+        target.add(&name, data, true);
+    }
+    target.writer.write(out)
+}
+
+// Packs every file under `dir` into a wad, named by their path relative to it
+pub fn dir_to_wad(
+    dir: &Path,
+    out: &Path,
+    manifest: Option<&Manifest>,
+    level: i32,
+) -> Result<(), String> {
+    let files = find_files(dir)?;
+
+    let mut target = Target::new(manifest, level);
+    for (name, path) in files {
+        target.add_file(&name, &path, true);
+    }
+    target.writer.write(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::fixtures::{write_wad, ENTRIES};
+    use super::*;
+
+    fn contents(path: &Path) -> Vec<(String, Vec<u8>, bool)> {
+        let mut archive = Archive::open(path).unwrap();
+        archive
+            .entries()
+            .to_vec()
+            .into_iter()
+            .map(|e| {
+                let data = archive.read(&e).unwrap();
+                (e.name, data, e.compressed)
+            })
+            .collect()
+    }
+
+    fn sorted_entries() -> Vec<(String, Vec<u8>, bool)> {
+        let mut entries: Vec<(String, Vec<u8>, bool)> = ENTRIES
+            .iter()
+            .map(|(name, data, compressed)| (name.to_string(), data.to_vec(), *compressed))
+            .collect();
+        entries.sort();
+        entries
+    }
+
+    #[test]
+    fn zip_round_trip_keeps_compression() {
+        let dir = tempfile::tempdir().unwrap();
+        let wad = write_wad(dir.path(), "Test.wad", ENTRIES);
+        let zip = dir.path().join("Test.zip");
+        wad_to_zip(&mut Archive::open(&wad).unwrap(), &zip).unwrap();
+
+        let out = dir.path().join("Out.wad");
+        zip_to_wad(&zip, &out, None, 9).unwrap();
+        assert_eq!(contents(&out), sorted_entries());
+    }
+
+    #[test]
+    fn tar_round_trip_needs_the_manifest() {
+        let dir = tempfile::tempdir().unwrap();
+        let wad = dir.path().join("Test.wad");
+        fs::write(&wad, super::super::fixtures::wad(1, 0, ENTRIES)).unwrap();
+        let mut archive = Archive::open(&wad).unwrap();
+        let manifest_path = dir.path().join("Test.json");
+        Manifest::from_archive(&archive)
+            .save(&manifest_path)
+            .unwrap();
+        let tar = dir.path().join("Test.tar");
+        wad_to_tar(&mut archive, &tar).unwrap();
+
+        // tar has nowhere to keep the flags, so everything gets compressed
+        let out = dir.path().join("Out.wad");
+        tar_to_wad(&tar, &out, None, 9).unwrap();
+        assert!(contents(&out).iter().all(|(_, _, compressed)| *compressed));
+
+        let manifest = Manifest::load(&manifest_path).unwrap();
+        tar_to_wad(&tar, &out, Some(&manifest), 9).unwrap();
+        assert_eq!(contents(&out), sorted_entries());
+        assert_eq!(Archive::open(&out).unwrap().version(), 1);
+
+        fs::write(&manifest_path, "{").unwrap();
+        assert!(Manifest::load(&manifest_path).is_err());
+    }
+
+    #[test]
+    fn packs_directories() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("src");
+        fs::create_dir_all(src.join("GameData")).unwrap();
+        fs::write(src.join("GameData/Foo.xml"), "<Foo/>").unwrap();
+        fs::write(src.join("Top.txt"), "top").unwrap();
+
+        let mut manifest = Manifest {
+            version: 2,
+            flags: 1,
+            entries: BTreeMap::new(),
+        };
+        manifest
+            .entries
+            .insert(String::from("Top.txt"), ManifestEntry { compressed: false });
+        let out = dir.path().join("Out.wad");
+        dir_to_wad(&src, &out, Some(&manifest), 9).unwrap();
+        assert_eq!(
+            contents(&out),
+            [
+                (String::from("GameData/Foo.xml"), b"<Foo/>".to_vec(), true),
+                (String::from("Top.txt"), b"top".to_vec(), false)
+            ]
+        );
+        assert_eq!(Archive::open(&out).unwrap().flags(), 1);
+    }
+}
@@ -0,0 +1,284 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+use std::path::Path;
+
+use super::{writer::Writer, Archive, Entry};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ChangeKind {
+    Added,
+    Removed,
+    // same size, different contents
+    CrcChanged,
+    SizeChanged,
+}
+
+impl fmt::Display for ChangeKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            ChangeKind::Added => "added",
+            ChangeKind::Removed => "removed",
+            ChangeKind::CrcChanged => "crc changed",
+            ChangeKind::SizeChanged => "size changed",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct EntryChange {
+    pub name: String,
+    pub kind: ChangeKind,
+    pub old: Option<Entry>,
+    pub new: Option<Entry>,
+}
+
+#[derive(Debug, Default)]
+pub struct WadDiff {
+    pub changes: Vec<EntryChange>,
+}
+
+impl WadDiff {
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty()
+    }
+
+    // Changes to any of `names`, eg. the entries a mod overrides
+    pub fn touching<S: AsRef<str>>(&self, names: &[S]) -> Vec<&EntryChange> {
+        self.changes
+            .iter()
+            .filter(|c| names.iter().any(|n| n.as_ref() == c.name))
+            .collect()
+    }
+}
+
+// Compares the file tables of two wads. Contents are compared by size and crc,
+// so nothing gets inflated; an entry that was only recompressed isn't a change.
+pub fn diff(old: &Archive, new: &Archive) -> WadDiff {
+    let mut changes = Vec::new();
+
+    for old_entry in old.entries() {
+        let kind = match new.entry(&old_entry.name) {
+            None => ChangeKind::Removed,
+            Some(e) if e.size != old_entry.size => ChangeKind::SizeChanged,
+            Some(e) if e.crc != old_entry.crc => ChangeKind::CrcChanged,
+            Some(_) => continue,
+        };
+        changes.push(EntryChange {
+            name: old_entry.name.clone(),
+            kind,
+            old: Some(old_entry.clone()),
+            new: new.entry(&old_entry.name).cloned(),
+        });
+    }
+
+    for new_entry in new.entries() {
+        if old.entry(&new_entry.name).is_none() {
+            changes.push(EntryChange {
+                name: new_entry.name.clone(),
+                kind: ChangeKind::Added,
+                old: None,
+                new: Some(new_entry.clone()),
+            });
+        }
+    }
+
+    changes.sort_by(|a, b| a.name.cmp(&b.name));
+    WadDiff { changes }
+}
+
+// Entry in a delta package describing everything but the changed entries
+// themselves, which are stored next to it as they are in the new wad
+const DELTA_INFO: &str = "__wad_delta__.json";
+
+#[derive(Debug, Serialize, Deserialize)]
+struct DeltaInfo {
+    version: u32,
+    flags: u8,
+    removed: Vec<String>,
+    // crc of every entry of the old wad, a delta only applies to that wad
+    base: BTreeMap<String, u32>,
+}
+
+// Writes a package (itself a wad) with the added and changed entries of `new`
+// copied as is, plus the list of removed ones. `apply_delta` turns the old wad
+// back into the new one with it.
+pub fn write_delta(
+    diff: &WadDiff,
+    old: &Archive,
+    new: &mut Archive,
+    out: &Path,
+) -> Result<(), String> {
+    let info = DeltaInfo {
+        version: new.version(),
+        flags: new.flags(),
+        base: old
+            .entries()
+            .iter()
+            .map(|e| (e.name.clone(), e.crc))
+            .collect(),
+        removed: diff
+            .changes
+            .iter()
+            .filter(|c| c.kind == ChangeKind::Removed)
+            .map(|c| c.name.clone())
+            .collect(),
+    };
+
+    let changed: HashSet<&str> = diff.changes.iter().map(|c| c.name.as_str()).collect();
+    let mut writer = Writer::from_archive(new);
+    writer.retain(|name| changed.contains(name));
+    writer.add(DELTA_INFO, serde_json::to_vec(&info).unwrap(), true);
+    writer.write(out)
+}
+
+pub fn apply_delta(old: &mut Archive, delta: &Path, out: &Path) -> Result<(), String> {
+    let mut delta = Archive::open(delta)?;
+    let info: DeltaInfo = serde_json::from_slice(&delta.read_file(DELTA_INFO)?)
+        .map_err(|e| format!("Invalid delta package: {}", e))?;
+
+    // applied to any other wad the result would be garbage
+    for entry in old.entries() {
+        match info.base.get(&entry.name) {
+            Some(crc) if *crc == entry.crc => {}
+            Some(_) => {
+                return Err(format!(
+                    "{} doesn't match the wad the delta was made from",
+                    entry.name
+                ))
+            }
+            None => {
+                return Err(format!(
+                    "{} isn't in the wad the delta was made from",
+                    entry.name
+                ))
+            }
+        }
+    }
+    if let Some(name) = info.base.keys().find(|name| old.entry(name).is_none()) {
+        return Err(format!(
+            "{} from the wad the delta was made from is missing",
+            name
+        ));
+    }
+
+    let mut changed = Vec::new();
+    for entry in delta.entries().to_vec() {
+        if entry.name != DELTA_INFO {
+            let stored = delta.read_raw(&entry)?;
+            changed.push((entry, stored));
+        }
+    }
+
+    let mut writer = Writer::from_archive(old);
+    writer.version(info.version).flags(info.flags);
+    let removed: HashSet<&str> = info.removed.iter().map(|n| n.as_str()).collect();
+    writer.retain(|name| !removed.contains(name));
+    for (entry, stored) in changed {
+        writer.add_raw(entry, stored);
+    }
+    writer.write(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::fixtures::{write_wad, ENTRIES};
+    use super::*;
+    use std::fs;
+
+    type Entries<'a> = &'a [(&'a str, &'a [u8], bool)];
+
+    const NEW: &[(&str, &[u8], bool)] = &[
+        (
+            "GameData/Foo.xml",
+            b"<Foo>changed changed changed</Foo>",
+            true,
+        ),
+        ("GameData/Bar.txt", b"plain text, longer", false),
+        ("GameData/New.xml", b"<New/>", true),
+    ];
+
+    #[test]
+    fn lists_changes_by_name() {
+        let dir = tempfile::tempdir().unwrap();
+        let old = Archive::open(write_wad(dir.path(), "Old.wad", ENTRIES)).unwrap();
+        let new = Archive::open(write_wad(dir.path(), "New.wad", NEW)).unwrap();
+
+        let changes = diff(&old, &new);
+        let kinds: Vec<(&str, ChangeKind)> = changes
+            .changes
+            .iter()
+            .map(|c| (c.name.as_str(), c.kind))
+            .collect();
+        assert_eq!(
+            kinds,
+            [
+                ("GameData/Bar.txt", ChangeKind::SizeChanged),
+                ("GameData/Foo.xml", ChangeKind::CrcChanged),
+                ("GameData/New.xml", ChangeKind::Added),
+                ("Locale/English/Items.lang", ChangeKind::Removed),
+            ]
+        );
+        assert!(changes.changes[2].old.is_none());
+        assert!(changes.changes[3].new.is_none());
+
+        let touched = changes.touching(&["GameData/Foo.xml", "GameData/Other.xml"]);
+        assert_eq!(touched.len(), 1);
+        assert_eq!(touched[0].name, "GameData/Foo.xml");
+        assert!(changes.touching(&["GameData/Other.xml"]).is_empty());
+
+        assert!(!changes.is_empty());
+        assert!(diff(&old, &old).is_empty());
+    }
+
+    #[test]
+    fn delta_turns_old_into_new() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut old = Archive::open(write_wad(dir.path(), "Old.wad", ENTRIES)).unwrap();
+        let mut new = Archive::open(write_wad(dir.path(), "New.wad", NEW)).unwrap();
+        let delta = dir.path().join("delta.wad");
+        write_delta(&diff(&old, &new), &old, &mut new, &delta).unwrap();
+
+        let out = dir.path().join("Out.wad");
+        apply_delta(&mut old, &delta, &out).unwrap();
+        let mut patched = Archive::open(&out).unwrap();
+        assert!(diff(&patched, &new).is_empty());
+        for (name, contents, _) in NEW {
+            assert_eq!(patched.read_file(name).unwrap(), *contents);
+        }
+        assert!(patched.entry("Locale/English/Items.lang").is_none());
+    }
+
+    #[test]
+    fn delta_refuses_other_wads() {
+        let dir = tempfile::tempdir().unwrap();
+        let old = Archive::open(write_wad(dir.path(), "Old.wad", ENTRIES)).unwrap();
+        let mut new = Archive::open(write_wad(dir.path(), "New.wad", NEW)).unwrap();
+        let delta = dir.path().join("delta.wad");
+        write_delta(&diff(&old, &new), &old, &mut new, &delta).unwrap();
+        let out = dir.path().join("Out.wad");
+
+        let mut changed = ENTRIES.to_vec();
+        changed[1] = ("GameData/Bar.txt", b"other text", false);
+        let extra = [ENTRIES, &[("Extra.txt", b"extra", false)]].concat();
+        let cases: [(Entries, &str); 3] = [
+            (&changed, "GameData/Bar.txt doesn't match"),
+            (&extra, "Extra.txt isn't in"),
+            (&ENTRIES[..2], "Locale/English/Items.lang from"),
+        ];
+        for (entries, err) in cases {
+            let mut other = Archive::open(write_wad(dir.path(), "Other.wad", entries)).unwrap();
+            let e = apply_delta(&mut other, &delta, &out).unwrap_err();
+            assert!(e.starts_with(err), "{}", e);
+            assert!(!out.exists());
+        }
+
+        // the delta itself has to be one
+        let not_delta = write_wad(dir.path(), "NotDelta.wad", ENTRIES);
+        let mut old = Archive::open(dir.path().join("Old.wad")).unwrap();
+        assert!(apply_delta(&mut old, &not_delta, &out).is_err());
+        fs::write(&not_delta, "junk").unwrap();
+        assert!(apply_delta(&mut old, &not_delta, &out).is_err());
+    }
+}
@@ -0,0 +1,189 @@
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+
+use super::{query::Query, Archive, Entry};
+
+// Where `name` ends up under `dest`. Entry names come from the wad, so anything
+// that could land outside of `dest` (`..`, absolute paths, drive letters) is
+// refused rather than cleaned up.
+pub fn entry_path(dest: &Path, name: &str) -> Result<PathBuf, String> {
+    let mut path = dest.to_path_buf();
+    if name.is_empty() || name.starts_with('/') || name.starts_with('\\') {
+        return Err(format!("Refusing to extract absolute path '{}'", name));
+    }
+    for part in name.split(['/', '\\']) {
+        match Path::new(part).components().next() {
+            _ if part.contains(':') => {
+                return Err(format!(
+                    "Refusing to extract '{}', it has a drive or stream in it",
+                    name
+                ))
+            }
+            Some(Component::Normal(_)) => path.push(part),
+            None | Some(Component::CurDir) => continue,
+            Some(_) => {
+                return Err(format!(
+                    "Refusing to extract '{}', it leaves the target directory",
+                    name
+                ))
+            }
+        }
+    }
+    Ok(path)
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct ExtractOptions {
+    // entries have to match one of these, all of them if there are none
+    pub include: Vec<Query>,
+    // and none of these
+    pub exclude: Vec<Query>,
+    // worker count, 0 for one per core
+    pub threads: usize,
+}
+
+impl ExtractOptions {
+    pub fn selects(&self, entry: &Entry) -> bool {
+        (self.include.is_empty() || self.include.iter().any(|q| q.matches(entry)))
+            && !self.exclude.iter().any(|q| q.matches(entry))
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct ExtractReport {
+    pub extracted: usize,
+    // entry name and why it wasn't extracted
+    pub failed: Vec<(String, String)>,
+}
+
+// Inflates the selected entries of `archive` into `dest`, keeping the wad's
+// directory layout. Each worker reads through its own handle on the wad.
+pub fn extract(
+    archive: &Archive,
+    dest: &Path,
+    options: &ExtractOptions,
+) -> Result<ExtractReport, String> {
+    let entries: Vec<&Entry> = archive
+        .entries()
+        .iter()
+        .filter(|e| options.selects(e))
+        .collect();
+
+    let threads = match options.threads {
+        0 => thread::available_parallelism().map_or(1, |n| n.get()),
+        n => n,
+    }
+    .min(entries.len().max(1));
+
+    let next = AtomicUsize::new(0);
+    let report = Mutex::new(ExtractReport::default());
+
+    thread::scope(|scope| -> Result<(), String> {
+        let mut workers = Vec::new();
+        for _i in 0..threads {
+            let mut handle = archive.try_clone()?;
+            let (next, report, entries) = (&next, &report, &entries);
+            workers.push(scope.spawn(move || loop {
+                let i = next.fetch_add(1, Ordering::Relaxed);
+                let entry = match entries.get(i) {
+                    Some(e) => *e,
+                    None => break,
+                };
+
+                let res =
+                    entry_path(dest, &entry.name).and_then(|path| handle.extract(entry, path));
+                let mut report = report.lock().unwrap();
+                match res {
+                    Ok(()) => report.extracted += 1,
+                    Err(e) => report.failed.push((entry.name.clone(), e)),
+                }
+            }));
+        }
+        for worker in workers {
+            worker
+                .join()
+                .or(Err(String::from("Extraction worker panicked")))?;
+        }
+        Ok(())
+    })?;
+
+    let mut report = report.into_inner().unwrap();
+    report.failed.sort();
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::fixtures::{write_wad, ENTRIES};
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn maps_entry_names_under_dest() {
+        let dest = Path::new("out");
+        assert_eq!(
+            entry_path(dest, "GameData/Foo.xml").unwrap(),
+            dest.join("GameData").join("Foo.xml")
+        );
+        assert_eq!(
+            entry_path(dest, "GameData\\./Sub//Foo.xml").unwrap(),
+            dest.join("GameData").join("Sub").join("Foo.xml")
+        );
+    }
+
+    #[test]
+    fn refuses_names_leaving_dest() {
+        let dest = Path::new("out");
+        for name in [
+            "",
+            "/etc/passwd",
+            "\\Windows\\evil.dll",
+            "../evil",
+            "GameData/../../evil",
+            "C:/evil",
+            "GameData/Foo.xml:stream",
+        ] {
+            assert!(entry_path(dest, name).is_err(), "{} was allowed", name);
+        }
+    }
+
+    #[test]
+    fn extracts_selected_entries() {
+        let dir = tempfile::tempdir().unwrap();
+        let wad = write_wad(dir.path(), "Test.wad", ENTRIES);
+        let archive = Archive::open(wad).unwrap();
+        let dest = dir.path().join("out");
+        let options = ExtractOptions {
+            include: vec![Query::new().glob("GameData/*").unwrap()],
+            exclude: vec![Query::new().glob("*/*.txt").unwrap()],
+            threads: 4,
+        };
+        let report = extract(&archive, &dest, &options).unwrap();
+        assert_eq!(report.extracted, 1);
+        assert!(report.failed.is_empty());
+        assert_eq!(
+            fs::read(dest.join("GameData/Foo.xml")).unwrap(),
+            ENTRIES[0].1
+        );
+        assert!(!dest.join("GameData/Bar.txt").exists());
+    }
+
+    #[test]
+    fn extracts_everything_and_reports_failures() {
+        let dir = tempfile::tempdir().unwrap();
+        let entries = [ENTRIES, &[("../evil.txt", b"evil", false)]].concat();
+        let wad = write_wad(dir.path(), "Test.wad", &entries);
+        let archive = Archive::open(wad).unwrap();
+        let dest = dir.path().join("out");
+        let report = extract(&archive, &dest, &Default::default()).unwrap();
+        assert_eq!(report.extracted, ENTRIES.len());
+        assert_eq!(report.failed.len(), 1);
+        assert_eq!(report.failed[0].0, "../evil.txt");
+        assert!(!dir.path().join("evil.txt").exists());
+        for (name, contents, _) in ENTRIES {
+            assert_eq!(fs::read(dest.join(name)).unwrap(), *contents);
+        }
+    }
+}
@@ -0,0 +1,70 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use super::{crc, UNCOMPRESSED_ZIP_SIZE};
+
+// Small KIWADs for the tests, laid out byte by byte rather than through the
+// writer so the reader and writer can be checked against each other.
+// Entries are (name, contents, compressed).
+pub fn wad(version: u32, flags: u8, entries: &[(&str, &[u8], bool)]) -> Vec<u8> {
+    let mut out = b"KIWAD".to_vec();
+    out.extend(version.to_le_bytes());
+    out.extend((entries.len() as u32).to_le_bytes());
+    if version >= 2 {
+        out.push(flags);
+    }
+
+    let table_size: usize = entries.iter().map(|(name, _, _)| 22 + name.len()).sum();
+    let mut offset = out.len() + table_size;
+    let mut data = Vec::new();
+    for (name, contents, compressed) in entries {
+        let stored = if *compressed {
+            let mut compressor = libdeflater::Compressor::new(Default::default());
+            let mut buf = vec![0; compressor.zlib_compress_bound(contents.len())];
+            let len = compressor.zlib_compress(contents, &mut buf).unwrap();
+            buf.truncate(len);
+            buf
+        } else {
+            contents.to_vec()
+        };
+        let zip_size = if *compressed {
+            stored.len() as u32
+        } else {
+            UNCOMPRESSED_ZIP_SIZE
+        };
+
+        out.extend((offset as u32).to_le_bytes());
+        out.extend((contents.len() as u32).to_le_bytes());
+        out.extend(zip_size.to_le_bytes());
+        out.push(*compressed as u8);
+        out.extend(crc(contents).to_le_bytes());
+        out.extend((name.len() as u32 + 1).to_le_bytes());
+        out.extend(name.as_bytes());
+        out.push(0);
+
+        offset += stored.len();
+        data.extend(stored);
+    }
+    out.extend(data);
+    out
+}
+
+// A v2 wad written to `dir/name`
+pub fn write_wad(dir: &Path, name: &str, entries: &[(&str, &[u8], bool)]) -> PathBuf {
+    let path = dir.join(name);
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).unwrap();
+    }
+    fs::write(&path, wad(2, 0, entries)).unwrap();
+    path
+}
+
+pub const ENTRIES: &[(&str, &[u8], bool)] = &[
+    (
+        "GameData/Foo.xml",
+        b"<Foo>hello hello hello hello</Foo>",
+        true,
+    ),
+    ("GameData/Bar.txt", b"plain text", false),
+    ("Locale/English/Items.lang", b"", false),
+];
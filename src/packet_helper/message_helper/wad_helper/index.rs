@@ -0,0 +1,318 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
+use std::path::Path;
+use std::time::UNIX_EPOCH;
+
+use super::{file_crc, find_wads, Archive};
+
+// Bumped whenever the layout or tokenizing changes, older indexes get rebuilt
+const INDEX_VERSION: u32 = 1;
+
+const TEXT_EXTENSIONS: [&str; 12] = [
+    "xml", "txt", "lang", "lua", "ini", "cfg", "json", "csv", "htm", "html", "js", "css",
+];
+
+// Anything bigger isn't worth inflating for the index
+const MAX_TEXT_SIZE: u32 = 64 << 20;
+
+pub fn is_text_entry(name: &str) -> bool {
+    match name.rsplit_once('.') {
+        Some((_, ext)) => TEXT_EXTENSIONS.iter().any(|e| e.eq_ignore_ascii_case(ext)),
+        None => false,
+    }
+}
+
+// Entries are UTF-8 unless they have a UTF-16 BOM, like the .lang tables
+pub fn decode_text(data: &[u8]) -> String {
+    let utf16 = |rest: &[u8], from_bytes: fn([u8; 2]) -> u16| {
+        let units: Vec<u16> = rest
+            .chunks_exact(2)
+            .map(|c| from_bytes([c[0], c[1]]))
+            .collect();
+        String::from_utf16_lossy(&units)
+    };
+    match data {
+        [0xFF, 0xFE, rest @ ..] => utf16(rest, u16::from_le_bytes),
+        [0xFE, 0xFF, rest @ ..] => utf16(rest, u16::from_be_bytes),
+        _ => String::from_utf8_lossy(data).into_owned(),
+    }
+}
+
+// Words of letters, digits and underscores, so ids like `MSG_USER_AUTHEN_V3`
+// stay in one piece. Matching is case insensitive.
+pub fn tokens(text: &str) -> Vec<String> {
+    let mut seen = HashSet::new();
+    text.split(|c: char| !(c.is_alphanumeric() || c == '_'))
+        .filter(|t| t.len() >= 2)
+        .map(|t| t.to_lowercase())
+        .filter(|t| seen.insert(t.clone()))
+        .collect()
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct WadIndex {
+    size: u64,
+    modified: u64,
+    crc: u32,
+    entries: Vec<String>,
+    // token -> (entry, line) it's on, lines start at 1
+    postings: HashMap<String, Vec<(u32, u32)>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct SearchHit {
+    pub wad: String,
+    pub entry: String,
+    pub line: u32,
+}
+
+#[derive(Debug, Default)]
+pub struct UpdateReport {
+    pub indexed: Vec<String>,
+    pub removed: Vec<String>,
+    pub unchanged: usize,
+    // wad and entry, and why it couldn't be indexed
+    pub failed: Vec<(String, String, String)>,
+}
+
+// Inverted index over the text entries of every wad in a game dir. Each wad
+// has its own postings, so a changed wad is reindexed without touching the
+// rest.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ContentIndex {
+    version: u32,
+    wads: BTreeMap<String, WadIndex>,
+}
+
+impl Default for ContentIndex {
+    fn default() -> Self {
+        ContentIndex {
+            version: INDEX_VERSION,
+            wads: BTreeMap::new(),
+        }
+    }
+}
+
+impl ContentIndex {
+    pub fn new() -> ContentIndex {
+        Default::default()
+    }
+
+    // A missing or outdated index file gives an empty index
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<ContentIndex, String> {
+        let path = path.as_ref();
+        if !path.exists() {
+            return Ok(ContentIndex::new());
+        }
+        let contents =
+            fs::read(path).or(Err(format!("Couldn't read index '{}'", path.display())))?;
+        let index: ContentIndex = serde_json::from_slice(&contents)
+            .map_err(|e| format!("Invalid index '{}': {}", path.display(), e))?;
+        if index.version != INDEX_VERSION {
+            return Ok(ContentIndex::new());
+        }
+        Ok(index)
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), String> {
+        let path = path.as_ref();
+        let contents = serde_json::to_vec(self).unwrap();
+        fs::write(path, contents).or(Err(format!("Failed to write file '{}'", path.display())))
+    }
+
+    // Brings the index up to date with the wads under `game_dir`. Wads whose
+    // size and modification time didn't change are skipped, otherwise their
+    // crc decides whether they get reindexed.
+    pub fn update<P: AsRef<Path>>(&mut self, game_dir: P) -> Result<UpdateReport, String> {
+        let mut report = UpdateReport::default();
+        let mut found = HashSet::new();
+
+        for path in find_wads(game_dir)? {
+            let key = path.to_string_lossy().into_owned();
+            found.insert(key.clone());
+
+            let meta =
+                fs::metadata(&path).or(Err(format!("Couldn't read '{}'", path.display())))?;
+            let size = meta.len();
+            let modified = meta
+                .modified()
+                .ok()
+                .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+                .map_or(0, |d| d.as_secs());
+
+            if let Some(wad) = self.wads.get_mut(&key) {
+                if wad.size == size && wad.modified == modified {
+                    report.unchanged += 1;
+                    continue;
+                }
+            }
+            let crc = file_crc(&path)?;
+            if let Some(wad) = self.wads.get_mut(&key) {
+                if wad.crc == crc {
+                    wad.size = size;
+                    wad.modified = modified;
+                    report.unchanged += 1;
+                    continue;
+                }
+            }
+
+            // a wad that can't be opened is left out until it's fixed
+            let mut wad = match index_wad(&path, &key, &mut report) {
+                Ok(wad) => wad,
+                Err(e) => {
+                    self.wads.remove(&key);
+                    report.failed.push((key, String::new(), e));
+                    continue;
+                }
+            };
+            wad.size = size;
+            wad.modified = modified;
+            wad.crc = crc;
+            self.wads.insert(key.clone(), wad);
+            report.indexed.push(key);
+        }
+
+        self.wads.retain(|key, _| {
+            let keep = found.contains(key);
+            if !keep {
+                report.removed.push(key.clone());
+            }
+            keep
+        });
+        Ok(report)
+    }
+
+    // Lines that have every word of `query` on them. Fails if the index
+    // points at entries it doesn't have, which only a damaged or hand edited
+    // index file does.
+    pub fn search(&self, query: &str) -> Result<Vec<SearchHit>, String> {
+        let words = tokens(query);
+        let mut hits = Vec::new();
+        if words.is_empty() {
+            return Ok(hits);
+        }
+
+        for (key, wad) in &self.wads {
+            let mut lines: Option<HashSet<(u32, u32)>> = None;
+            for word in &words {
+                let postings: HashSet<(u32, u32)> = match wad.postings.get(word) {
+                    Some(p) => p.iter().copied().collect(),
+                    None => HashSet::new(),
+                };
+                lines = Some(match lines {
+                    Some(l) => l.intersection(&postings).copied().collect(),
+                    None => postings,
+                });
+            }
+
+            for (entry, line) in lines.unwrap_or_default() {
+                let name = wad.entries.get(entry as usize).ok_or(format!(
+                    "Index of '{}' refers to entry {} of {}, rebuild it",
+                    key,
+                    entry,
+                    wad.entries.len()
+                ))?;
+                hits.push(SearchHit {
+                    wad: key.clone(),
+                    entry: name.clone(),
+                    line,
+                });
+            }
+        }
+        hits.sort();
+        Ok(hits)
+    }
+}
+
+fn index_wad(path: &Path, key: &str, report: &mut UpdateReport) -> Result<WadIndex, String> {
+    let mut archive = Archive::open(path)?;
+    let mut wad = WadIndex::default();
+
+    for entry in archive.entries().to_vec() {
+        if !is_text_entry(&entry.name) || entry.size > MAX_TEXT_SIZE {
+            continue;
+        }
+        let data = match archive.read(&entry) {
+            Ok(d) => d,
+            Err(e) => {
+                report.failed.push((key.to_string(), entry.name, e));
+                continue;
+            }
+        };
+
+        let id = wad.entries.len() as u32;
+        for (i, line) in decode_text(&data).lines().enumerate() {
+            for token in tokens(line) {
+                wad.postings
+                    .entry(token)
+                    .or_default()
+                    .push((id, i as u32 + 1));
+            }
+        }
+        wad.entries.push(entry.name);
+    }
+    Ok(wad)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::fixtures::{write_wad, ENTRIES};
+    use super::*;
+
+    #[test]
+    fn tokenizes_words() {
+        assert_eq!(
+            tokens("<MSG_USER_AUTHEN_V3 a=\"Foo\"/> foo x"),
+            ["msg_user_authen_v3", "foo"]
+        );
+        assert!(is_text_entry("GameData/Foo.XML"));
+        assert!(!is_text_entry("Textures/Foo.dds"));
+        assert_eq!(decode_text(&[0xFF, 0xFE, b'h', 0, b'i', 0]), "hi");
+    }
+
+    #[test]
+    fn indexes_and_searches() {
+        let dir = tempfile::tempdir().unwrap();
+        let wad = write_wad(dir.path(), "Root.wad", ENTRIES);
+        let key = wad.to_string_lossy().into_owned();
+
+        let mut index = ContentIndex::new();
+        let report = index.update(dir.path()).unwrap();
+        assert_eq!(report.indexed, std::slice::from_ref(&key));
+        assert_eq!(
+            index.search("HELLO foo").unwrap(),
+            [SearchHit {
+                wad: key.clone(),
+                entry: String::from("GameData/Foo.xml"),
+                line: 1,
+            }]
+        );
+        assert_eq!(index.search("plain").unwrap()[0].entry, "GameData/Bar.txt");
+        assert!(index.search("hello plain").unwrap().is_empty());
+        assert!(index.search("").unwrap().is_empty());
+
+        // saved and loaded again nothing needs reindexing
+        let path = dir.path().join("index.json");
+        index.save(&path).unwrap();
+        let mut index = ContentIndex::load(&path).unwrap();
+        assert_eq!(index.update(dir.path()).unwrap().unchanged, 1);
+
+        fs::remove_file(&wad).unwrap();
+        assert_eq!(index.update(dir.path()).unwrap().removed, [key]);
+        assert!(index.search("hello").unwrap().is_empty());
+    }
+
+    #[test]
+    fn stale_entries_are_an_error() {
+        let dir = tempfile::tempdir().unwrap();
+        write_wad(dir.path(), "Root.wad", ENTRIES);
+        let mut index = ContentIndex::new();
+        index.update(dir.path()).unwrap();
+        for wad in index.wads.values_mut() {
+            wad.entries.clear();
+        }
+        let err = index.search("hello").unwrap_err();
+        assert!(err.contains("rebuild"), "{}", err);
+    }
+}
@@ -0,0 +1,357 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use super::{find_wads, Archive};
+
+// Locale string tables (Locale/<language>/*.lang). The first line is the
+// section name, then every string takes three lines: its key, a comment
+// (mostly empty) and the text. The client writes them as UTF-16LE with a BOM.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Encoding {
+    Utf16Le,
+    Utf16Be,
+    Utf8,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LangEntry {
+    pub comment: String,
+    pub value: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct LangFile {
+    pub section: String,
+    pub encoding: Encoding,
+    // how the file was laid out, so a table that wasn't edited writes back
+    // the bytes it was read from
+    bom: bool,
+    crlf: bool,
+    trailing_newline: bool,
+    // in file order, a key can show up more than once
+    entries: Vec<(String, LangEntry)>,
+    // key -> its last entry, the one the client uses
+    index: HashMap<String, usize>,
+}
+
+fn decode_utf16(data: &[u8], from_bytes: fn([u8; 2]) -> u16) -> Result<String, String> {
+    if !data.len().is_multiple_of(2) {
+        return Err(String::from("UTF-16 text with an odd number of bytes"));
+    }
+    let units: Vec<u16> = data
+        .chunks_exact(2)
+        .map(|c| from_bytes([c[0], c[1]]))
+        .collect();
+    String::from_utf16(&units).or(Err(String::from("Invalid UTF-16 text")))
+}
+
+fn check_line(s: &str) -> Result<(), String> {
+    if s.contains(['\r', '\n']) {
+        return Err(format!("'{}' spans more than one line", s.escape_debug()));
+    }
+    Ok(())
+}
+
+impl LangFile {
+    pub fn parse(data: &[u8]) -> Result<LangFile, String> {
+        let (encoding, bom, text) = match data {
+            [0xFF, 0xFE, rest @ ..] => (
+                Encoding::Utf16Le,
+                true,
+                decode_utf16(rest, u16::from_le_bytes)?,
+            ),
+            [0xFE, 0xFF, rest @ ..] => (
+                Encoding::Utf16Be,
+                true,
+                decode_utf16(rest, u16::from_be_bytes)?,
+            ),
+            _ => {
+                let rest = data.strip_prefix(b"\xEF\xBB\xBF");
+                let text = std::str::from_utf8(rest.unwrap_or(data)).or(Err(String::from(
+                    "Lang file is neither UTF-16 with a BOM nor UTF-8",
+                )))?;
+                (Encoding::Utf8, rest.is_some(), text.to_string())
+            }
+        };
+
+        // line endings are taken to be those of the first line
+        let crlf = text.find('\n').is_some_and(|i| text[..i].ends_with('\r'));
+        let mut lines: Vec<&str> = text
+            .split('\n')
+            .map(|l| l.strip_suffix('\r').unwrap_or(l))
+            .collect();
+        let trailing_newline = lines.len() > 1 && lines.last() == Some(&"");
+        if trailing_newline {
+            lines.pop();
+        }
+
+        let section = match lines.first() {
+            Some(s) if !text.is_empty() => s.to_string(),
+            _ => return Err(String::from("Lang file is empty")),
+        };
+        let body = &lines[1..];
+        if !body.len().is_multiple_of(3) {
+            return Err(format!(
+                "Lang file has a truncated entry at line {}",
+                body.len() / 3 * 3 + 2
+            ));
+        }
+
+        let mut lang = LangFile {
+            section,
+            encoding,
+            bom,
+            crlf,
+            trailing_newline,
+            entries: Vec::new(),
+            index: HashMap::new(),
+        };
+        for e in body.chunks_exact(3) {
+            lang.push(
+                e[0],
+                LangEntry {
+                    comment: e[1].to_string(),
+                    value: e[2].to_string(),
+                },
+            );
+        }
+        Ok(lang)
+    }
+
+    fn push(&mut self, key: &str, entry: LangEntry) {
+        // a key that shows up twice keeps its last text, like in the client
+        self.index.insert(key.to_string(), self.entries.len());
+        self.entries.push((key.to_string(), entry));
+    }
+
+    // Writes the table back in the encoding and layout it was read in
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let newline = if self.crlf { "\r\n" } else { "\n" };
+        let mut lines = vec![self.section.as_str()];
+        for (key, entry) in &self.entries {
+            lines.extend([key.as_str(), entry.comment.as_str(), entry.value.as_str()]);
+        }
+        let mut text = lines.join(newline);
+        if self.trailing_newline {
+            text.push_str(newline);
+        }
+
+        let bom: &[u8] = match (self.bom, self.encoding) {
+            (false, _) => &[],
+            (true, Encoding::Utf16Le) => &[0xFF, 0xFE],
+            (true, Encoding::Utf16Be) => &[0xFE, 0xFF],
+            (true, Encoding::Utf8) => b"\xEF\xBB\xBF",
+        };
+        let mut out = bom.to_vec();
+        match self.encoding {
+            Encoding::Utf16Le => out.extend(text.encode_utf16().flat_map(|u| u.to_le_bytes())),
+            Encoding::Utf16Be => out.extend(text.encode_utf16().flat_map(|u| u.to_be_bytes())),
+            Encoding::Utf8 => out.extend(text.into_bytes()),
+        }
+        out
+    }
+
+    // Every key with the entry the client uses for it, in file order
+    pub fn entries(&self) -> impl Iterator<Item = (&String, &LangEntry)> {
+        self.entries
+            .iter()
+            .enumerate()
+            .filter(|(i, (key, _))| self.index[key] == *i)
+            .map(|(_, (key, entry))| (key, entry))
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.index
+            .get(key)
+            .map(|i| self.entries[*i].1.value.as_str())
+    }
+
+    // Id the rest of the game refers to the string by, `<section>_<key>`
+    pub fn id(&self, key: &str) -> String {
+        format!("{}_{}", self.section, key)
+    }
+
+    // Sets the text of `key`, adding it to the end if it doesn't exist.
+    // Comments of existing entries are kept.
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        check_line(key)?;
+        check_line(value)?;
+        match self.index.get(key) {
+            Some(i) => self.entries[*i].1.value = value.to_string(),
+            None => self.push(
+                key,
+                LangEntry {
+                    comment: String::new(),
+                    value: value.to_string(),
+                },
+            ),
+        }
+        Ok(())
+    }
+
+    // Removes every entry of `key`, returning the one the client used
+    pub fn remove(&mut self, key: &str) -> Option<LangEntry> {
+        let last = self.index.remove(key)?;
+        let removed = self.entries[last].1.clone();
+        self.entries.retain(|(k, _)| k != key);
+        self.index = self
+            .entries
+            .iter()
+            .enumerate()
+            .map(|(i, (k, _))| (k.clone(), i))
+            .collect();
+        Some(removed)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct LangMatch {
+    pub wad: PathBuf,
+    pub entry: String,
+    pub key: String,
+    pub value: String,
+}
+
+// What a lookup found, and the wads it couldn't search
+#[derive(Debug, Default)]
+pub struct LangLookup {
+    pub matches: Vec<LangMatch>,
+    pub unreadable: Vec<(PathBuf, String)>,
+}
+
+// Looks `id` up in every .lang table of every wad under `game_dir`. `id` is
+// either `<section>_<key>` or just the key.
+pub fn lookup<P: AsRef<Path>>(game_dir: P, id: &str) -> Result<LangLookup, String> {
+    let mut found = LangLookup::default();
+    for wad in find_wads(game_dir)? {
+        let mut archive = match Archive::open(&wad) {
+            Ok(archive) => archive,
+            Err(e) => {
+                found.unreadable.push((wad, e));
+                continue;
+            }
+        };
+        for entry in archive.entries().to_vec() {
+            if !entry.name.ends_with(".lang") {
+                continue;
+            }
+            // tables that don't parse can't hold the id either
+            let lang = match archive.read(&entry).and_then(|d| LangFile::parse(&d)) {
+                Ok(lang) => lang,
+                Err(_) => continue,
+            };
+            let prefix = format!("{}_", lang.section);
+            let keys = [Some(id), id.strip_prefix(&prefix)];
+            for key in keys.into_iter().flatten() {
+                if let Some(value) = lang.get(key) {
+                    found.matches.push(LangMatch {
+                        wad: wad.clone(),
+                        entry: entry.name.clone(),
+                        key: key.to_string(),
+                        value: value.to_string(),
+                    });
+                }
+            }
+        }
+    }
+    Ok(found)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::fixtures::{write_wad, ENTRIES};
+    use super::*;
+    use std::fs;
+
+    fn utf16(text: &str) -> Vec<u8> {
+        [0xFF, 0xFE]
+            .into_iter()
+            .chain(text.encode_utf16().flat_map(|u| u.to_le_bytes()))
+            .collect()
+    }
+
+    const TABLE: &str =
+        "Items\r\nSword\r\n\r\nA sword\r\nAxe\r\nold\r\nAn axe\r\nSword\r\n\r\nA better sword\r\n";
+
+    #[test]
+    fn parses_utf16_in_file_order() {
+        let lang = LangFile::parse(&utf16(TABLE)).unwrap();
+        assert_eq!(lang.section, "Items");
+        assert_eq!(lang.encoding, Encoding::Utf16Le);
+        assert_eq!(lang.entries().count(), 2);
+        // the last of a duplicated key wins
+        assert_eq!(lang.get("Sword"), Some("A better sword"));
+        let keys: Vec<_> = lang.entries().map(|(k, _)| k.as_str()).collect();
+        assert_eq!(keys, ["Axe", "Sword"]);
+        assert_eq!(lang.id("Axe"), "Items_Axe");
+    }
+
+    #[test]
+    fn round_trips_byte_identical() {
+        let tables = [
+            utf16(TABLE),
+            utf16("Items\r\nB\r\n\r\n2\r\nA\r\n\r\n1"),
+            b"Items\nB\n\n2\nA\n\n1\n".to_vec(),
+            b"\xEF\xBB\xBFItems\r\nA\r\n\r\n1\r\n".to_vec(),
+            [0xFE, 0xFF]
+                .into_iter()
+                .chain(
+                    "Items\r\nA\r\n\r\n1\r\n"
+                        .encode_utf16()
+                        .flat_map(|u| u.to_be_bytes()),
+                )
+                .collect(),
+        ];
+        for data in tables {
+            assert_eq!(LangFile::parse(&data).unwrap().to_bytes(), data);
+        }
+    }
+
+    #[test]
+    fn edits_keep_order() {
+        let mut lang = LangFile::parse(&utf16(TABLE)).unwrap();
+        lang.set("Axe", "A sharp axe").unwrap();
+        lang.set("Wand", "A wand").unwrap();
+        assert!(lang.set("Bad", "two\nlines").is_err());
+        assert_eq!(
+            lang.remove("Sword").map(|e| e.value),
+            Some(String::from("A better sword"))
+        );
+        assert_eq!(lang.get("Sword"), None);
+        assert_eq!(
+            lang.to_bytes(),
+            utf16("Items\r\nAxe\r\nold\r\nA sharp axe\r\nWand\r\n\r\nA wand\r\n")
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_tables() {
+        assert!(LangFile::parse(b"").is_err());
+        assert!(LangFile::parse(&[0xFF, 0xFE, 0x41]).is_err());
+        assert!(LangFile::parse(b"Items\r\nKey\r\n").is_err());
+        assert!(LangFile::parse(b"\xC3\x28").is_err());
+    }
+
+    #[test]
+    fn lookup_skips_unreadable_wads() {
+        let dir = tempfile::tempdir().unwrap();
+        let table = utf16("Items\r\nSword\r\n\r\nA sword\r\n");
+        write_wad(
+            dir.path(),
+            "Items.wad",
+            &[ENTRIES[0], ("Locale/English/Items.lang", &table, true)],
+        );
+        write_wad(dir.path(), "Other.wad", ENTRIES);
+        fs::write(dir.path().join("Broken.wad"), b"not a wad").unwrap();
+
+        let found = lookup(dir.path(), "Items_Sword").unwrap();
+        assert_eq!(found.matches.len(), 1);
+        assert_eq!(found.matches[0].value, "A sword");
+        assert!(found.matches[0].wad.ends_with("Items.wad"));
+        assert_eq!(found.unreadable.len(), 1);
+        assert!(found.unreadable[0].0.ends_with("Broken.wad"));
+
+        assert_eq!(lookup(dir.path(), "Sword").unwrap().matches.len(), 1);
+        assert!(lookup(dir.path(), "Shield").unwrap().matches.is_empty());
+    }
+}
@@ -1,131 +1,736 @@
-use libdeflater::Decompressor;
-use std::collections::HashMap;
-use std::convert::TryInto;
-use std::fs;
-use std::iter::FromIterator;
-use std::str;
-
-extern crate flame;
-
-#[repr(C, packed)]
-struct Header {
-    file_header: [u8; 5],
-    version: u32,
-    num_files: u32,
-    padding: u8,
-}
-
-impl Header {
-    fn read(buf: &Vec<u8>, pos: usize) -> Self {
-        Header {
-            file_header: buf[pos..pos + 5].try_into().unwrap(),
-            version: u32::from_le_bytes(buf[pos + 5..pos + 9].try_into().unwrap()),
-            num_files: u32::from_le_bytes(buf[pos + 9..pos + 13].try_into().unwrap()),
-            padding: buf[pos + 13],
-        }
-    }
-}
-
-#[repr(C, packed)]
-struct File {
-    offset: u32,
-    size: u32,
-    zip_size: u32,
-    zip: u8,
-    crc: u32,
-    name_size: u32,
-}
-
-impl File {
-    fn read(buf: &Vec<u8>, pos: usize) -> Self {
-        File {
-            offset: u32::from_le_bytes(buf[pos..pos + 4].try_into().unwrap()),
-            size: u32::from_le_bytes(buf[pos + 4..pos + 8].try_into().unwrap()),
-            zip_size: u32::from_le_bytes(buf[pos + 8..pos + 12].try_into().unwrap()),
-            zip: buf[pos + 12],
-            crc: u32::from_le_bytes(buf[pos + 13..pos + 17].try_into().unwrap()),
-            name_size: u32::from_le_bytes(buf[pos + 17..pos + 21].try_into().unwrap()),
-        }
-    }
-}
-
-pub struct FileList {
-    files: HashMap<String, Vec<u8>>,
-}
-
-impl FileList {
-    /*let match_str = "GameMessages.xml";
-    match file_list.find_file(match_str) {
-        Some(x) => println!("Found! {}", str::from_utf8(&x).unwrap().to_string()),
-        None => println!("{} doesn't exist in root.wad", match_str)
-    }*/
-    pub fn find_file(&self, name: &str) -> Option<&Vec<u8>> {
-        return self.files.get(name);
-    }
-
-    pub fn get_files_with_ext(&mut self, pat: &str) -> Vec<(String, Vec<u8>)> {
-        let mut ret: Vec<(String, Vec<u8>)> = Vec::new();
-        for (key, value) in &self.files {
-            ret.push((key.try_into().unwrap(), value.to_vec()));
-        }
-        ret
-    }
-
-    pub fn get_file_list(file_name: &str) -> FileList {
-        let mut files = HashMap::new();
-        let contents = fs::read(file_name).expect("Couldn't read wad file");
-        let mut current_pos = 0; // drain is SUPER expensive and makes execution take 1000x as long
-
-        let header = Header::read(&contents, current_pos);
-        current_pos += std::mem::size_of::<Header>();
-
-        for _i in 0..header.num_files {
-            let file = File::read(&contents, current_pos);
-            current_pos += std::mem::size_of::<File>();
-
-            let file_name = str::from_utf8(&Vec::from_iter(
-                contents[current_pos..(current_pos + (file.name_size - 1) as usize)]
-                    .iter()
-                    .cloned(),
-            ))
-            .unwrap()
-            .to_string();
-            current_pos += (file.name_size) as usize;
-
-            if file_name.find("Messages.xml") == None {
-                continue;
-            }
-
-            let pos_backup = current_pos;
-            current_pos = file.offset as usize;
-
-            if file.zip == 0 {
-                let file_data = Vec::from_iter(
-                    contents[current_pos..(current_pos + (file.size) as usize)]
-                        .iter()
-                        .cloned(),
-                );
-                files.insert(file_name, file_data);
-            } else {
-                let compressed = Vec::from_iter(
-                    contents[current_pos..(current_pos + (file.zip_size) as usize)]
-                        .iter()
-                        .cloned(),
-                );
-                let decompressed = {
-                    let mut decompressor = Decompressor::new();
-                    let mut outbuf = Vec::new();
-                    outbuf.resize(file.size as usize, 0);
-                    decompressor
-                        .zlib_decompress(&compressed, &mut outbuf)
-                        .unwrap();
-                    outbuf
-                };
-                files.insert(file_name, decompressed);
-            }
-            current_pos = pos_backup;
-        }
-
-        FileList { files }
-    }
-}
+pub mod convert;
+pub mod diff;
+pub mod extract;
+pub mod index;
+pub mod lang;
+pub mod query;
+pub mod verify;
+pub mod writer;
+
+#[cfg(test)]
+pub mod fixtures;
+
+use eio::{ReadExt, WriteExt};
+use libdeflater::Decompressor;
+use std::collections::HashMap;
+use std::fs;
+use std::io::{BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::str;
+
+// zip_size of entries that are stored uncompressed
+pub const UNCOMPRESSED_ZIP_SIZE: u32 = 0xFFFFFFFF;
+
+// Checksum stored for each entry, over the uncompressed contents
+pub fn crc(data: &[u8]) -> u32 {
+    libdeflater::crc32(data)
+}
+
+// Same checksum over a whole file, read in chunks. This is what the patch
+// file list has for every file of the game.
+pub fn file_crc<P: AsRef<Path>>(path: P) -> Result<u32, String> {
+    let path = path.as_ref();
+    let read_err = |_| format!("Couldn't read '{}'", path.display());
+    let mut file = fs::File::open(path).map_err(read_err)?;
+    let mut crc = libdeflater::Crc::new();
+    let mut buf = vec![0; 1 << 20];
+    loop {
+        let n = file.read(&mut buf).map_err(read_err)?;
+        if n == 0 {
+            break;
+        }
+        crc.update(&buf[..n]);
+    }
+    Ok(crc.sum())
+}
+
+const MAGIC: &[u8; 5] = b"KIWAD";
+
+// Header layout by version:
+//   v1:  "KIWAD", u32 version, u32 num_files
+//   v2+: same as v1 followed by a u8 of flags
+struct Header {
+    version: u32,
+    num_files: u32,
+    flags: u8,
+}
+
+impl Header {
+    fn size(version: u32) -> u64 {
+        if version >= 2 {
+            14
+        } else {
+            13
+        }
+    }
+
+    fn read<R: Read>(reader: &mut R) -> Result<Self, String> {
+        let err = |_| String::from("Unexpected end of wad header");
+        let mut file_header = [0; 5];
+        reader.read_exact(&mut file_header).map_err(err)?;
+        if &file_header != MAGIC {
+            return Err(format!(
+                "Not a KIWAD archive (expected magic {:02X?}, found {:02X?})",
+                MAGIC, file_header
+            ));
+        }
+
+        let version: u32 = reader.read_le().map_err(err)?;
+        if version == 0 {
+            return Err(String::from("Unsupported KIWAD version 0"));
+        }
+        let num_files = reader.read_le().map_err(err)?;
+        let flags = if version >= 2 {
+            reader.read_le().map_err(err)?
+        } else {
+            0
+        };
+
+        Ok(Header {
+            version,
+            num_files,
+            flags,
+        })
+    }
+
+    fn write<W: Write>(&self, writer: &mut W) -> std::io::Result<()> {
+        writer.write_all(MAGIC)?;
+        writer.write_le(self.version)?;
+        writer.write_le(self.num_files)?;
+        if self.version >= 2 {
+            writer.write_le(self.flags)?;
+        }
+        Ok(())
+    }
+}
+
+struct File {
+    offset: u32,
+    size: u32,
+    zip_size: u32,
+    zip: u8,
+    crc: u32,
+    name_size: u32,
+}
+
+impl File {
+    fn read<R: Read>(reader: &mut R) -> Result<Self, String> {
+        let err = |_| String::from("Unexpected end of wad file table");
+        Ok(File {
+            offset: reader.read_le().map_err(err)?,
+            size: reader.read_le().map_err(err)?,
+            zip_size: reader.read_le().map_err(err)?,
+            zip: reader.read_le().map_err(err)?,
+            crc: reader.read_le().map_err(err)?,
+            name_size: reader.read_le().map_err(err)?,
+        })
+    }
+}
+
+// A file stored in a wad, as listed in its file table. Reading the contents
+// goes through the `Archive` it came from.
+#[derive(Debug, Clone)]
+pub struct Entry {
+    pub name: String,
+    pub offset: u32,
+    pub size: u32,     // uncompressed size
+    pub zip_size: u32, // size of the data in the wad if compressed
+    pub compressed: bool,
+    pub crc: u32,
+}
+
+impl Entry {
+    // number of bytes the entry takes up in the wad
+    pub fn stored_size(&self) -> u32 {
+        if self.compressed {
+            self.zip_size
+        } else {
+            self.size
+        }
+    }
+}
+
+// Longest entry name we accept, the game's own are well under this
+const MAX_NAME_SIZE: u32 = 4096;
+// Smallest possible file table record (fixed fields plus a 1 byte name)
+const MIN_RECORD_SIZE: u64 = 22;
+
+// Caps on what an entry may inflate to, so a hostile wad can't make us
+// allocate gigabytes from a few bytes of zlib.
+#[derive(Debug, Clone, Copy)]
+pub struct Limits {
+    pub max_entry_size: u32,
+    // uncompressed size / compressed size, zlib can't do much over 1032:1
+    pub max_ratio: u32,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_entry_size: 1 << 30,
+            max_ratio: 1100,
+        }
+    }
+}
+
+// Seek-based KIWAD reader. Only the file table is kept in memory, entry data is
+// read from disk when asked for so large wads don't need to fit in RAM.
+//
+// Nothing in the file is trusted: the file table is checked against the size
+// of the wad when it's opened, and every entry against `Limits` before it is
+// read or inflated.
+pub struct Archive<R: Read + Seek = BufReader<fs::File>> {
+    path: PathBuf,
+    reader: R,
+    len: u64,
+    limits: Limits,
+    version: u32,
+    flags: u8,
+    entries: Vec<Entry>,
+    lookup: HashMap<String, usize>,
+}
+
+impl Archive {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Archive, String> {
+        let path = path.as_ref();
+        let file =
+            fs::File::open(path).or(Err(format!("Couldn't open wad file '{}'", path.display())))?;
+        let mut archive = Archive::from_reader(BufReader::new(file))
+            .map_err(|e| format!("{}: {}", path.display(), e))?;
+        archive.path = path.to_path_buf();
+        Ok(archive)
+    }
+
+    // Another handle on the same wad that can be read from independently (eg.
+    // on another thread), without parsing the file table again
+    pub fn try_clone(&self) -> Result<Archive, String> {
+        let file = fs::File::open(&self.path).or(Err(format!(
+            "Couldn't open wad file '{}'",
+            self.path.display()
+        )))?;
+        Ok(Archive {
+            path: self.path.clone(),
+            reader: BufReader::new(file),
+            len: self.len,
+            limits: self.limits,
+            version: self.version,
+            flags: self.flags,
+            entries: self.entries.clone(),
+            lookup: self.lookup.clone(),
+        })
+    }
+}
+
+impl<R: Read + Seek> Archive<R> {
+    pub fn from_reader(mut reader: R) -> Result<Archive<R>, String> {
+        let len = reader
+            .seek(SeekFrom::End(0))
+            .and_then(|len| reader.rewind().map(|_| len))
+            .or(Err(String::from("Couldn't get the size of the wad")))?;
+
+        let header = Header::read(&mut reader)?;
+
+        let table_start = Header::size(header.version);
+        if header.num_files as u64 * MIN_RECORD_SIZE > len - table_start {
+            return Err(format!(
+                "Wad claims {} files, which don't fit in {} bytes",
+                header.num_files, len
+            ));
+        }
+
+        let mut entries = Vec::new();
+        let mut lookup = HashMap::new();
+        for _i in 0..header.num_files {
+            let file = File::read(&mut reader)?;
+
+            if file.name_size == 0 || file.name_size > MAX_NAME_SIZE {
+                return Err(format!("Invalid file name length {}", file.name_size));
+            }
+            let mut name = vec![0; file.name_size as usize];
+            reader
+                .read_exact(&mut name)
+                .or(Err(String::from("Unexpected end of wad file table")))?;
+            // names are null terminated
+            if name.last() == Some(&0) {
+                name.pop();
+            }
+            if name.contains(&0) {
+                return Err(String::from("Wad contains a file name with a null byte"));
+            }
+            let name = String::from_utf8(name)
+                .or(Err(String::from("Wad contains a non utf-8 file name")))?;
+
+            let entry = Entry {
+                name,
+                offset: file.offset,
+                size: file.size,
+                zip_size: file.zip_size,
+                compressed: file.zip != 0,
+                crc: file.crc,
+            };
+            if entry.offset as u64 + entry.stored_size() as u64 > len {
+                return Err(format!(
+                    "{} ({} bytes at {}) runs past the end of the wad",
+                    entry.name,
+                    entry.stored_size(),
+                    entry.offset
+                ));
+            }
+
+            lookup.insert(entry.name.clone(), entries.len());
+            entries.push(entry);
+        }
+
+        Ok(Archive {
+            path: PathBuf::new(),
+            reader,
+            len,
+            limits: Default::default(),
+            version: header.version,
+            flags: header.flags,
+            entries,
+            lookup,
+        })
+    }
+
+    // The launcher itself sticks to the defaults, the fuzz target lowers them
+    // to stay under its memory limit
+    #[allow(dead_code)]
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
+    }
+
+    // Empty for archives that weren't opened from a file
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    pub fn flags(&self) -> u8 {
+        self.flags
+    }
+
+    pub fn entries(&self) -> &[Entry] {
+        &self.entries
+    }
+
+    pub fn entry(&self, name: &str) -> Option<&Entry> {
+        self.lookup.get(name).map(|i| &self.entries[*i])
+    }
+
+    // Checks an entry's size against the limits before anything gets allocated
+    // for it. Entries from this archive's file table are already known to lie
+    // within the wad, but ones passed in from elsewhere aren't.
+    pub fn check_entry(&self, entry: &Entry) -> Result<(), String> {
+        if entry.offset as u64 + entry.stored_size() as u64 > self.len {
+            return Err(format!("{} runs past the end of the wad", entry.name));
+        }
+        if entry.size > self.limits.max_entry_size {
+            return Err(format!(
+                "{} is {} bytes, over the limit of {}",
+                entry.name, entry.size, self.limits.max_entry_size
+            ));
+        }
+        if entry.compressed
+            && entry.size as u64 > entry.zip_size as u64 * self.limits.max_ratio as u64
+        {
+            return Err(format!(
+                "{} claims to inflate {} bytes to {}, over the ratio limit of {}",
+                entry.name, entry.zip_size, entry.size, self.limits.max_ratio
+            ));
+        }
+        Ok(())
+    }
+
+    // Data as stored in the wad, still compressed if the entry is
+    pub fn read_raw(&mut self, entry: &Entry) -> Result<Vec<u8>, String> {
+        self.check_entry(entry)?;
+        self.reader
+            .seek(SeekFrom::Start(entry.offset as u64))
+            .or(Err(format!("Couldn't seek to {}", entry.name)))?;
+
+        let mut data = vec![0; entry.stored_size() as usize];
+        self.reader.read_exact(&mut data).or(Err(format!(
+            "Unexpected end of wad while reading {}",
+            entry.name
+        )))?;
+        Ok(data)
+    }
+
+    // The contents as far as they go, which for a damaged compressed entry
+    // can be less than its size. Never more, the limits are checked first.
+    pub fn read_partial(&mut self, entry: &Entry) -> Result<Vec<u8>, String> {
+        let data = self.read_raw(entry)?;
+        if !entry.compressed {
+            return Ok(data);
+        }
+        inflate_partial(&data, entry.size as usize)
+            .map_err(|e| format!("Couldn't decompress {}: {}", entry.name, e))
+    }
+
+    pub fn read(&mut self, entry: &Entry) -> Result<Vec<u8>, String> {
+        let data = self.read_partial(entry)?;
+        if data.len() != entry.size as usize {
+            return Err(format!(
+                "Couldn't decompress {}: expected {} bytes, got {}",
+                entry.name,
+                entry.size,
+                data.len()
+            ));
+        }
+        Ok(data)
+    }
+
+    pub fn read_file(&mut self, name: &str) -> Result<Vec<u8>, String> {
+        let entry = match self.entry(name) {
+            Some(e) => e.clone(),
+            None => return Err(format!("{} doesn't exist in {}", name, self.path.display())),
+        };
+        self.read(&entry)
+    }
+
+    // Writes a single entry out to `path`, creating parent directories as needed
+    pub fn extract<P: AsRef<Path>>(&mut self, entry: &Entry, path: P) -> Result<(), String> {
+        let path = path.as_ref();
+        let data = self.read(entry)?;
+        if let Some(prefix) = path.parent() {
+            fs::create_dir_all(prefix).or(Err(format!(
+                "Failed to create directory '{}'",
+                prefix.display()
+            )))?;
+        }
+        fs::write(path, data).or(Err(format!("Failed to write file '{}'", path.display())))
+    }
+}
+
+// Inflates into at most `size` bytes
+fn inflate_partial(compressed: &[u8], size: usize) -> Result<Vec<u8>, String> {
+    let mut decompressor = Decompressor::new();
+    let mut outbuf = vec![0; size];
+    let written = match decompressor.zlib_decompress(compressed, &mut outbuf) {
+        Ok(written) => written,
+        Err(libdeflater::DecompressionError::InsufficientSpace) => {
+            return Err(format!("inflates to more than {} bytes", size))
+        }
+        Err(e) => return Err(format!("{:?}", e)),
+    };
+    outbuf.truncate(written);
+    Ok(outbuf)
+}
+
+pub fn inflate(compressed: &[u8], size: usize) -> Result<Vec<u8>, String> {
+    let outbuf = inflate_partial(compressed, size)?;
+    if outbuf.len() != size {
+        return Err(format!("expected {} bytes, got {}", size, outbuf.len()));
+    }
+    Ok(outbuf)
+}
+
+// Every .wad under `dir`, sorted so callers see them in the same order
+pub fn find_wads<P: AsRef<Path>>(dir: P) -> Result<Vec<PathBuf>, String> {
+    fn walk(dir: &Path, wads: &mut Vec<PathBuf>) -> Result<(), String> {
+        let read_err = |_| format!("Couldn't read directory '{}'", dir.display());
+        for item in fs::read_dir(dir).map_err(read_err)? {
+            let path = item.map_err(read_err)?.path();
+            if path.is_dir() {
+                walk(&path, wads)?;
+            } else if path
+                .extension()
+                .is_some_and(|e| e.eq_ignore_ascii_case("wad"))
+            {
+                wads.push(path);
+            }
+        }
+        Ok(())
+    }
+
+    let mut wads = Vec::new();
+    walk(dir.as_ref(), &mut wads)?;
+    wads.sort();
+    Ok(wads)
+}
+
+// Every file under `dir` with its path relative to it, slash separated and
+// sorted like `find_wads`
+pub(crate) fn find_files<P: AsRef<Path>>(dir: P) -> Result<Vec<(String, PathBuf)>, String> {
+    fn walk(dir: &Path, prefix: &str, files: &mut Vec<(String, PathBuf)>) -> Result<(), String> {
+        let read_err = |_| format!("Couldn't read directory '{}'", dir.display());
+        for item in fs::read_dir(dir).map_err(read_err)? {
+            let item = item.map_err(read_err)?;
+            let name = format!("{}{}", prefix, item.file_name().to_string_lossy());
+            let file_type = item.file_type().map_err(read_err)?;
+            if file_type.is_dir() {
+                walk(&item.path(), &format!("{}/", name), files)?;
+            } else if file_type.is_file() {
+                files.push((name, item.path()));
+            }
+        }
+        Ok(())
+    }
+
+    let mut files = Vec::new();
+    walk(dir.as_ref(), "", &mut files)?;
+    files.sort();
+    Ok(files)
+}
+
+pub struct FileList {
+    files: HashMap<String, Vec<u8>>,
+}
+
+impl FileList {
+    pub fn get_files_with_ext(&mut self, pat: &str) -> Vec<(String, Vec<u8>)> {
+        let mut ret: Vec<(String, Vec<u8>)> = Vec::new();
+        for (key, value) in &self.files {
+            if key.ends_with(pat) {
+                ret.push((key.to_string(), value.to_vec()));
+            }
+        }
+        ret
+    }
+
+    pub fn get_file_list(file_name: &str) -> Result<FileList, String> {
+        let mut files = HashMap::new();
+        let mut archive = Archive::open(file_name)?;
+
+        for entry in archive.entries().to_vec() {
+            if !entry.name.contains("Messages.xml") {
+                continue;
+            }
+
+            let file_data = archive.read(&entry)?;
+            files.insert(entry.name, file_data);
+        }
+
+        Ok(FileList { files })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::fixtures::{wad, write_wad, ENTRIES};
+    use super::*;
+    use std::io::Cursor;
+
+    fn archive(data: Vec<u8>) -> Archive<Cursor<Vec<u8>>> {
+        Archive::from_reader(Cursor::new(data)).unwrap()
+    }
+
+    #[test]
+    fn lists_the_file_table() {
+        let archive = archive(wad(2, 0, ENTRIES));
+        let names: Vec<&str> = archive.entries().iter().map(|e| e.name.as_str()).collect();
+        assert_eq!(
+            names,
+            [
+                "GameData/Foo.xml",
+                "GameData/Bar.txt",
+                "Locale/English/Items.lang"
+            ]
+        );
+        let foo = archive.entry("GameData/Foo.xml").unwrap();
+        assert!(foo.compressed);
+        assert_eq!(foo.size as usize, ENTRIES[0].1.len());
+        assert_eq!(foo.crc, crc(ENTRIES[0].1));
+        let bar = archive.entry("GameData/Bar.txt").unwrap();
+        assert!(!bar.compressed);
+        assert_eq!(bar.zip_size, UNCOMPRESSED_ZIP_SIZE);
+        assert_eq!(bar.stored_size(), bar.size);
+        assert!(archive.entry("GameData/Missing.xml").is_none());
+    }
+
+    #[test]
+    fn reads_stored_and_compressed_entries() {
+        let mut archive = archive(wad(2, 0, ENTRIES));
+        for (name, contents, _) in ENTRIES {
+            assert_eq!(archive.read_file(name).unwrap(), *contents);
+        }
+        let foo = archive.entry("GameData/Foo.xml").unwrap().clone();
+        assert_ne!(archive.read_raw(&foo).unwrap(), ENTRIES[0].1);
+        assert!(archive.read_file("GameData/Missing.xml").is_err());
+    }
+
+    #[test]
+    fn reads_from_disk_through_clones() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = write_wad(dir.path(), "Test.wad", ENTRIES);
+        let mut archive = Archive::open(&path).unwrap();
+        let mut other = archive.try_clone().unwrap();
+        assert_eq!(archive.path(), path);
+        assert_eq!(other.read_file("GameData/Bar.txt").unwrap(), b"plain text");
+        assert_eq!(archive.read_file("GameData/Foo.xml").unwrap(), ENTRIES[0].1);
+
+        let entry = archive.entry("GameData/Foo.xml").unwrap().clone();
+        let out = dir.path().join("out/GameData/Foo.xml");
+        archive.extract(&entry, &out).unwrap();
+        assert_eq!(fs::read(out).unwrap(), ENTRIES[0].1);
+        assert!(Archive::open(dir.path().join("Missing.wad")).is_err());
+    }
+
+    #[test]
+    fn finds_wads_and_crcs_files() {
+        let dir = tempfile::tempdir().unwrap();
+        write_wad(dir.path(), "b/Zed.WAD", ENTRIES);
+        write_wad(dir.path(), "Root.wad", ENTRIES);
+        fs::write(dir.path().join("notes.txt"), "not a wad").unwrap();
+        let wads = find_wads(dir.path()).unwrap();
+        assert_eq!(
+            wads,
+            [dir.path().join("Root.wad"), dir.path().join("b/Zed.WAD")]
+        );
+        assert_eq!(
+            file_crc(&wads[0]).unwrap(),
+            crc(&fs::read(&wads[0]).unwrap())
+        );
+    }
+
+    // Empty archives of each header version, as older and newer clients
+    // write them
+    const V1_HEADER: &[u8] = b"KIWAD\x01\0\0\0\0\0\0\0";
+    const V2_HEADER: &[u8] = b"KIWAD\x02\0\0\0\0\0\0\0\x01";
+    const V3_HEADER: &[u8] = b"KIWAD\x03\0\0\0\0\0\0\0\x80";
+
+    fn header_err(data: &[u8]) -> String {
+        match Archive::from_reader(Cursor::new(data.to_vec())) {
+            Ok(_) => panic!("{:02X?} parsed", data),
+            Err(e) => e,
+        }
+    }
+
+    #[test]
+    fn parses_each_header_version() {
+        for (data, version, flags) in [(V1_HEADER, 1, 0), (V2_HEADER, 2, 1), (V3_HEADER, 3, 0x80)] {
+            assert_eq!(data.len() as u64, Header::size(version));
+            let archive = archive(data.to_vec());
+            assert_eq!((archive.version(), archive.flags()), (version, flags));
+            assert!(archive.entries().is_empty());
+        }
+    }
+
+    #[test]
+    fn reads_entries_after_each_header() {
+        for (version, flags) in [(1, 0), (2, 0x5A), (3, 0)] {
+            let data = wad(version, flags, ENTRIES);
+            // the file table starts right after the header
+            let table = Header::size(version) as usize;
+            let first_offset = u32::from_le_bytes(data[table..table + 4].try_into().unwrap());
+            let mut archive = archive(data);
+            assert_eq!(archive.entries()[0].offset, first_offset);
+            assert_eq!((archive.version(), archive.flags()), (version, flags));
+            for (name, contents, _) in ENTRIES {
+                assert_eq!(archive.read_file(name).unwrap(), *contents);
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_bad_magic() {
+        let mut data = wad(2, 0, ENTRIES);
+        data[..5].copy_from_slice(b"KIWAF");
+        assert!(header_err(&data).starts_with("Not a KIWAD archive"));
+        assert!(header_err(b"PK\x03\x04 not a wad at all").starts_with("Not a KIWAD archive"));
+    }
+
+    #[test]
+    fn rejects_version_0() {
+        let mut data = wad(2, 0, ENTRIES);
+        data[5..9].copy_from_slice(&0u32.to_le_bytes());
+        assert_eq!(header_err(&data), "Unsupported KIWAD version 0");
+    }
+
+    // Where the fields of the first file table record are in a v2 wad
+    const OFFSET: usize = 14;
+    const SIZE: usize = 18;
+    const ZIP_SIZE: usize = 22;
+    const NAME_SIZE: usize = 31;
+    const NAME: usize = 35;
+
+    fn set_u32(data: &mut [u8], at: usize, val: u32) {
+        data[at..at + 4].copy_from_slice(&val.to_le_bytes());
+    }
+
+    #[test]
+    fn rejects_file_tables_that_dont_fit() {
+        let mut data = wad(2, 0, ENTRIES);
+        set_u32(&mut data, 9, 100_000);
+        assert!(header_err(&data).starts_with("Wad claims 100000 files"));
+
+        let data = wad(2, 0, &ENTRIES[..1]);
+        assert_eq!(
+            header_err(&data[..NAME + 4]),
+            "Unexpected end of wad file table"
+        );
+    }
+
+    #[test]
+    fn rejects_entries_past_the_end() {
+        let mut data = wad(2, 0, ENTRIES);
+        let len = data.len() as u32;
+        set_u32(&mut data, OFFSET, len - 2);
+        assert!(header_err(&data).ends_with("runs past the end of the wad"));
+
+        let mut data = wad(2, 0, ENTRIES);
+        set_u32(&mut data, ZIP_SIZE, u32::MAX);
+        assert!(header_err(&data).ends_with("runs past the end of the wad"));
+    }
+
+    #[test]
+    fn rejects_bad_names() {
+        for name_size in [0, MAX_NAME_SIZE + 1] {
+            let mut data = wad(2, 0, ENTRIES);
+            set_u32(&mut data, NAME_SIZE, name_size);
+            assert!(header_err(&data).starts_with("Invalid file name length"));
+        }
+
+        let mut data = wad(2, 0, ENTRIES);
+        data[NAME + 3] = 0;
+        assert_eq!(
+            header_err(&data),
+            "Wad contains a file name with a null byte"
+        );
+
+        let mut data = wad(2, 0, ENTRIES);
+        data[NAME] = 0xFF;
+        assert_eq!(header_err(&data), "Wad contains a non utf-8 file name");
+    }
+
+    #[test]
+    fn caps_entry_sizes() {
+        let mut capped = archive(wad(2, 0, ENTRIES));
+        capped.set_limits(Limits {
+            max_entry_size: 20,
+            ..Default::default()
+        });
+        let err = capped.read_file("GameData/Foo.xml").unwrap_err();
+        assert!(err.ends_with("over the limit of 20"));
+        assert_eq!(capped.read_file("GameData/Bar.txt").unwrap(), b"plain text");
+
+        // half a gigabyte from a few bytes of zlib
+        let mut data = wad(2, 0, ENTRIES);
+        set_u32(&mut data, SIZE, 1 << 29);
+        let mut bomb = archive(data);
+        let err = bomb.read_file("GameData/Foo.xml").unwrap_err();
+        assert!(err.ends_with("over the ratio limit of 1100"));
+    }
+
+    #[test]
+    fn checks_entries_from_elsewhere() {
+        let mut archive = archive(wad(2, 0, ENTRIES));
+        let mut entry = archive.entry("GameData/Bar.txt").unwrap().clone();
+        entry.offset = archive.len as u32;
+        assert!(archive.check_entry(&entry).is_err());
+        assert!(archive.read(&entry).is_err());
+    }
+
+    #[test]
+    fn rejects_truncated_headers() {
+        for header in [V1_HEADER, V2_HEADER, V3_HEADER] {
+            for len in 0..header.len() {
+                assert_eq!(header_err(&header[..len]), "Unexpected end of wad header");
+            }
+        }
+    }
+}
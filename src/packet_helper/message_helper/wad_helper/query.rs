@@ -0,0 +1,180 @@
+use regex::Regex;
+use std::io::{Read, Seek};
+
+use super::{Archive, Entry};
+
+// Turns a glob such as `GameData/*.xml` or `**/*Messages.xml` into a regex
+// over the whole entry path. `*` and `?` don't cross directories, `**/`
+// matches any number of them (including none).
+fn glob_to_regex(glob: &str) -> String {
+    let mut re = String::from("^");
+    let chars: Vec<char> = glob.chars().collect();
+    let mut i = 0;
+    while i < chars.len() {
+        match chars[i] {
+            '*' if chars.get(i + 1) == Some(&'*') => {
+                if chars.get(i + 2) == Some(&'/') {
+                    re.push_str("(?:.*/)?");
+                    i += 1;
+                } else {
+                    re.push_str(".*");
+                }
+                i += 1;
+            }
+            '*' => re.push_str("[^/]*"),
+            '?' => re.push_str("[^/]"),
+            c => re.push_str(&regex::escape(&c.to_string())),
+        }
+        i += 1;
+    }
+    re.push('$');
+    re
+}
+
+// Filter over the file table of a wad. Nothing is decompressed, matching only
+// looks at the entry's name and metadata.
+//
+// let query = Query::new().glob("GameData/*.xml")?.compressed(true);
+// for entry in query.run(&archive) { ... }
+#[derive(Debug, Clone, Default)]
+pub struct Query {
+    pattern: Option<Regex>,
+    min_size: Option<u32>,
+    max_size: Option<u32>,
+    compressed: Option<bool>,
+}
+
+impl Query {
+    pub fn new() -> Query {
+        Default::default()
+    }
+
+    pub fn glob(self, pat: &str) -> Result<Query, String> {
+        let re = Regex::new(&glob_to_regex(pat))
+            .map_err(|e| format!("Invalid glob '{}': {}", pat, e))?;
+        Ok(Query {
+            pattern: Some(re),
+            ..self
+        })
+    }
+
+    // Unlike `glob` this isn't anchored, it matches anywhere in the path
+    pub fn regex(self, pat: &str) -> Result<Query, String> {
+        let re = Regex::new(pat).map_err(|e| format!("Invalid regex '{}': {}", pat, e))?;
+        Ok(Query {
+            pattern: Some(re),
+            ..self
+        })
+    }
+
+    // Sizes are the uncompressed size of the entry
+    pub fn min_size(self, size: u32) -> Query {
+        Query {
+            min_size: Some(size),
+            ..self
+        }
+    }
+
+    pub fn max_size(self, size: u32) -> Query {
+        Query {
+            max_size: Some(size),
+            ..self
+        }
+    }
+
+    pub fn compressed(self, compressed: bool) -> Query {
+        Query {
+            compressed: Some(compressed),
+            ..self
+        }
+    }
+
+    pub fn matches(&self, entry: &Entry) -> bool {
+        if let Some(re) = &self.pattern {
+            if !re.is_match(&entry.name) {
+                return false;
+            }
+        }
+        if self.min_size.is_some_and(|min| entry.size < min) {
+            return false;
+        }
+        if self.max_size.is_some_and(|max| entry.size > max) {
+            return false;
+        }
+        if self.compressed.is_some_and(|c| entry.compressed != c) {
+            return false;
+        }
+        true
+    }
+
+    pub fn run<'a, R: Read + Seek>(&self, archive: &'a Archive<R>) -> Vec<&'a Entry> {
+        archive
+            .entries()
+            .iter()
+            .filter(|e| self.matches(e))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::fixtures::{wad, ENTRIES};
+    use super::*;
+    use std::io::Cursor;
+
+    fn names(query: &Query) -> Vec<String> {
+        let archive = Archive::from_reader(Cursor::new(wad(2, 0, ENTRIES))).unwrap();
+        query.run(&archive).iter().map(|e| e.name.clone()).collect()
+    }
+
+    #[test]
+    fn globs_stay_within_directories() {
+        let re = Regex::new(&glob_to_regex("GameData/*.xml")).unwrap();
+        assert!(re.is_match("GameData/Foo.xml"));
+        assert!(!re.is_match("GameData/Sub/Foo.xml"));
+        assert!(!re.is_match("Other/GameData/Foo.xml"));
+
+        let re = Regex::new(&glob_to_regex("**/*Messages.xml")).unwrap();
+        assert!(re.is_match("GameMessages.xml"));
+        assert!(re.is_match("a/b/LoginMessages.xml"));
+
+        let re = Regex::new(&glob_to_regex("Foo?.x+ml")).unwrap();
+        assert!(re.is_match("Foo1.x+ml"));
+        assert!(!re.is_match("Foo/.x+ml"));
+        assert!(!re.is_match("Foo1.xxml"));
+    }
+
+    #[test]
+    fn filters_by_path() {
+        assert_eq!(
+            names(&Query::new().glob("GameData/*").unwrap()),
+            ["GameData/Foo.xml", "GameData/Bar.txt"]
+        );
+        assert_eq!(
+            names(&Query::new().regex(r"\.lang$").unwrap()),
+            ["Locale/English/Items.lang"]
+        );
+        assert!(Query::new().regex("(").is_err());
+    }
+
+    #[test]
+    fn filters_by_size_and_compression() {
+        assert_eq!(names(&Query::new().min_size(11)), ["GameData/Foo.xml"]);
+        assert_eq!(
+            names(&Query::new().max_size(10)),
+            ["GameData/Bar.txt", "Locale/English/Items.lang"]
+        );
+        assert_eq!(names(&Query::new().compressed(true)), ["GameData/Foo.xml"]);
+        assert_eq!(
+            names(
+                &Query::new()
+                    .glob("**/*")
+                    .unwrap()
+                    .compressed(false)
+                    .min_size(1)
+            ),
+            ["GameData/Bar.txt"]
+        );
+        assert_eq!(names(&Query::new()).len(), ENTRIES.len());
+    }
+}
@@ -0,0 +1,177 @@
+use std::fmt;
+use std::io::{Read, Seek};
+
+use super::{crc, Archive, Entry};
+
+#[derive(Debug, PartialEq)]
+pub enum EntryStatus {
+    Ok,
+    // the data couldn't be read or inflated at all
+    Corrupt(String),
+    SizeMismatch { expected: u32, actual: u32 },
+    CrcMismatch { expected: u32, actual: u32 },
+}
+
+impl fmt::Display for EntryStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EntryStatus::Ok => write!(f, "ok"),
+            EntryStatus::Corrupt(e) => write!(f, "corrupt: {}", e),
+            EntryStatus::SizeMismatch { expected, actual } => {
+                write!(f, "size mismatch: expected {}, got {}", expected, actual)
+            }
+            EntryStatus::CrcMismatch { expected, actual } => write!(
+                f,
+                "crc mismatch: expected {:08x}, got {:08x}",
+                expected, actual
+            ),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct EntryReport {
+    pub name: String,
+    pub status: EntryStatus,
+}
+
+#[derive(Debug)]
+pub struct VerifyReport {
+    pub entries: Vec<EntryReport>,
+}
+
+impl VerifyReport {
+    pub fn is_ok(&self) -> bool {
+        self.entries.iter().all(|e| e.status == EntryStatus::Ok)
+    }
+
+    pub fn failures(&self) -> Vec<&EntryReport> {
+        self.entries
+            .iter()
+            .filter(|e| e.status != EntryStatus::Ok)
+            .collect()
+    }
+}
+
+// Goes through the archive's own reader, so the entry is checked against its
+// limits before anything is allocated for it
+pub fn verify_entry<R: Read + Seek>(archive: &mut Archive<R>, entry: &Entry) -> EntryStatus {
+    let data = match archive.read_partial(entry) {
+        Ok(d) => d,
+        Err(e) => return EntryStatus::Corrupt(e),
+    };
+
+    if data.len() != entry.size as usize {
+        return EntryStatus::SizeMismatch {
+            expected: entry.size,
+            actual: data.len() as u32,
+        };
+    }
+
+    let actual = crc(&data);
+    if actual != entry.crc {
+        return EntryStatus::CrcMismatch {
+            expected: entry.crc,
+            actual,
+        };
+    }
+    EntryStatus::Ok
+}
+
+// Inflates every entry and checks it against the size and crc in the file
+// table. Nothing panics on a bad entry, it just ends up in the report.
+pub fn verify<R: Read + Seek>(archive: &mut Archive<R>) -> VerifyReport {
+    let mut entries = Vec::new();
+    for entry in archive.entries().to_vec() {
+        let status = verify_entry(archive, &entry);
+        entries.push(EntryReport {
+            name: entry.name,
+            status,
+        });
+    }
+    VerifyReport { entries }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::fixtures::{wad, ENTRIES};
+    use super::*;
+    use std::io::Cursor;
+
+    // Where the fields of the first file table record are in a v2 wad
+    const SIZE: usize = 18;
+    const CRC: usize = 27;
+
+    fn verify_first(data: Vec<u8>) -> EntryStatus {
+        let mut archive = Archive::from_reader(Cursor::new(data)).unwrap();
+        let report = verify(&mut archive);
+        assert_eq!(report.entries.len(), ENTRIES.len());
+        assert!(report.entries[1..]
+            .iter()
+            .all(|e| e.status == EntryStatus::Ok));
+        report.entries.into_iter().next().unwrap().status
+    }
+
+    #[test]
+    fn passes_intact_wads() {
+        let mut archive = Archive::from_reader(Cursor::new(wad(2, 0, ENTRIES))).unwrap();
+        let report = verify(&mut archive);
+        assert!(report.is_ok());
+        assert!(report.failures().is_empty());
+    }
+
+    #[test]
+    fn reports_crc_mismatches() {
+        let mut data = wad(2, 0, ENTRIES);
+        data[CRC] ^= 0xFF;
+        let expected = crc(ENTRIES[0].1) ^ 0xFF;
+        assert_eq!(
+            verify_first(data),
+            EntryStatus::CrcMismatch {
+                expected,
+                actual: crc(ENTRIES[0].1)
+            }
+        );
+    }
+
+    #[test]
+    fn reports_size_mismatches() {
+        let mut data = wad(2, 0, ENTRIES);
+        let size = ENTRIES[0].1.len() as u32;
+        data[SIZE..SIZE + 4].copy_from_slice(&(size + 10).to_le_bytes());
+        assert_eq!(
+            verify_first(data),
+            EntryStatus::SizeMismatch {
+                expected: size + 10,
+                actual: size
+            }
+        );
+    }
+
+    #[test]
+    fn reports_corrupt_data() {
+        let mut data = wad(2, 0, ENTRIES);
+        let size = ENTRIES[0].1.len() as u32;
+        data[SIZE..SIZE + 4].copy_from_slice(&(size - 1).to_le_bytes());
+        assert!(matches!(verify_first(data), EntryStatus::Corrupt(_)));
+
+        let mut data = wad(2, 0, ENTRIES);
+        let mut archive = Archive::from_reader(Cursor::new(data.clone())).unwrap();
+        let offset = archive.entries()[0].offset as usize;
+        data[offset + 2] ^= 0xFF;
+        data[offset + 3] ^= 0xFF;
+        assert!(matches!(verify_first(data), EntryStatus::Corrupt(_)));
+        // the archive it was read from is still fine
+        assert!(verify(&mut archive).is_ok());
+    }
+
+    #[test]
+    fn checks_limits_before_inflating() {
+        let mut data = wad(2, 0, ENTRIES);
+        data[SIZE..SIZE + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+        match verify_first(data) {
+            EntryStatus::Corrupt(e) => assert!(e.contains("over the limit"), "{}", e),
+            status => panic!("{}", status),
+        }
+    }
+}
@@ -0,0 +1,373 @@
+use eio::WriteExt;
+use libdeflater::{CompressionLvl, Compressor};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use super::{crc, Archive, Entry, Header, UNCOMPRESSED_ZIP_SIZE};
+
+enum Contents {
+    Data(Vec<u8>),
+    File(PathBuf),
+    // copied over from the source archive without recompressing
+    Copy(Entry),
+    // already stored data (compressed or not) and the entry describing it
+    Raw(Entry, Vec<u8>),
+}
+
+struct PendingEntry {
+    contents: Contents,
+    compress: bool,
+}
+
+// Builds a KIWAD. Entries are written sorted by name, which is how the game's
+// own wads are laid out.
+//
+// let mut writer = Writer::new();
+// writer.add("GameData/Foo.xml", data, true);
+// writer.write("Foo.wad")?;
+pub struct Writer<'a> {
+    version: u32,
+    flags: u8,
+    level: i32,
+    source: Option<&'a mut Archive>,
+    // kept sorted by name
+    entries: BTreeMap<String, PendingEntry>,
+}
+
+impl Default for Writer<'_> {
+    fn default() -> Self {
+        Writer {
+            version: 2,
+            flags: 0,
+            level: 9,
+            source: None,
+            entries: BTreeMap::new(),
+        }
+    }
+}
+
+impl<'a> Writer<'a> {
+    pub fn new() -> Writer<'a> {
+        Default::default()
+    }
+
+    // Starts from every entry in `archive`, keeping its version and flags.
+    // Entries that aren't replaced are copied as is.
+    pub fn from_archive(archive: &'a mut Archive) -> Writer<'a> {
+        let entries = archive
+            .entries()
+            .iter()
+            .map(|e| {
+                let pending = PendingEntry {
+                    compress: e.compressed,
+                    contents: Contents::Copy(e.clone()),
+                };
+                (e.name.clone(), pending)
+            })
+            .collect();
+        Writer {
+            version: archive.version(),
+            flags: archive.flags(),
+            level: 9,
+            source: Some(archive),
+            entries,
+        }
+    }
+
+    // Header version to write, v1 wads have no flags byte
+    pub fn version(&mut self, version: u32) -> &mut Self {
+        self.version = version;
+        self
+    }
+
+    pub fn flags(&mut self, flags: u8) -> &mut Self {
+        self.flags = flags;
+        self
+    }
+
+    // zlib level used for compressed entries, 0-12 (libdeflate levels)
+    pub fn compression_level(&mut self, level: i32) -> &mut Self {
+        self.level = level;
+        self
+    }
+
+    fn put(&mut self, name: &str, contents: Contents, compress: bool) {
+        self.entries
+            .insert(name.to_string(), PendingEntry { contents, compress });
+    }
+
+    // Adds an entry, replacing any existing one with the same name
+    pub fn add(&mut self, name: &str, data: Vec<u8>, compress: bool) -> &mut Self {
+        self.put(name, Contents::Data(data), compress);
+        self
+    }
+
+    // Same as `add`, but the file is only read when the wad is written
+    pub fn add_file<P: AsRef<Path>>(&mut self, name: &str, path: P, compress: bool) -> &mut Self {
+        self.put(name, Contents::File(path.as_ref().to_path_buf()), compress);
+        self
+    }
+
+    // Adds data exactly as it's stored in another wad, keeping its compression
+    // and crc as they are in `entry`
+    pub fn add_raw(&mut self, entry: Entry, stored: Vec<u8>) -> &mut Self {
+        let (name, compress) = (entry.name.clone(), entry.compressed);
+        self.put(&name, Contents::Raw(entry, stored), compress);
+        self
+    }
+
+    pub fn remove(&mut self, name: &str) -> &mut Self {
+        self.entries.remove(name);
+        self
+    }
+
+    // Keeps only the entries `keep` returns true for
+    pub fn retain<F: FnMut(&str) -> bool>(&mut self, mut keep: F) -> &mut Self {
+        self.entries.retain(|name, _| keep(name));
+        self
+    }
+
+    pub fn write<P: AsRef<Path>>(mut self, path: P) -> Result<(), String> {
+        let path = path.as_ref();
+        let level = CompressionLvl::new(self.level)
+            .or(Err(format!("Invalid compression level {}", self.level)))?;
+        let mut compressor = Compressor::new(level);
+
+        if let Some(source) = &self.source {
+            if fs::canonicalize(source.path()).ok() == fs::canonicalize(path).ok() {
+                return Err(String::from(
+                    "Can't write a wad over the one it's repacked from",
+                ));
+            }
+        }
+
+        let write_err = |_| format!("Failed to write to '{}'", path.display());
+        let file = fs::File::create(path)
+            .or(Err(format!("Failed to create file '{}'", path.display())))?;
+        let mut out = BufWriter::new(file);
+
+        // file table goes right after the header, data after that
+        let table_size: u64 = self
+            .entries
+            .keys()
+            .map(|name| 21 + name.len() as u64 + 1)
+            .sum();
+        let mut offset = Header::size(self.version) + table_size;
+        out.seek(SeekFrom::Start(offset)).map_err(write_err)?;
+
+        let mut table = Vec::new();
+        for (name, pending) in std::mem::take(&mut self.entries) {
+            if offset > u32::MAX as u64 {
+                return Err(String::from(
+                    "Wad is too big, offsets have to fit in 32 bits",
+                ));
+            }
+            let data = match pending.contents {
+                Contents::Copy(entry) => {
+                    let source = self.source.as_mut().unwrap();
+                    let data = source.read_raw(&entry)?;
+                    out.write_all(&data).map_err(write_err)?;
+                    table.push(Entry {
+                        offset: offset as u32,
+                        ..entry
+                    });
+                    offset += data.len() as u64;
+                    continue;
+                }
+                Contents::Raw(entry, data) => {
+                    if data.len() != entry.stored_size() as usize {
+                        return Err(format!("Stored data for {} has the wrong size", entry.name));
+                    }
+                    out.write_all(&data).map_err(write_err)?;
+                    table.push(Entry {
+                        offset: offset as u32,
+                        ..entry
+                    });
+                    offset += data.len() as u64;
+                    continue;
+                }
+                Contents::Data(data) => data,
+                Contents::File(file) => {
+                    fs::read(&file).or(Err(format!("Couldn't read '{}'", file.display())))?
+                }
+            };
+
+            let size = data.len() as u32;
+            let data_crc = crc(&data);
+            let stored = if pending.compress {
+                let mut buf = vec![0; compressor.zlib_compress_bound(data.len())];
+                let len = compressor
+                    .zlib_compress(&data, &mut buf)
+                    .map_err(|e| format!("Couldn't compress {}: {:?}", name, e))?;
+                buf.truncate(len);
+                buf
+            } else {
+                data
+            };
+            out.write_all(&stored).map_err(write_err)?;
+            table.push(Entry {
+                name,
+                offset: offset as u32,
+                size,
+                zip_size: if pending.compress {
+                    stored.len() as u32
+                } else {
+                    UNCOMPRESSED_ZIP_SIZE
+                },
+                compressed: pending.compress,
+                crc: data_crc,
+            });
+            offset += stored.len() as u64;
+            if offset > u32::MAX as u64 {
+                return Err(String::from(
+                    "Wad is too big, offsets have to fit in 32 bits",
+                ));
+            }
+        }
+
+        out.seek(SeekFrom::Start(0)).map_err(write_err)?;
+        let header = Header {
+            version: self.version,
+            num_files: table.len() as u32,
+            flags: self.flags,
+        };
+        header.write(&mut out).map_err(write_err)?;
+        for entry in &table {
+            out.write_le(entry.offset).map_err(write_err)?;
+            out.write_le(entry.size).map_err(write_err)?;
+            out.write_le(entry.zip_size).map_err(write_err)?;
+            out.write_le(entry.compressed as u8).map_err(write_err)?;
+            out.write_le(entry.crc).map_err(write_err)?;
+            out.write_le(entry.name.len() as u32 + 1)
+                .map_err(write_err)?;
+            out.write_all(entry.name.as_bytes()).map_err(write_err)?;
+            out.write_le(0u8).map_err(write_err)?;
+        }
+        out.flush().map_err(write_err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::fixtures::{write_wad, ENTRIES};
+    use super::*;
+
+    fn read_all(path: &Path) -> Vec<(String, Vec<u8>, bool)> {
+        let mut archive = Archive::open(path).unwrap();
+        archive
+            .entries()
+            .to_vec()
+            .into_iter()
+            .map(|e| {
+                let data = archive.read(&e).unwrap();
+                (e.name, data, e.compressed)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn writes_sorted_entries_that_read_back() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("New.wad");
+        let mut writer = Writer::new();
+        writer
+            .flags(3)
+            .add("b.xml", b"<b>bbbbbbbbbbbbbbbb</b>".to_vec(), true)
+            .add("a.txt", b"aaa".to_vec(), false)
+            .add("c.txt", b"gone".to_vec(), false)
+            .remove("c.txt");
+        writer.write(&path).unwrap();
+
+        let archive = Archive::open(&path).unwrap();
+        assert_eq!((archive.version(), archive.flags()), (2, 3));
+        assert_eq!(
+            read_all(&path),
+            [
+                (String::from("a.txt"), b"aaa".to_vec(), false),
+                (
+                    String::from("b.xml"),
+                    b"<b>bbbbbbbbbbbbbbbb</b>".to_vec(),
+                    true
+                )
+            ]
+        );
+        let b = archive.entry("b.xml").unwrap();
+        assert_eq!(b.crc, crc(b"<b>bbbbbbbbbbbbbbbb</b>"));
+        assert_ne!(b.zip_size, UNCOMPRESSED_ZIP_SIZE);
+    }
+
+    #[test]
+    fn writes_v1_headers() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("Old.wad");
+        let mut writer = Writer::new();
+        writer
+            .version(1)
+            .flags(3)
+            .add("a.txt", b"aaa".to_vec(), false);
+        writer.write(&path).unwrap();
+
+        let data = fs::read(&path).unwrap();
+        // 13 byte header, the file table starts with the first entry's offset
+        let table_size = 22 + "a.txt".len() as u32;
+        assert_eq!(data[13..17], (13 + table_size).to_le_bytes());
+        let archive = Archive::open(&path).unwrap();
+        assert_eq!((archive.version(), archive.flags()), (1, 0));
+        assert_eq!(read_all(&path)[0].1, b"aaa");
+    }
+
+    #[test]
+    fn repacks_an_archive() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = write_wad(dir.path(), "Source.wad", ENTRIES);
+        let replacement = dir.path().join("Bar.txt");
+        fs::write(&replacement, "new bar").unwrap();
+
+        let mut archive = Archive::open(&source).unwrap();
+        let foo = archive.entry("GameData/Foo.xml").unwrap().clone();
+        let foo_stored = archive.read_raw(&foo).unwrap();
+        let mut writer = Writer::from_archive(&mut archive);
+        writer
+            .add_file("GameData/Bar.txt", &replacement, false)
+            .remove("Locale/English/Items.lang")
+            .retain(|name| name != "Nothing");
+        let out = dir.path().join("Out.wad");
+        writer.write(&out).unwrap();
+
+        assert_eq!(
+            read_all(&out),
+            [
+                (String::from("GameData/Bar.txt"), b"new bar".to_vec(), false),
+                (
+                    String::from("GameData/Foo.xml"),
+                    ENTRIES[0].1.to_vec(),
+                    true
+                )
+            ]
+        );
+        // untouched entries are copied without recompressing
+        let mut repacked = Archive::open(&out).unwrap();
+        let copied = repacked.entry("GameData/Foo.xml").unwrap().clone();
+        assert_eq!(repacked.read_raw(&copied).unwrap(), foo_stored);
+    }
+
+    #[test]
+    fn refuses_bad_input() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = write_wad(dir.path(), "Source.wad", ENTRIES);
+        let mut archive = Archive::open(&source).unwrap();
+        assert!(Writer::from_archive(&mut archive).write(&source).is_err());
+        assert_eq!(read_all(&source).len(), ENTRIES.len());
+
+        let mut writer = Writer::new();
+        writer.compression_level(99);
+        assert!(writer.write(dir.path().join("a.wad")).is_err());
+
+        let entry = archive.entry("GameData/Bar.txt").unwrap().clone();
+        let mut writer = Writer::new();
+        writer.add_raw(entry, b"too short".to_vec());
+        assert!(writer.write(dir.path().join("b.wad")).is_err());
+    }
+}
@@ -186,6 +186,36 @@ impl PatchFile {
             header_crc: cursor.read_le().unwrap(),
         }
     }
+
+    // path relative to the game dir, eg. Data/GameData/Root.wad
+    pub fn name(&self) -> &str {
+        &self.src_name
+    }
+
+    pub fn size(&self) -> u32 {
+        self.size
+    }
+
+    // crc32 of the whole file once it's installed
+    pub fn crc(&self) -> u32 {
+        self.crc
+    }
+
+    // A record as the file list would have it, for tests of what checks
+    // files against it
+    #[cfg(test)]
+    pub(crate) fn new(name: &str, size: u32, crc: u32) -> PatchFile {
+        PatchFile {
+            src_name: name.to_string(),
+            tar_name: name.to_string(),
+            file_type: 0,
+            size,
+            header_size: 0,
+            compressed_size: size,
+            crc,
+            header_crc: 0,
+        }
+    }
 }
 
 #[derive(Debug)]